@@ -0,0 +1,14 @@
+//! Feeds arbitrary bytes to `Packet::parse`, which must turn anything a
+//! server sends into a packet or an error without panicking.
+//! Run with `cargo fuzz run packet_parse` from `seg-fs`.
+#![no_main]
+
+// seg-fs is a binary, so the modules the parser needs are built in here
+#[path = "../../src/error.rs"]
+mod error;
+#[path = "../../src/packets.rs"]
+mod packets;
+
+libfuzzer_sys::fuzz_target!(|data: &[u8]| {
+    let _ = packets::Packet::parse(data);
+});
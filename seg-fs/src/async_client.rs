@@ -0,0 +1,110 @@
+use super::client::Client;
+use super::error::*;
+use std::path::PathBuf;
+use tokio::io::Interest;
+
+/// Drives a `Client` from async code, waiting on tokio for the socket to
+/// become readable instead of blocking a thread on it
+pub struct AsyncClient {
+    client: Client,
+    socket: tokio::net::UdpSocket
+}
+
+impl AsyncClient {
+    /// Wrap a connected socket. It's switched to non-blocking, and must be
+    /// made inside a tokio runtime.
+    pub fn new(udp_socket: std::net::UdpSocket) -> Result<Self, SegFsError> {
+        udp_socket.set_nonblocking(true).map_err(|e| format!("unable to make socket non-blocking {}", e))?;
+        let shared = udp_socket.try_clone().map_err(|e| format!("unable to share udp socket {}", e))?;
+        let socket = tokio::net::UdpSocket::from_std(shared).map_err(|e| format!("unable to register socket with tokio {}", e))?;
+
+        Ok(Self { client: Client::new(udp_socket), socket })
+    }
+
+    /// The client underneath, for changing its settings or looking at files
+    pub fn client(&mut self) -> &mut Client {
+        &mut self.client
+    }
+
+    /// Send the request, receive until `n` files are complete, then write
+    /// them out and hand back where they went, in the order they finished.
+    ///
+    /// Each packet is received and filed away in one go between awaits, so
+    /// dropping the future part way leaves the client as it was after the
+    /// last whole packet, and calling this again carries on from there.
+    pub async fn download_n(&mut self, n: usize) -> Result<Vec<PathBuf>, SegFsError> {
+        self.client.send_request()?;
+
+        while !self.client.is_batch_complete(n) {
+            self.socket.readable().await.map_err(|e| format!("unable to wait on socket {}", e))?;
+
+            // The client reads from its own handle on the socket, so tell
+            // tokio when that runs dry or it will keep saying it's readable
+            let client = &mut self.client;
+            let received = self.socket.try_io(Interest::READABLE, || match client.recv_packet() {
+                Err(SegFsError::Timeout) => Err(std::io::ErrorKind::WouldBlock.into()),
+                result => Ok(result)
+            });
+
+            match received {
+                Ok(result) => result?,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(format!("unable to recieve data over socket {}", e).into())
+            }
+        }
+
+        let ids: Vec<_> = self.client.completed_files().map(|(id, _)| id).collect();
+        let mut paths = Vec::with_capacity(ids.len());
+        for id in ids {
+            paths.extend(self.client.finalize_file(id)?);
+        }
+        Ok(paths)
+    }
+}
+
+#[tokio::test]
+async fn async_download() {
+    use super::packets::*;
+
+    let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.connect(server.local_addr().unwrap()).unwrap();
+
+    let dir = std::env::temp_dir().join(format!("seg-fs-async_download-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut client = AsyncClient::new(socket).unwrap();
+    client.client().set_output_dir(&dir);
+
+    // The second file's header only comes with the second request
+    let server_task = tokio::spawn(async move {
+        let mut buf = [0; 16];
+        let (_, peer) = server.recv_from(&mut buf).await.unwrap();
+        let packets = [
+            DataPacket { is_last: true, file_id: 2, packet_number: 0, data: b"two".to_vec() }.to_bytes(),
+            HeaderPacket { file_id: 1, name: String::from("one.txt"), expected_size: None }.to_bytes(),
+            DataPacket { is_last: true, file_id: 1, packet_number: 0, data: b"one".to_vec() }.to_bytes()
+        ];
+        for packet in packets.iter() {
+            server.send_to(packet, peer).await.unwrap();
+        }
+
+        server.recv_from(&mut buf).await.unwrap();
+        server.send_to(&HeaderPacket { file_id: 2, name: String::from("two.txt"), expected_size: None }.to_bytes(), peer).await.unwrap();
+    });
+
+    // Give up on the first try partway, once it has run out of packets
+    let first = tokio::time::timeout(std::time::Duration::from_millis(200), client.download_n(2)).await;
+    assert!(first.is_err());
+    assert_eq!(client.client().file_count(), 1);
+
+    let paths = client.download_n(2).await.unwrap();
+    server_task.await.unwrap();
+
+    assert_eq!(paths, vec![dir.join("one.txt"), dir.join("two.txt")]);
+    assert_eq!(std::fs::read(dir.join("one.txt")).unwrap(), b"one");
+    assert_eq!(std::fs::read(dir.join("two.txt")).unwrap(), b"two");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
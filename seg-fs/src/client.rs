@@ -1,11 +1,32 @@
+use super::clock::*;
+use super::error::*;
+use super::filesystem::*;
+use super::journal::*;
 use super::packets::*;
-use std::collections::HashMap;
+use super::rng::SmallRng;
+use super::trace::*;
+use super::transport::*;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 pub struct File {
     file_id: FileId,
     name: Option<String>,
     segments: HashMap<PacketNumber, Vec<u8>>,
-    max_segments: Option<PacketNumber>
+    /// How many segments from 0 on have all arrived, kept up as they do
+    contiguous: usize,
+    max_segments: Option<PacketNumber>,
+    expected_segment_size: Option<usize>,
+    size_anomaly: bool,
+    last_conflict: bool,
+    last_activity: Option<Instant>,
+    generation: u32,
+    expected_size: Option<u64>,
+    completion_index: usize,
+    retransmits: u32,
+    arrival_log: Option<Vec<PacketNumber>>
 }
 
 impl File {
@@ -14,19 +35,256 @@ impl File {
             file_id,
             name: None,
             segments: HashMap::new(),
-            max_segments: None
+            contiguous: 0,
+            max_segments: None,
+            expected_segment_size: None,
+            size_anomaly: false,
+            last_conflict: false,
+            last_activity: None,
+            generation: 0,
+            expected_size: None,
+            completion_index: 0,
+            retransmits: 0,
+            arrival_log: None
         }
     }
 
+    pub fn file_id(&self) -> FileId {
+        self.file_id
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Start noting the number of every data packet as it's reported,
+    /// repeats included
+    pub fn log_arrivals(&mut self) {
+        self.arrival_log.get_or_insert_with(Vec::new);
+    }
+
+    /// The packet numbers in the order they arrived, if they're being logged
+    pub fn arrival_order(&self) -> Option<&[PacketNumber]> {
+        self.arrival_log.as_deref()
+    }
+
+    /// How many times `Client::request_retransmit` has asked for the file's
+    /// missing segments
+    pub fn retransmits(&self) -> u32 {
+        self.retransmits
+    }
+
+    /// How many earlier files the server sent under the same id
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// The number of the last packet, once it has arrived
+    pub fn last_packet_number(&self) -> Option<PacketNumber> {
+        self.max_segments
+    }
+
     pub fn report_header_packet(&mut self, data: HeaderPacket) {
         self.name = Some(data.name);
+        if data.expected_size.is_some() {
+            self.expected_size = data.expected_size;
+        }
+    }
+
+    /// The total size the server said the file is, if its header included it
+    pub fn expected_size(&self) -> Option<u64> {
+        self.expected_size
+    }
+
+    /// How much of the file has arrived, from 0 to 1, when its size is known
+    pub fn fraction_complete(&self) -> Option<f64> {
+        self.expected_size.map(|size| {
+            if size == 0 {
+                1.0
+            }
+            else {
+                (self.data_len() as f64 / size as f64).min(1.0)
+            }
+        })
     }
 
+    /// File a segment away. A last packet numbered below a segment that's
+    /// already arrived can't be right, so it doesn't set the end of the file
+    /// and the conflict is flagged instead. Nothing comes after the last
+    /// packet, so a segment numbered past it is dropped.
     pub fn report_data_packet(&mut self, data: DataPacket) {
-        self.segments.insert(data.packet_number, data.data);
+        if let Some(log) = self.arrival_log.as_mut() {
+            log.push(data.packet_number);
+        }
+        if self.max_segments.is_some_and(|last| data.packet_number > last) {
+            return;
+        }
+        self.check_segment_size(&data);
+        let below_received = self.highest_packet_number().filter(|&highest| highest > data.packet_number).is_some();
+        self.insert_segment(data.packet_number, data.data);
         if data.is_last {
-            self.max_segments = Some(data.packet_number);
+            if below_received {
+                self.last_conflict = true;
+            }
+            else {
+                self.max_segments = Some(data.packet_number);
+            }
+        }
+    }
+
+    fn insert_segment(&mut self, packet_number: PacketNumber, data: Vec<u8>) {
+        self.segments.insert(packet_number, data);
+        while self.contiguous <= PacketNumber::MAX as usize && self.segments.contains_key(&(self.contiguous as PacketNumber)) {
+            self.contiguous += 1;
+        }
+    }
+
+    /// Every packet but the last should carry the same amount of data, so the
+    /// first non-last packet sets the size the others are checked against.
+    fn check_segment_size(&mut self, data: &DataPacket) {
+        let size = data.data.len();
+
+        if let Some(expected) = self.expected_segment_size {
+            if size > expected || (!data.is_last && size != expected) {
+                self.size_anomaly = true;
+            }
+        }
+        else if !data.is_last {
+            self.expected_segment_size = Some(size);
+
+            // A last packet may have arrived first, it can't be bigger
+            // than any of the others
+            if let Some(last) = self.max_segments.and_then(|id| self.segments.get(&id)) {
+                if last.len() > size {
+                    self.size_anomaly = true;
+                }
+            }
+        }
+    }
+
+    pub fn approx_memory_bytes(&self) -> usize {
+        let segment_overhead = std::mem::size_of::<(PacketNumber, Vec<u8>)>();
+
+        std::mem::size_of::<Self>()
+            + self.name.as_ref().map(|name| name.len()).unwrap_or(0)
+            + self.segments.values().map(|data| data.len() + segment_overhead).sum::<usize>()
+    }
+
+    pub fn expected_segment_size(&self) -> Option<usize> {
+        self.expected_segment_size
+    }
+
+    /// Whether a data packet arrived whose size doesn't match the others
+    pub fn has_size_anomaly(&self) -> bool {
+        self.size_anomaly
+    }
+
+    /// Whether a last packet arrived numbered below segments already received
+    pub fn has_last_conflict(&self) -> bool {
+        self.last_conflict
+    }
+
+    /// The whole file's data in order, failing if any of it is missing or
+    /// it doesn't come to the size its header gave
+    pub fn assemble(&self) -> Result<Vec<u8>, SegFsError> {
+        self.assemble_with(ShortFilePolicy::Error)
+    }
+
+    /// Like `assemble`, but with `policy` deciding what happens when the
+    /// data comes to less than the size its header gave. More than that is
+    /// always an error.
+    pub fn assemble_with(&self, policy: ShortFilePolicy) -> Result<Vec<u8>, SegFsError> {
+        let fallback = format!("file_{}", self.file_id);
+        let name = self.name.as_deref().unwrap_or(&fallback);
+
+        let missing = self.missing_segments()
+            .ok_or_else(|| format!("unable to assemble {}, its last packet never arrived", name))?;
+        if let Some(first) = missing.first() {
+            return Err(format!("unable to assemble {}, missing packet id {}", name, first).into());
+        }
+        let short = self.expected_size.filter(|&expected| (self.data_len() as u64) < expected);
+        if let Some(expected) = self.expected_size {
+            if self.data_len() as u64 > expected || (short.is_some() && policy == ShortFilePolicy::Error) {
+                return Err(format!("unable to assemble {}, received {} bytes but expected {}", name, self.data_len(), expected).into());
+            }
+        }
+
+        let mut data = Vec::with_capacity(self.data_len());
+        for id in 0..=self.max_segments.unwrap_or_default() {
+            data.extend_from_slice(&self.segments[&id]);
+        }
+        if let (Some(expected), ShortFilePolicy::PadZero) = (short, policy) {
+            data.resize(expected as usize, 0);
+        }
+        Ok(data)
+    }
+
+    /// Total bytes of data received so far
+    pub fn data_len(&self) -> usize {
+        self.segments.values().map(|data| data.len()).sum()
+    }
+
+    /// The segments still to arrive, `None` until the last packet says how
+    /// many there are
+    pub fn missing_segments(&self) -> Option<Vec<PacketNumber>> {
+        let max = self.max_segments?;
+        Some((0..=max).filter(|number| !self.segments.contains_key(number)).collect())
+    }
+
+    /// Which segments have arrived, by packet number, up to the last packet
+    /// once it's known or the highest one received so far until then
+    pub fn received_bitmap(&self) -> Vec<bool> {
+        let len = self.max_segments.or(self.highest_packet_number()).map(|last| last as usize + 1).unwrap_or(0);
+        let mut bitmap = vec![false; len];
+        for &number in self.segments.keys().filter(|&&number| (number as usize) < len) {
+            bitmap[number as usize] = true;
+        }
+        bitmap
+    }
+
+    /// How many segments have arrived in order from the start, which is the
+    /// number of the first one still to come
+    pub fn contiguous_segments(&self) -> usize {
+        self.contiguous
+    }
+
+    /// Cut the file down to its first `limit` bytes and end it there, if
+    /// its leading segments cover that many. The segment the limit falls in
+    /// is trimmed and any past it dropped. Returns whether it was cut.
+    fn truncate_to_prefix(&mut self, limit: u64) -> bool {
+        let mut covered = 0;
+        for number in 0..=PacketNumber::MAX {
+            let Some(segment) = self.segments.get_mut(&number) else {
+                return false;
+            };
+            if covered + segment.len() as u64 >= limit {
+                segment.truncate((limit - covered) as usize);
+                self.segments.retain(|&other, _| other <= number);
+                self.contiguous = number as usize + 1;
+                self.max_segments = Some(number);
+                self.expected_size = Some(limit);
+                return true;
+            }
+            covered += segment.len() as u64;
         }
+        false
+    }
+
+    /// Segments missing below the highest one received so far. Unlike
+    /// `missing_segments` this works before the last packet arrives, so it
+    /// gives an early sign of loss.
+    pub fn current_gaps(&self) -> usize {
+        self.highest_packet_number()
+            .map(|highest| highest as usize + 1 - self.segments.len())
+            .unwrap_or(0)
+    }
+
+    fn highest_packet_number(&self) -> Option<PacketNumber> {
+        self.segments.keys().max().copied()
     }
 
     pub fn is_done(&self) -> bool {
@@ -37,6 +295,69 @@ impl File {
             false
         }
     }
+
+    /// A hash of the file's data in order, for comparing downloads. Like
+    /// `reader` it covers only the segments in order from the start.
+    pub fn content_hash(&self, algorithm: HashAlgorithm) -> Vec<u8> {
+        match algorithm {
+            HashAlgorithm::Crc32 => self.crc32().to_be_bytes().to_vec(),
+            #[cfg(feature = "sha256")]
+            HashAlgorithm::Sha256 => {
+                use sha2::Digest;
+                self.ordered_segments().fold(sha2::Sha256::new(), |hasher, data| hasher.chain_update(data)).finalize().to_vec()
+            }
+        }
+    }
+
+    fn crc32(&self) -> u32 {
+        !self.ordered_segments().fold(CRC32_INIT, |crc, data| crc32_update(crc, data))
+    }
+
+    /// The segments in order from the start, up to the first missing one
+    fn ordered_segments(&self) -> impl Iterator<Item = &Vec<u8>> {
+        (0..=PacketNumber::MAX).map_while(|number| self.segments.get(&number))
+    }
+
+    /// Read the file's data in order without copying it into one buffer.
+    /// The reader stops at the first missing segment, so for a file that
+    /// isn't done it gives back only what has arrived in order from the start.
+    pub fn reader(&self) -> SegmentReader<'_> {
+        SegmentReader { file: self, next: 0, offset: 0 }
+    }
+}
+
+/// Reads a `File`'s segments one after another, from `File::reader`
+pub struct SegmentReader<'a> {
+    file: &'a File,
+    next: u32,
+    offset: usize
+}
+
+impl std::io::Read for SegmentReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while !buf.is_empty() {
+            if self.file.max_segments.is_some_and(|last| self.next > last as u32) {
+                break;
+            }
+            let Some(data) = PacketNumber::try_from(self.next).ok().and_then(|number| self.file.segments.get(&number)) else {
+                break;
+            };
+
+            // Empty segments, or ones already read to the end, are skipped
+            let remaining = &data[self.offset..];
+            if remaining.is_empty() {
+                self.next += 1;
+                self.offset = 0;
+                continue;
+            }
+
+            let count = remaining.len().min(buf.len());
+            buf[..count].copy_from_slice(&remaining[..count]);
+            self.offset += count;
+            return Ok(count);
+        }
+        Ok(0)
+    }
 }
 
 impl std::fmt::Display for File {
@@ -58,136 +379,4592 @@ impl std::fmt::Display for File {
     }   
 }
 
+/// What became of one file in `Client::finalize_batch`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileStatus {
+    Written,
+    /// Left out, because it was already there or by the nameless policy
+    Skipped,
+    /// The write failed for this reason, and the file is kept to try again
+    Failed(String)
+}
+
+/// One file's part in `Client::finalize_batch`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileResult {
+    pub file_id: FileId,
+    /// Where it was written, or for a file skipped because it was already
+    /// there, where that is
+    pub path: Option<PathBuf>,
+    /// How much data was received for it
+    pub bytes: usize,
+    /// The CRC-32 of its data, for files that were written
+    pub crc: Option<u32>,
+    pub status: FileStatus
+}
+
+/// Everything `Client::finalize_batch` did, a file at a time
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchResult {
+    pub files: Vec<FileResult>
+}
+
+impl BatchResult {
+    /// Whether every write succeeded. Skipped files don't count against it.
+    pub fn is_success(&self) -> bool {
+        self.files.iter().all(|file| !matches!(file.status, FileStatus::Failed(_)))
+    }
+
+    /// Where each written file went
+    pub fn written(&self) -> impl Iterator<Item = &std::path::Path> {
+        self.files.iter().filter(|file| file.status == FileStatus::Written).filter_map(|file| file.path.as_deref())
+    }
+}
+
+/// A file which has been sent data but never its last packet, so it has no
+/// way to complete by itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NeverFinalized {
+    pub file_id: FileId,
+    pub segments: usize
+}
+
+/// Something odd the server did which the client put up with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// A segment arrived again
+    DuplicatePacket { file_id: FileId, packet_number: PacketNumber },
+    /// A header was sent again for a file already in progress
+    DuplicateHeader { file_id: FileId, name: String },
+    /// A segment arrived again with different contents, which points to
+    /// corruption or tampering rather than a plain resend
+    ConflictingSegment { file_id: FileId, packet_number: PacketNumber },
+    /// A segment arrived past the one marked as last, and was dropped
+    PastLastPacket { file_id: FileId, packet_number: PacketNumber, last: PacketNumber },
+    /// A last packet was numbered below `highest`, a segment already received,
+    /// so it was not taken as the end of the file
+    LastBelowReceived { file_id: FileId, last: PacketNumber, highest: PacketNumber },
+    /// A segment's size didn't match the file's other segments
+    SegmentSizeAnomaly { file_id: FileId, packet_number: PacketNumber, size: usize },
+    /// A header's name couldn't be decoded, and the bad header policy let
+    /// the transfer carry on
+    BadHeader { file_id: FileId, error: String },
+    /// Control characters in a name were swapped for `_`, `name` is the
+    /// name as the server sent it
+    ControlCharsReplaced { file_id: FileId, name: String },
+    /// A file id still in progress was given to a new file, dropping the old one
+    FileIdReused { file_id: FileId, old_name: String, new_name: String },
+    /// A name outside the expected set, under `UnexpectedFilePolicy::Warn`
+    UnexpectedFile { name: String },
+    /// A file dropped because its extension isn't allowed
+    DisallowedExtension { file_id: FileId, name: String },
+    /// A completed file with no name was left out, under `NamelessPolicy::Skip`
+    NamelessFileSkipped { file_id: FileId }
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Warning::DuplicatePacket { file_id, packet_number } => write!(f, "file id {} sent packet {} more than once", file_id, packet_number),
+            Warning::DuplicateHeader { file_id, name } => write!(f, "file id {} sent its header {} more than once", file_id, name),
+            Warning::ConflictingSegment { file_id, packet_number } => write!(f, "file id {} sent packet {} again with different contents", file_id, packet_number),
+            Warning::PastLastPacket { file_id, packet_number, last } => write!(f, "file id {} sent packet {} after its last packet {}", file_id, packet_number, last),
+            Warning::LastBelowReceived { file_id, last, highest } => write!(f, "file id {} marked packet {} as last after packet {} arrived", file_id, last, highest),
+            Warning::SegmentSizeAnomaly { file_id, packet_number, size } => write!(f, "file id {} packet {} has unexpected size {}", file_id, packet_number, size),
+            Warning::BadHeader { file_id, error } => write!(f, "file id {} sent a bad header: {}", file_id, error),
+            Warning::ControlCharsReplaced { file_id, name } => write!(f, "file id {} name {:?} had control characters replaced", file_id, name),
+            Warning::FileIdReused { file_id, old_name, new_name } => write!(f, "file id {} was reused for {} before {} finished", file_id, new_name, old_name),
+            Warning::UnexpectedFile { name } => write!(f, "server sent unexpected file {}", name),
+            Warning::DisallowedExtension { file_id, name } => write!(f, "file id {} {} was dropped, its extension isn't allowed", file_id, name),
+            Warning::NamelessFileSkipped { file_id } => write!(f, "file id {} completed without a name and wasn't written", file_id)
+        }
+    }
+}
+
+/// What to do with a file name that includes directories, like `docs/readme.txt`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum SubdirPolicy {
+    /// Keep only the last component of the name
+    #[default]
+    Flatten,
+    /// Recreate the directories under the output directory
+    CreateDirs,
+    /// Refuse to write the file
+    Reject
+}
+
+/// What to do when a header names a file that wasn't expected
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum UnexpectedFilePolicy {
+    /// Fail the packet with `SegFsError::UnexpectedFile`
+    #[default]
+    Error,
+    /// Carry on, noting the name in `Client::unexpected_files`
+    Warn
+}
+
+/// What to do when a header names a file whose extension isn't allowed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum DisallowedExtensionPolicy {
+    /// Drop the file's packets, noting it in `Client::rejected_files`
+    #[default]
+    Drop,
+    /// Fail the header packet
+    Error
+}
+
+/// What to do when a segment arrives again with different contents
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum SegmentConflictPolicy {
+    /// Keep the newer copy
+    #[default]
+    Overwrite,
+    /// Keep the copy that arrived first
+    KeepFirst,
+    /// Fail the packet
+    Error
+}
+
+/// What to do with control characters (newlines, tabs, terminal escapes and
+/// the like) in names sent by the server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum ControlCharPolicy {
+    /// Swap each one for `_`
+    #[default]
+    Replace,
+    /// Fail the header packet
+    Reject,
+    /// Keep the name as sent
+    Allow
+}
+
+/// What to do with a header whose name can't be decoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum BadHeaderPolicy {
+    /// Fail the packet, which usually ends the transfer
+    #[default]
+    Abort,
+    /// Drop the file's packets until its id gets a good header
+    SkipFile,
+    /// Carry on with a made up name, `file_{id}`
+    Synthesize
+}
+
+/// What to do with a file that comes to fewer bytes than its header said
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum ShortFilePolicy {
+    /// Refuse to write it
+    #[default]
+    Error,
+    /// Write what arrived
+    Truncate,
+    /// Make up the difference with zero bytes
+    PadZero
+}
+
+/// What to do when writing out a completed file that never got a name
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum NamelessPolicy {
+    /// Refuse to write it
+    #[default]
+    Error,
+    /// Write it as `file_{id}.bin`
+    Synthesize,
+    /// Leave it out, recording a `Warning::NamelessFileSkipped`
+    Skip
+}
+
+/// How `File::content_hash` sums up a file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum HashAlgorithm {
+    /// The CRC-32 used by zip and gzip
+    #[default]
+    Crc32,
+    /// SHA-256, which needs the `sha256` feature
+    #[cfg(feature = "sha256")]
+    Sha256
+}
+
+/// What to do about an error reading the socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorAction {
+    /// Try the read again
+    Retry,
+    /// Fail the receive with the error
+    Abort,
+    /// Give up on this receive as if it had timed out, leaving the caller
+    /// to decide whether to carry on
+    Skip
+}
+
+impl ErrorAction {
+    /// Retry reads cut short by a signal and fail on anything else.
+    /// Timeouts also count as failures so they still come out as
+    /// `SegFsError::Timeout`.
+    pub fn default_for(e: &std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::Interrupted => ErrorAction::Retry,
+            _ => ErrorAction::Abort
+        }
+    }
+}
+
+/// Counters describing the transfer so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientStats {
+    pub files_completed: usize,
+    pub files_in_progress: usize,
+    pub bytes_received: u64,
+    /// Segments known to be missing from in-progress files whose last packet has arrived
+    pub missing_segments: usize,
+    pub duplicate_packets: u64,
+    /// Segments dropped for arriving too far beyond the reorder window
+    pub window_drops: u64,
+    /// Every datagram read from the socket, whether or not it made sense
+    pub datagrams_received: u64,
+    /// Time since the request was sent, or the first packet arrived
+    pub elapsed: Duration
+}
+
+/// How old a `.part` file has to be before `Client::clean_partials` treats
+/// it as left behind, rather than being written by another run right now
+pub const STALE_PARTIAL_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// The most data a packet carries unless the server's handshake says otherwise
+pub const DEFAULT_SEGMENT_SIZE: usize = 1024;
+
+/// The status byte, file id and packet number in front of a data packet's data
+pub const DATA_HEADER_LEN: usize = 4;
+
+/// The biggest segment a server can announce, as much as fits in a UDP
+/// datagram alongside the packet header
+const MAX_SEGMENT_SIZE: usize = 65_507 - DATA_HEADER_LEN;
+
+/// The HMAC-SHA256 signed datagrams end with
+#[cfg(feature = "hmac")]
+const MAC_LEN: usize = 32;
+
+pub type FilenameTransform = Box<dyn Fn(&str) -> String>;
+
+/// Called with the id, name and size of each file as it completes
+pub type CompletionCallback = Box<dyn FnMut(FileId, &str, usize)>;
+
+/// Called with each data packet's file id, packet number and payload,
+/// returning what is stored in its place
+pub type PayloadTransform = Box<dyn FnMut(FileId, PacketNumber, &[u8]) -> Vec<u8>>;
+
+/// Called with each error reading the socket to decide what happens next
+pub type SocketErrorCallback = Box<dyn FnMut(&std::io::Error) -> ErrorAction>;
+
 pub struct Client {
-    udp_socket: std::net::UdpSocket,
+    transport: Box<dyn PacketTransport>,
+    rng: SmallRng,
     in_progress_files: HashMap<FileId, File>,
-    final_files: Vec<File>
+    final_files: Vec<File>,
+    buffered_bytes_limit: Option<usize>,
+    log_arrivals: bool,
+    evicted_files: Vec<(FileId, Option<String>, PathBuf)>,
+    output_dir: Option<PathBuf>,
+    filename_transform: Option<FilenameTransform>,
+    naming_template: String,
+    subdir_policy: SubdirPolicy,
+    durable: bool,
+    write_concurrency: usize,
+    write_timeout: Option<Duration>,
+    journal: Option<Journal>,
+    filesystem: Arc<dyn FileSystem>,
+    request_burst: usize,
+    on_complete: Option<CompletionCallback>,
+    payload_transform: Option<PayloadTransform>,
+    on_socket_error: Option<SocketErrorCallback>,
+    trace: Option<TraceWriter>,
+    #[cfg(feature = "pcap")]
+    pcap: Option<super::pcap::PcapWriter>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+    generations: HashMap<FileId, u32>,
+    skip_existing: bool,
+    drop_repeated_files: bool,
+    auto_finalize: bool,
+    skipped_files: HashMap<FileId, String>,
+    expected_files: Option<HashSet<String>>,
+    unexpected_file_policy: UnexpectedFilePolicy,
+    allowed_extensions: Option<HashSet<String>>,
+    disallowed_extension_policy: DisallowedExtensionPolicy,
+    rejected_files: HashMap<FileId, String>,
+    control_char_policy: ControlCharPolicy,
+    segment_conflict_policy: SegmentConflictPolicy,
+    hash_algorithm: HashAlgorithm,
+    short_file_policy: ShortFilePolicy,
+    nameless_policy: NamelessPolicy,
+    filename_encoding: FilenameEncoding,
+    endianness: Endianness,
+    bad_header_policy: BadHeaderPolicy,
+    bad_headers: HashSet<FileId>,
+    prefix_bytes: Option<u64>,
+    prefixed_files: HashSet<FileId>,
+    seen_file_ids: HashSet<FileId>,
+    completed_file_ids: HashSet<FileId>,
+    max_retransmits_per_file: Option<u32>,
+    failed_files: HashMap<FileId, IncompleteFile>,
+    #[cfg(feature = "hmac")]
+    hmac_key: Option<Vec<u8>>,
+    #[cfg(feature = "zip")]
+    zip_compression: zip::CompressionMethod,
+    unexpected_files: Vec<String>,
+    warnings: Vec<Warning>,
+    clock: Box<dyn Clock>,
+    server_caps: Option<ServerCaps>,
+    segment_size: usize,
+    recv_buffer: Vec<u8>,
+    paused: std::sync::atomic::AtomicBool,
+    bytes_received: u64,
+    max_total_bytes: Option<u64>,
+    throughput_history: VecDeque<(Instant, u64)>,
+    throughput_history_len: usize,
+    rate_limit: Option<u64>,
+    rate_limit_start: Option<(Instant, u64)>,
+    started: Option<Instant>,
+    last_datagram: Option<Instant>,
+    quiet_period: Duration,
+    files_completed: usize,
+    duplicate_packets: u64,
+    reorder_window: Option<usize>,
+    window_drops: u64,
+    datagrams_received: u64,
+    max_packet_number: Option<PacketNumber>
 }
 
 impl Client {
     pub fn new(udp_socket: std::net::UdpSocket) -> Self {
+        Self::with_transport(udp_socket)
+    }
+
+    /// Seed the client's random numbers, so anything random it does, like
+    /// jittering backoff, happens the same way each run. They're seeded from
+    /// the clock otherwise.
+    pub fn with_rng_seed(mut self, seed: u64) -> Self {
+        self.rng = SmallRng::seed_from_u64(seed);
+        self
+    }
+
+    /// The client's random numbers, for anything working with it that
+    /// needs some
+    pub fn rng(&mut self) -> &mut SmallRng {
+        &mut self.rng
+    }
+
+    /// A client receiving over something other than a UDP socket
+    pub fn with_transport(transport: impl PacketTransport + 'static) -> Self {
+        #[cfg(feature = "tracing")]
+        let span = transfer_span(&transport);
+
         Self {
-            udp_socket,
+            transport: Box::new(transport),
+            rng: SmallRng::from_entropy(),
             in_progress_files: HashMap::new(),
             final_files: Vec::new(),
+            buffered_bytes_limit: None,
+            log_arrivals: false,
+            evicted_files: Vec::new(),
+            output_dir: None,
+            filename_transform: None,
+            naming_template: String::from("{name}"),
+            subdir_policy: SubdirPolicy::default(),
+            durable: false,
+            write_concurrency: 1,
+            write_timeout: None,
+            journal: None,
+            filesystem: Arc::new(StdFileSystem),
+            request_burst: 1,
+            on_complete: None,
+            payload_transform: None,
+            on_socket_error: None,
+            trace: None,
+            #[cfg(feature = "pcap")]
+            pcap: None,
+            #[cfg(feature = "tracing")]
+            span,
+            generations: HashMap::new(),
+            skip_existing: false,
+            drop_repeated_files: false,
+            auto_finalize: true,
+            skipped_files: HashMap::new(),
+            expected_files: None,
+            unexpected_file_policy: UnexpectedFilePolicy::default(),
+            allowed_extensions: None,
+            disallowed_extension_policy: DisallowedExtensionPolicy::default(),
+            rejected_files: HashMap::new(),
+            control_char_policy: ControlCharPolicy::default(),
+            segment_conflict_policy: SegmentConflictPolicy::default(),
+            hash_algorithm: HashAlgorithm::default(),
+            short_file_policy: ShortFilePolicy::default(),
+            nameless_policy: NamelessPolicy::default(),
+            filename_encoding: FilenameEncoding::default(),
+            endianness: Endianness::default(),
+            bad_header_policy: BadHeaderPolicy::default(),
+            bad_headers: HashSet::new(),
+            prefix_bytes: None,
+            prefixed_files: HashSet::new(),
+            seen_file_ids: HashSet::new(),
+            completed_file_ids: HashSet::new(),
+            max_retransmits_per_file: None,
+            failed_files: HashMap::new(),
+            #[cfg(feature = "hmac")]
+            hmac_key: None,
+            #[cfg(feature = "zip")]
+            zip_compression: zip::CompressionMethod::Deflated,
+            unexpected_files: Vec::new(),
+            warnings: Vec::new(),
+            clock: Box::new(SystemClock),
+            server_caps: None,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            recv_buffer: vec![0; DEFAULT_SEGMENT_SIZE + DATA_HEADER_LEN],
+            paused: std::sync::atomic::AtomicBool::new(false),
+            bytes_received: 0,
+            max_total_bytes: None,
+            throughput_history: VecDeque::new(),
+            throughput_history_len: 64,
+            rate_limit: None,
+            rate_limit_start: None,
+            started: None,
+            last_datagram: None,
+            quiet_period: Duration::from_secs(5),
+            files_completed: 0,
+            duplicate_packets: 0,
+            reorder_window: None,
+            window_drops: 0,
+            datagrams_received: 0,
+            max_packet_number: None
         }
     }
 
-    pub fn send_request(&mut self) -> Result<(), String> {
-        self.udp_socket.send(&[0]).map_err(|e| format!("unable to send request over socket {}", e))?;
-        Ok(())
+    /// Forget every file, finished or not, so another request can be made.
+    /// The socket, settings and anything learned about the server are kept.
+    pub fn reset(&mut self) {
+        self.in_progress_files.clear();
+        self.final_files.clear();
+        self.evicted_files.clear();
+        self.bytes_received = 0;
+        self.throughput_history.clear();
+        self.unexpected_files.clear();
+        self.warnings.clear();
+        self.generations.clear();
+        self.skipped_files.clear();
+        self.rejected_files.clear();
+        self.bad_headers.clear();
+        self.prefixed_files.clear();
+        self.seen_file_ids.clear();
+        self.completed_file_ids.clear();
+        self.failed_files.clear();
+        self.rate_limit_start = None;
+        self.started = None;
+        self.last_datagram = None;
+        self.files_completed = 0;
+        self.duplicate_packets = 0;
+        self.window_drops = 0;
+        self.datagrams_received = 0;
+        self.max_packet_number = None;
+        #[cfg(feature = "tracing")]
+        {
+            self.span = transfer_span(self.transport.as_ref());
+        }
     }
 
-    fn read_data(&mut self) -> Result<Vec<u8>, String> {
-        let mut buf = [0; 1024 + 4];
-        match self.udp_socket.recv_from(&mut buf) {
-            Ok((size, _)) => Ok(buf[..size].to_vec()),
-            Err(e) => Err(format!("unable to recieve data over socket {}", e))
-        }
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Box::new(clock);
     }
 
-    fn get_mut_file_id(&mut self, file_id: FileId) -> &mut File {
-        if !self.in_progress_files.contains_key(&file_id) {
-            self.in_progress_files.insert(file_id, File::new(file_id));
-        }
+    /// Write finished files under `dir` instead of the working directory
+    pub fn set_output_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.output_dir = Some(dir.into());
+    }
 
-        if let Some(file) = self.in_progress_files.get_mut(&file_id) {
-            file
-        }
-        else {
-            unreachable!()
+    /// Rewrite each file name just before it is written, e.g. to add a prefix
+    pub fn set_filename_transform(&mut self, transform: impl Fn(&str) -> String + 'static) {
+        self.filename_transform = Some(Box::new(transform));
+    }
+
+    /// Name output files from a template instead of using the server's names
+    /// as they are. The placeholders are:
+    ///
+    /// - `{id}` the file id
+    /// - `{name}` the server's name for the file, or `file_{id}` without one
+    /// - `{index}` the order the file finished in, counting from 0
+    /// - `{ext}` the extension of the name without the dot, which may be empty
+    ///
+    /// Write `{{` and `}}` for literal braces. The template only renames the
+    /// last part of the name, so directories the server sends are kept for
+    /// the subdir policy. Any filename transform runs on the result.
+    pub fn set_naming_template(&mut self, template: &str) -> Result<(), SegFsError> {
+        if template.contains(std::path::is_separator) {
+            return Err(format!("naming template {} can't contain a path separator", template).into());
         }
+        render_template(template, 0, "", 0)?;
+
+        self.naming_template = template.to_string();
+        Ok(())
     }
 
-    fn move_complete_files(&mut self) -> Result<(), String> {
-        let mut transition_files = Vec::new();
+    pub fn set_subdir_policy(&mut self, policy: SubdirPolicy) {
+        self.subdir_policy = policy;
+    }
 
-        for file in self.in_progress_files.values_mut() {
-            if file.is_done() {
-                transition_files.push(file.file_id);
-            }
-        }
+    /// Sync each file to disk once it's written, so it survives a crash
+    pub fn set_durable(&mut self, durable: bool) {
+        self.durable = durable;
+    }
 
-        for id in transition_files {
-            if let Some(file) = self.in_progress_files.remove(&id) {
-                self.final_files.push(file);
-            }
-        }
+    /// Write up to `files` completed files at once when finalizing, 1 by
+    /// default. Each file is still written by a single thread.
+    pub fn set_write_concurrency(&mut self, files: usize) {
+        self.write_concurrency = files.max(1);
+    }
 
-        Ok(())
+    /// Keep at most `bytes` of completed files waiting in memory. Past that
+    /// the oldest are written out straight away, as `finalize_file` would,
+    /// and only their paths are kept, in `evicted_files`. They still count
+    /// towards `file_count`, but `finalize_files` and the archive writers
+    /// only deal with files still in memory. No limit by default.
+    pub fn set_buffered_bytes_limit(&mut self, bytes: Option<usize>) {
+        self.buffered_bytes_limit = bytes;
     }
 
-    pub fn recv_packet(&mut self) -> Result<(), String> {
-        let data = self.read_data()?;
+    /// Completed files already written out to keep memory down, and where
+    /// they went, oldest first
+    pub fn evicted_files(&self) -> impl Iterator<Item = (FileId, &std::path::Path)> {
+        self.evicted_files.iter().map(|(id, _, path)| (*id, path.as_path()))
+    }
 
-        if data.len() == 0 {
-            return Err(format!("data packet has zero length"));
-        }
+    /// Give up on writing a file once it takes longer than `timeout`, for
+    /// output on slow network mounts. With a timeout each file is written to
+    /// a `.part` file on a thread of its own and renamed into place when
+    /// done; on giving up the `.part` file is removed and finalizing fails
+    /// with `SegFsError::WriteTimeout`. The thread is left to finish or fail
+    /// by itself. No timeout by default.
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.write_timeout = timeout;
+    }
 
-        if data[0] & 1 > 0 {
-            let packet = DataPacket::try_from(data)?;
-            self.get_mut_file_id(packet.file_id).report_data_packet(packet);
-        }
-        else {
-            let packet = HeaderPacket::try_from(data)?;
-            self.get_mut_file_id(packet.file_id).report_header_packet(packet);
+    /// Keep a journal at `path` of the files being written, each going
+    /// through a `.part` file renamed into place once it's whole, so after a
+    /// crash `recover_journal` can tell which writes were cut short. Off by
+    /// default.
+    pub fn set_journal(&mut self, path: Option<&std::path::Path>) {
+        self.journal = path.map(Journal::new);
+    }
+
+    /// Write finished files through `filesystem` rather than straight to the
+    /// disk. The journal, if there is one, is always kept on the disk.
+    pub fn set_filesystem(&mut self, filesystem: impl FileSystem + 'static) {
+        self.filesystem = Arc::new(filesystem);
+    }
+
+    /// Clean up the writes a crashed run left unfinished in the journal and
+    /// start it afresh, handing back those writes so their files can be
+    /// downloaded again. Meant to be called before receiving anything.
+    pub fn recover_journal(&self) -> Result<Vec<InFlight>, SegFsError> {
+        match &self.journal {
+            Some(journal) => journal.recover(),
+            None => Ok(Vec::new())
         }
+    }
 
-        self.move_complete_files()?;
+    pub fn set_on_complete(&mut self, on_complete: impl FnMut(FileId, &str, usize) + 'static) {
+        self.on_complete = Some(Box::new(on_complete));
+    }
 
-        Ok(())
+    /// Change each data packet's payload before it's stored, to decrypt it
+    /// for instance. The transform sees packets in the order they arrive,
+    /// which needn't be the order they were sent, and may see the same
+    /// packet more than once, so it has to work from the file id and packet
+    /// number alone rather than on what came before. Stored payloads are
+    /// left as they came by default.
+    pub fn set_payload_transform(&mut self, transform: impl FnMut(FileId, PacketNumber, &[u8]) -> Vec<u8> + 'static) {
+        self.payload_transform = Some(Box::new(transform));
     }
 
-    pub fn file_count(&self) -> usize {
-        self.final_files.len()
-    }
-
-    pub fn finalize_files(self) -> Result<(), String> {
-        use std::io::prelude::*;
-
-        for file in self.final_files {
-            if let Some(filename) = file.name {
-                let mut file_io = std::fs::File::create(&filename).map_err(|e| format!("unable to create file {}: {}", &filename, e))?;
-            
-                if let Some(last_packet) = file.max_segments {
-                    for id in 0..=last_packet {
-                        if let Some(data) = file.segments.get(&id) {
-                            file_io.write_all(data).map_err(|e| format!("unable to write to file {}", e))?;
-                        }
-                        else {
-                            return Err(format!("unable to write file {}, bad data at packet id {}", &filename, id));
-                        }
-                    }
-                }
-            }
-            else {
-                return Err(format!("unable to write file id {}, no name", file.file_id));
-            }
-        }
+    /// Decide what happens when reading the socket fails, timeouts
+    /// included, in place of `ErrorAction::default_for`
+    pub fn set_on_socket_error(&mut self, on_socket_error: impl FnMut(&std::io::Error) -> ErrorAction + 'static) {
+        self.on_socket_error = Some(Box::new(on_socket_error));
+    }
 
-        Ok(())
+    /// Don't download files that are already in the output directory. Their
+    /// packets are dropped and they count as complete without being written.
+    pub fn set_skip_existing(&mut self, skip_existing: bool) {
+        self.skip_existing = skip_existing;
     }
 
-    pub fn print_line_length(&self) -> usize {
-        2 + self.in_progress_files.len() + self.final_files.len()
+    /// Throw away files that finish under a name that's already complete, for
+    /// when the request is sent again and the server starts over
+    pub fn set_drop_repeated_files(&mut self, drop_repeated_files: bool) {
+        self.drop_repeated_files = drop_repeated_files;
     }
-}
 
-impl std::fmt::Display for Client {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "In Progress:")?;
-        for file in self.in_progress_files.values() {
-            writeln!(f, "  {}", file)?;
-        }
-        writeln!(f, "Done:")?;
-        for file in self.final_files.iter() {
-            if let Some(name) = &file.name {
-                writeln!(f, "  {}", name)?;
-            }
-            else {
-                writeln!(f, "  <BAD FILE>")?;
-            }
-        }
-        Ok(())
-    }   
-}
\ No newline at end of file
+    /// Keep the order every file's packets arrived in, for debugging, see
+    /// `File::arrival_order`. Only files started afterwards are logged. Off
+    /// by default, since it grows with every packet.
+    pub fn set_log_arrivals(&mut self, log_arrivals: bool) {
+        self.log_arrivals = log_arrivals;
+    }
+
+    /// Leave complete files in progress until `promote_complete` is called,
+    /// so their segments can still be looked at. On by default.
+    pub fn set_auto_finalize(&mut self, auto_finalize: bool) {
+        self.auto_finalize = auto_finalize;
+    }
+
+    /// Names of the files skipped because they were already on disk
+    pub fn skipped_files(&self) -> impl Iterator<Item = &str> {
+        self.skipped_files.values().map(|name| name.as_str())
+    }
+
+    /// Only these file names should be sent, anything else is handled by the
+    /// unexpected file policy as its header arrives
+    pub fn expect_files(&mut self, names: &[&str]) {
+        self.expected_files = Some(names.iter().map(|name| name.to_string()).collect());
+    }
+
+    pub fn set_unexpected_file_policy(&mut self, policy: UnexpectedFilePolicy) {
+        self.unexpected_file_policy = policy;
+    }
+
+    /// Only accept files with one of these extensions, given without the dot
+    /// and matched ignoring case. Others are handled by the disallowed
+    /// extension policy as their header arrives. `None` allows anything.
+    pub fn set_allowed_extensions(&mut self, extensions: Option<&[&str]>) {
+        self.allowed_extensions = extensions.map(|extensions| extensions.iter().map(|extension| extension.to_lowercase()).collect());
+    }
+
+    pub fn set_disallowed_extension_policy(&mut self, policy: DisallowedExtensionPolicy) {
+        self.disallowed_extension_policy = policy;
+    }
+
+    /// Names of the files dropped because their extension isn't allowed
+    pub fn rejected_files(&self) -> impl Iterator<Item = &str> {
+        self.rejected_files.values().map(|name| name.as_str())
+    }
+
+    fn extension_allowed(&self, name: &str) -> bool {
+        let Some(allowed) = &self.allowed_extensions else {
+            return true;
+        };
+        std::path::Path::new(name).extension()
+            .map(|extension| allowed.contains(&extension.to_string_lossy().to_lowercase()))
+            .unwrap_or(false)
+    }
+
+    /// How header names are decoded, strict UTF-8 by default
+    pub fn set_filename_encoding(&mut self, encoding: FilenameEncoding) {
+        self.filename_encoding = encoding;
+    }
+
+    /// The byte order packet numbers and sizes are sent in, big endian as
+    /// the protocol says by default
+    pub fn set_endianness(&mut self, endianness: Endianness) {
+        self.endianness = endianness;
+    }
+
+    /// Only accept packets signed with `key`. Each datagram must then end
+    /// with a 32 byte HMAC-SHA256 of the rest of the datagram, which is
+    /// checked and stripped before the packet is parsed.
+    #[cfg(feature = "hmac")]
+    pub fn set_hmac_key(&mut self, key: Option<Vec<u8>>) {
+        self.hmac_key = key;
+        self.recv_buffer = vec![0; self.max_datagram_len()];
+    }
+
+    /// Decide whether a header with a name that can't be decoded stops the
+    /// transfer or only affects its own file
+    pub fn set_bad_header_policy(&mut self, policy: BadHeaderPolicy) {
+        self.bad_header_policy = policy;
+    }
+
+    /// Decide how control characters in file names are handled. They're
+    /// replaced by default so a name can't mess with the progress display.
+    pub fn set_control_char_policy(&mut self, policy: ControlCharPolicy) {
+        self.control_char_policy = policy;
+    }
+
+    /// Decide which copy of a segment is kept when two arrive with different
+    /// contents. Either way a `Warning::ConflictingSegment` is recorded.
+    pub fn set_segment_conflict_policy(&mut self, policy: SegmentConflictPolicy) {
+        self.segment_conflict_policy = policy;
+    }
+
+    /// Decide what's written for a file with less data than its header
+    /// said it has. By default it isn't written at all.
+    pub fn set_short_file_policy(&mut self, policy: ShortFilePolicy) {
+        self.short_file_policy = policy;
+    }
+
+    /// Decide what's written for a completed file the server never named.
+    /// By default it isn't written and finalizing fails.
+    pub fn set_nameless_policy(&mut self, policy: NamelessPolicy) {
+        self.nameless_policy = policy;
+    }
+
+    /// How completed files are hashed in the JSON report, CRC-32 by default
+    pub fn set_hash_algorithm(&mut self, algorithm: HashAlgorithm) {
+        self.hash_algorithm = algorithm;
+    }
+
+    /// Everything odd the server has done so far, oldest first
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// Hand back the warnings so far and start a fresh list
+    pub fn take_warnings(&mut self) -> Vec<Warning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Names sent by the server that weren't expected, under `UnexpectedFilePolicy::Warn`
+    pub fn unexpected_files(&self) -> &[String] {
+        &self.unexpected_files
+    }
+
+    /// Expected names which haven't been completed, sorted
+    pub fn missing_expected_files(&self) -> Vec<String> {
+        let mut missing: Vec<String> = self.expected_files.iter()
+            .flatten()
+            .filter(|name| !self.final_files.iter().any(|file| file.name.as_ref() == Some(*name)))
+            .cloned()
+            .collect();
+        missing.sort();
+        missing
+    }
+
+    /// The path a file should be written to relative to the output directory.
+    /// Names which would escape it, like `../x` or `/x`, are always rejected.
+    fn relative_name(&self, name: &str) -> Result<PathBuf, SegFsError> {
+        use std::path::Component;
+
+        let path = std::path::Path::new(name);
+        let unsafe_name = || SegFsError::from(format!("refusing to write file with unsafe name {}", name));
+
+        let path = match self.subdir_policy {
+            SubdirPolicy::Flatten => PathBuf::from(path.file_name().ok_or_else(unsafe_name)?),
+            SubdirPolicy::CreateDirs => {
+                if !path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+                    return Err(unsafe_name());
+                }
+                path.to_path_buf()
+            }
+            SubdirPolicy::Reject => {
+                if path.components().count() != 1 || path.file_name().is_none() {
+                    return Err(format!("refusing to write file {}, it names a directory", name).into());
+                }
+                path.to_path_buf()
+            }
+        };
+
+        Ok(match &self.filename_transform {
+            Some(transform) => PathBuf::from(transform(&path.to_string_lossy())),
+            None => path
+        })
+    }
+
+    /// A file's name after the naming template is applied
+    fn templated_name(&self, file_id: FileId, name: Option<&str>, index: usize) -> Result<String, SegFsError> {
+        let fallback = format!("file_{}", file_id);
+        let name = name.unwrap_or(&fallback);
+        let (dir, base) = match name.rfind('/') {
+            Some(split) => name.split_at(split + 1),
+            None => ("", name)
+        };
+
+        let rendered = render_template(&self.naming_template, file_id, base, index)?;
+        if rendered.is_empty() {
+            return Err(format!("naming template {} gives file id {} an empty name", self.naming_template, file_id).into());
+        }
+        Ok(format!("{}{}", dir, rendered))
+    }
+
+    fn output_path(&self, name: &str) -> Result<PathBuf, SegFsError> {
+        let name = self.relative_name(name)?;
+
+        Ok(match &self.output_dir {
+            Some(dir) => dir.join(name),
+            None => name
+        })
+    }
+
+    /// How many copies of the request `send_request` sends back to back, 1
+    /// by default. A few more improve the odds one gets through to a lossy
+    /// server, and some servers start sooner for them.
+    pub fn set_request_burst(&mut self, count: usize) {
+        self.request_burst = count.max(1);
+    }
+
+    pub fn send_request(&mut self) -> Result<(), SegFsError> {
+        self.send_request_burst(self.request_burst)
+    }
+
+    /// Send the request `count` times back to back
+    pub fn send_request_burst(&mut self, count: usize) -> Result<(), SegFsError> {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(parent: &self.span, count, "sending request");
+        for _ in 0..count {
+            self.transport.send(&[0]).map_err(|e| self.socket_error("unable to send request over socket", e))?;
+        }
+        self.started.get_or_insert(self.clock.now());
+        Ok(())
+    }
+
+    /// Where the server should pick each known file up from: after the
+    /// segments received in order for files in progress, and nowhere for
+    /// completed ones
+    pub fn resume_request(&self) -> ResumeRequest {
+        let mut files: Vec<ResumePoint> = self.in_progress_files.values()
+            .map(|file| ResumePoint {
+                file_id: file.file_id,
+                next_packet: file.contiguous_segments().min(RESUME_DONE as usize - 1) as PacketNumber
+            })
+            .chain(self.final_files.iter().map(|file| ResumePoint { file_id: file.file_id, next_packet: RESUME_DONE }))
+            .collect();
+        files.sort_by_key(|point| point.file_id);
+        files.dedup_by_key(|point| point.file_id);
+        ResumeRequest { files }
+    }
+
+    /// The span transfer events are recorded under
+    #[cfg(feature = "tracing")]
+    pub fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
+    /// Carry on a transfer recorded to a trace with `record_to`: replay it,
+    /// then ask the server to resume rather than start over. If the server
+    /// sends everything again anyway, the repeats are dropped.
+    pub fn resume_from(&mut self, path: impl AsRef<std::path::Path>) -> Result<(), SegFsError> {
+        self.replay_from(path)?;
+        self.set_drop_repeated_files(true);
+
+        let request = self.resume_request().to_bytes();
+        self.transport.send(&request).map_err(|e| self.socket_error("unable to send resume request over socket", e))?;
+        self.started.get_or_insert(self.clock.now());
+        Ok(())
+    }
+
+    /// Give up on a file once `request_retransmit` has asked for it this
+    /// many times and it still has gaps. No limit by default.
+    pub fn set_max_retransmits_per_file(&mut self, max: Option<u32>) {
+        self.max_retransmits_per_file = max;
+    }
+
+    /// Ask the server again for the segments still missing from files in
+    /// progress, using a resume request. Files that have used up their
+    /// retransmits are failed instead: they're dropped, along with any more
+    /// of their packets, and listed in `failed_files`. Returns the ids of
+    /// the files failed by this call.
+    pub fn request_retransmit(&mut self) -> Result<Vec<FileId>, SegFsError> {
+        let mut gappy: Vec<FileId> = self.in_progress_files.values()
+            .filter(|file| file.missing_segments().map_or(file.current_gaps() > 0, |missing| !missing.is_empty()))
+            .map(|file| file.file_id)
+            .collect();
+        gappy.sort();
+
+        let mut failed = Vec::new();
+        for file_id in gappy {
+            let file = self.in_progress_files.get_mut(&file_id).expect("only ids of files in progress");
+            if self.max_retransmits_per_file.is_some_and(|max| file.retransmits >= max) {
+                let file = self.in_progress_files.remove(&file_id).expect("only ids of files in progress");
+                self.failed_files.insert(file_id, IncompleteFile {
+                    file_id,
+                    name: file.name.clone(),
+                    received_segments: file.segments.len(),
+                    missing_segments: file.missing_segments()
+                });
+                failed.push(file_id);
+            }
+            else {
+                file.retransmits += 1;
+            }
+        }
+
+        let request = self.resume_request().to_bytes();
+        self.transport.send(&request).map_err(|e| self.socket_error("unable to send retransmit request over socket", e))?;
+        Ok(failed)
+    }
+
+    /// Files given up on by `request_retransmit`, sorted by id
+    pub fn failed_files(&self) -> Vec<IncompleteFile> {
+        let mut files: Vec<IncompleteFile> = self.failed_files.values().cloned().collect();
+        files.sort_by_key(|file| file.file_id);
+        files
+    }
+
+    /// The address the socket is connected to, which shows which of a
+    /// hostname's addresses was picked
+    pub fn remote_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        self.transport.peer_addr()
+    }
+
+    fn socket_error(&self, context: &str, e: std::io::Error) -> SegFsError {
+        match e.kind() {
+            std::io::ErrorKind::ConnectionRefused => SegFsError::ServerUnreachable(self.transport.peer_addr().ok()),
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(parent: &self.span, "timed out waiting for data");
+                SegFsError::Timeout
+            }
+            _ => format!("{} {}", context, e).into()
+        }
+    }
+
+    /// How long a receive waits before failing with `SegFsError::Timeout`,
+    /// `None` waits forever
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), SegFsError> {
+        self.transport.set_read_timeout(timeout).map_err(|e| format!("unable to set socket timeout {}", e))?;
+        Ok(())
+    }
+
+    /// Ask the kernel for a `bytes` sized receive buffer (`SO_RCVBUF`) so a
+    /// fast server doesn't overflow it while we're busy. The OS treats this as
+    /// a hint: Linux doubles the value and caps it at `net.core.rmem_max`,
+    /// other platforms have their own limits, so check with
+    /// `recv_buffer_size` to see what was actually granted.
+    pub fn set_recv_buffer_size(&mut self, bytes: usize) -> Result<(), SegFsError> {
+        self.socket()?.set_recv_buffer_size(bytes).map_err(|e| format!("unable to set receive buffer size {}", e))?;
+        Ok(())
+    }
+
+    /// The receive buffer size the kernel is currently using for the socket
+    pub fn recv_buffer_size(&self) -> Result<usize, SegFsError> {
+        Ok(self.socket()?.recv_buffer_size().map_err(|e| format!("unable to read receive buffer size {}", e))?)
+    }
+
+    fn socket(&self) -> Result<socket2::SockRef<'_>, SegFsError> {
+        self.transport.socket().ok_or_else(|| String::from("the transport has no socket").into())
+    }
+
+    /// Send the request and check whether the server replies with its
+    /// capabilities first. Servers that start sending files straight away are
+    /// fine too, the first packet is handled as usual and no caps are stored.
+    /// An announced segment size only ever grows the receive buffer, so
+    /// headers with long names still fit, and one too big for a datagram
+    /// is refused.
+    pub fn handshake(&mut self) -> Result<ServerCaps, SegFsError> {
+        self.send_request()?;
+
+        let data = self.read_data()?;
+        match ServerCaps::parse(&data) {
+            Some(caps) => {
+                if let Some(segment_size) = caps.segment_size {
+                    if segment_size > MAX_SEGMENT_SIZE {
+                        return Err(format!("server announced a segment size of {} bytes, more than a datagram can hold", segment_size).into());
+                    }
+                    self.segment_size = segment_size.max(DEFAULT_SEGMENT_SIZE);
+                    self.recv_buffer = vec![0; self.max_datagram_len()];
+                }
+                self.server_caps = Some(caps);
+                Ok(caps)
+            }
+            None => {
+                self.process_datagram(data)?;
+                Ok(ServerCaps::default())
+            }
+        }
+    }
+
+    pub fn server_caps(&self) -> Option<&ServerCaps> {
+        self.server_caps.as_ref()
+    }
+
+    /// The file ids past the `expected` many the server should be sending,
+    /// a sign it's misconfigured or the stream carries someone else's
+    /// transfer too. There's no telling which ids are the extra ones, so
+    /// this guesses: ids whose files completed, or were skipped as already
+    /// there, are taken as expected first, then the rest from the lowest id
+    /// up. Whatever's left over is returned, lowest first.
+    pub fn unexpected_file_ids(&self, expected: usize) -> Vec<FileId> {
+        let mut ids: Vec<_> = self.seen_file_ids.iter().copied().collect();
+        ids.sort_by_key(|id| (!self.completed_file_ids.contains(id) && !self.skipped_files.contains_key(id), *id));
+
+        let mut unexpected = ids.split_off(expected.min(ids.len()));
+        unexpected.sort();
+        unexpected
+    }
+
+    /// The highest packet number in any data packet so far, including ones
+    /// that were dropped. One close to `PacketNumber::MAX` is more likely a
+    /// server speaking some other protocol, or up to no good, than a real
+    /// file that size.
+    pub fn max_packet_number_seen(&self) -> Option<PacketNumber> {
+        self.max_packet_number
+    }
+
+    /// The number of files the server announced in its handshake reply, if it did
+    pub fn announced_file_count(&self) -> Option<usize> {
+        self.server_caps.and_then(|caps| caps.file_count)
+    }
+
+    /// How many files to wait for: the announced count when there is one,
+    /// since the server knows best, otherwise `requested`
+    pub fn target_file_count(&self, requested: usize) -> usize {
+        self.announced_file_count().unwrap_or(requested)
+    }
+
+    fn read_data(&mut self) -> Result<Vec<u8>, SegFsError> {
+        self.with_datagram(|_, data| Ok(data.to_vec()))
+    }
+
+    /// Stop reading the socket: receives fail with `SegFsError::Paused`
+    /// straight away and leave datagrams where they are. The server doesn't
+    /// know, so once the OS receive buffer fills anything more it sends is
+    /// lost and has to be asked for again.
+    pub fn pause(&self) {
+        self.paused.store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// The longest datagram the server should send, a full segment with its
+    /// packet header and, when packets are signed, the MAC after it
+    pub fn max_datagram_len(&self) -> usize {
+        #[cfg(feature = "hmac")]
+        let mac_len = if self.hmac_key.is_some() { MAC_LEN } else { 0 };
+        #[cfg(not(feature = "hmac"))]
+        let mac_len = 0;
+
+        self.segment_size + DATA_HEADER_LEN + mac_len
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    /// Receive a datagram into the reusable buffer and hand it to `f`, so the
+    /// only copy made is of whatever `f` keeps
+    fn with_datagram<T>(&mut self, f: impl FnOnce(&mut Self, &[u8]) -> Result<T, SegFsError>) -> Result<T, SegFsError> {
+        if self.is_paused() {
+            return Err(SegFsError::Paused);
+        }
+
+        let mut buf = std::mem::take(&mut self.recv_buffer);
+        let result = self.recv_into(&mut buf).and_then(|size| f(self, &buf[..size]));
+        self.recv_buffer = buf;
+        result
+    }
+
+    fn recv_into(&mut self, buf: &mut [u8]) -> Result<usize, SegFsError> {
+        #[cfg_attr(not(feature = "pcap"), allow(unused_variables))]
+        let (size, peer) = loop {
+            let e = match self.transport.recv_from(buf) {
+                Ok(received) => break received,
+                Err(e) => e
+            };
+
+            let action = match self.on_socket_error.as_mut() {
+                Some(on_socket_error) => on_socket_error(&e),
+                None => ErrorAction::default_for(&e)
+            };
+            match action {
+                ErrorAction::Retry => continue,
+                ErrorAction::Abort => return Err(self.socket_error("unable to recieve data over socket", e)),
+                ErrorAction::Skip => return Err(SegFsError::Timeout)
+            }
+        };
+        self.datagrams_received += 1;
+
+        if let Some(trace) = self.trace.as_mut() {
+            trace.record(&buf[..size])?;
+        }
+
+        #[cfg(feature = "pcap")]
+        if let (Some(pcap), Some(peer)) = (self.pcap.as_mut(), peer) {
+            let local = self.transport.local_addr().map_err(|e| format!("unable to get local address {}", e))?;
+            pcap.record(peer, local, &buf[..size])?;
+        }
+
+        Ok(size)
+    }
+
+    /// Append every datagram received from now on to a trace file
+    pub fn record_to(&mut self, path: impl AsRef<std::path::Path>) -> Result<(), SegFsError> {
+        self.trace = Some(TraceWriter::open(path.as_ref())?);
+        Ok(())
+    }
+
+    /// Write every datagram received from now on to a pcap capture, for
+    /// looking at in Wireshark
+    #[cfg(feature = "pcap")]
+    pub fn capture_pcap(&mut self, path: impl AsRef<std::path::Path>) -> Result<(), SegFsError> {
+        self.pcap = Some(super::pcap::PcapWriter::create(path.as_ref())?);
+        Ok(())
+    }
+
+    /// Process every datagram in a trace as if it had just been received
+    pub fn replay_from(&mut self, path: impl AsRef<std::path::Path>) -> Result<(), SegFsError> {
+        for datagram in read_trace(path.as_ref())? {
+            self.process_datagram(datagram)?;
+        }
+        Ok(())
+    }
+
+    fn get_mut_file_id(&mut self, file_id: FileId) -> &mut File {
+        let generation = self.generations.get(&file_id).copied().unwrap_or(0);
+        let log_arrivals = self.log_arrivals;
+        self.in_progress_files.entry(file_id).or_insert_with(|| {
+            let mut file = File::new(file_id);
+            file.generation = generation;
+            if log_arrivals {
+                file.log_arrivals();
+            }
+            file
+        })
+    }
+
+    /// Note that the file currently using an id is finished with, so the
+    /// next one is treated as a new file
+    fn retire_file_id(&mut self, file_id: FileId) {
+        *self.generations.entry(file_id).or_insert(0) += 1;
+    }
+
+    /// Move finished files out of progress, returning how many there were.
+    /// Does nothing while auto finalize is off.
+    fn move_complete_files(&mut self) -> Result<usize, SegFsError> {
+        if !self.auto_finalize {
+            return Ok(0);
+        }
+        self.promote_complete()
+    }
+
+    /// Move every finished file out of progress whether or not auto finalize
+    /// is on, returning how many there were
+    pub fn promote_complete(&mut self) -> Result<usize, SegFsError> {
+        if let Some(limit) = self.prefix_bytes {
+            for file in self.in_progress_files.values_mut() {
+                if file.name.is_some() && file.truncate_to_prefix(limit) {
+                    self.prefixed_files.insert(file.file_id);
+                }
+            }
+        }
+
+        let mut transition_files = Vec::new();
+
+        for file in self.in_progress_files.values_mut() {
+            if file.is_done() {
+                transition_files.push(file.file_id);
+            }
+        }
+
+        transition_files.sort();
+        let mut completed = transition_files.len();
+
+        for id in transition_files {
+            if let Some(mut file) = self.in_progress_files.remove(&id) {
+                self.retire_file_id(id);
+                if self.drop_repeated_files
+                    && (self.final_files.iter().any(|done| done.name == file.name) || self.evicted_files.iter().any(|(_, name, _)| *name == file.name)) {
+                    completed -= 1;
+                    continue;
+                }
+                file.completion_index = self.files_completed;
+                self.files_completed += 1;
+                self.completed_file_ids.insert(file.file_id);
+                #[cfg(feature = "tracing")]
+                tracing::info!(parent: &self.span, file_id = file.file_id, name = file.name.as_deref(), bytes = file.data_len(), "file complete");
+                if let Some(on_complete) = self.on_complete.as_mut() {
+                    on_complete(file.file_id, file.name.as_deref().unwrap_or_default(), file.data_len());
+                }
+                self.final_files.push(file);
+            }
+        }
+
+        self.evict_buffered()?;
+        Ok(completed)
+    }
+
+    /// Write out the oldest completed files until what's left in memory is
+    /// under the buffered bytes limit
+    fn evict_buffered(&mut self) -> Result<(), SegFsError> {
+        let Some(limit) = self.buffered_bytes_limit else {
+            return Ok(());
+        };
+
+        self.skip_nameless();
+        let mut buffered: usize = self.final_files.iter().map(File::data_len).sum();
+        while buffered > limit && !self.final_files.is_empty() {
+            let path = self.finalize(&self.final_files[0])?;
+            let file = self.final_files.remove(0);
+            buffered -= file.data_len();
+            self.evicted_files.push((file.file_id, file.name, path));
+        }
+        Ok(())
+    }
+
+    pub fn recv_packet(&mut self) -> Result<(), SegFsError> {
+        self.recv_packet_counted()?;
+        Ok(())
+    }
+
+    /// Receive a packet, returning how many files it completed
+    pub fn recv_packet_counted(&mut self) -> Result<usize, SegFsError> {
+        let completed = self.with_datagram(|client, data| {
+            let packet = client.parse_datagram(data)?;
+            client.process_packet(packet)
+        })?;
+        self.throttle();
+        Ok(completed)
+    }
+
+    /// Like `recv_packet`, but also hands back the packet that was received
+    pub fn recv_packet_parsed(&mut self) -> Result<Packet, SegFsError> {
+        let packet = self.with_datagram(|client, data| {
+            let packet = client.parse_datagram(data)?;
+            client.process_packet(packet.clone())?;
+            Ok(packet)
+        })?;
+        self.throttle();
+        Ok(packet)
+    }
+
+    /// Only keep segments up to `window` past the first one a file is still
+    /// waiting for, dropping anything further ahead. This caps how much a
+    /// file missing an early segment can hold, but the dropped segments
+    /// have to be sent again, so a small window on a link that reorders a
+    /// lot means more retransmits. `None`, the default, keeps everything.
+    pub fn set_reorder_window(&mut self, window: Option<usize>) {
+        self.reorder_window = window;
+    }
+
+    /// Only download the first `limit` bytes of each file, for sniffing its
+    /// type or making a thumbnail. Once a file's leading segments cover that
+    /// much it's cut down to exactly `limit` bytes and completes, and any
+    /// more packets for it are dropped. The protocol has no way to tell the
+    /// server to stop, so it carries on sending them. Files shorter than
+    /// `limit` are downloaded whole. Off by default.
+    pub fn set_prefix_bytes(&mut self, limit: Option<u64>) {
+        self.prefix_bytes = limit;
+    }
+
+    /// Fail with `SegFsError::TransferTooLarge` once more than `limit` bytes
+    /// have been received in total, counting whole datagrams. There's no
+    /// limit by default.
+    pub fn set_max_total_bytes(&mut self, limit: Option<u64>) {
+        self.max_total_bytes = limit;
+    }
+
+    /// Limit how fast the socket is drained, in bytes per second. While the
+    /// client sleeps the server keeps sending, and the OS can drop packets
+    /// once its receive buffer fills.
+    pub fn set_rate_limit(&mut self, bytes_per_second: Option<u64>) {
+        self.rate_limit = bytes_per_second.filter(|&rate| rate > 0);
+        self.rate_limit_start = Some((self.clock.now(), self.bytes_received));
+    }
+
+    /// Sleep long enough to keep the average rate since the limit was set, or
+    /// the client was reset, under the limit
+    fn throttle(&mut self) {
+        let Some(rate) = self.rate_limit else {
+            return;
+        };
+
+        let now = self.clock.now();
+        let (start, start_bytes) = *self.rate_limit_start.get_or_insert((now, 0));
+
+        let allowed = Duration::from_secs_f64((self.bytes_received - start_bytes) as f64 / rate as f64);
+        let elapsed = now.duration_since(start);
+        if allowed > elapsed {
+            self.clock.sleep(allowed - elapsed);
+        }
+    }
+
+    /// Parse a raw datagram and file it away as if it had just been received
+    pub fn process_datagram(&mut self, data: Vec<u8>) -> Result<(), SegFsError> {
+        let packet = self.parse_datagram(&data)?;
+        self.process_packet(packet)?;
+        Ok(())
+    }
+
+    fn parse_datagram(&mut self, data: &[u8]) -> Result<Packet, SegFsError> {
+        self.bytes_received += data.len() as u64;
+        if let Some(limit) = self.max_total_bytes.filter(|&limit| self.bytes_received > limit) {
+            return Err(SegFsError::TransferTooLarge { limit, received: self.bytes_received });
+        }
+        self.started.get_or_insert(self.clock.now());
+        self.last_datagram = Some(self.clock.now());
+
+        let sample = self.sample_throughput();
+        if self.throughput_history.len() >= self.throughput_history_len {
+            self.throughput_history.pop_front();
+        }
+        self.throughput_history.push_back(sample);
+
+        #[cfg(feature = "hmac")]
+        let data = self.verify_mac(data)?;
+
+        match Packet::parse_with(data, self.filename_encoding, self.endianness) {
+            Ok(Packet::Header(header)) => {
+                self.bad_headers.remove(&header.file_id);
+                Ok(Packet::Header(header))
+            }
+            Ok(packet) => Ok(packet),
+            Err(e) => self.recover_bad_header(data, e)
+        }
+    }
+
+    /// Check the MAC at the end of a datagram when a key is set, handing back
+    /// the packet without it
+    #[cfg(feature = "hmac")]
+    fn verify_mac<'d>(&self, data: &'d [u8]) -> Result<&'d [u8], SegFsError> {
+        use hmac::Mac;
+
+        let Some(key) = &self.hmac_key else {
+            return Ok(data);
+        };
+
+        if data.len() < MAC_LEN {
+            return Err(format!("packet of length {} is too short to carry an hmac", data.len()).into());
+        }
+
+        let (packet, tag) = data.split_at(data.len() - MAC_LEN);
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(key).map_err(|e| format!("unusable hmac key {}", e))?;
+        mac.update(packet);
+        mac.verify_slice(tag).map_err(|_| "packet failed its hmac check".to_string())?;
+        Ok(packet)
+    }
+
+    /// Apply the bad header policy to a datagram that failed to parse, if it
+    /// was a header which only failed because of its name
+    fn recover_bad_header(&mut self, data: &[u8], error: SegFsError) -> Result<Packet, SegFsError> {
+        let Ok(Packet::Header(mut header)) = Packet::parse_with(data, FilenameEncoding::Lossy, self.endianness) else {
+            return Err(error);
+        };
+
+        match self.bad_header_policy {
+            BadHeaderPolicy::Abort => return Err(error),
+            BadHeaderPolicy::SkipFile => {
+                self.bad_headers.insert(header.file_id);
+            }
+            BadHeaderPolicy::Synthesize => {
+                header.name = match self.generations.get(&header.file_id) {
+                    Some(generation) if *generation > 0 => format!("file_{}_{}", header.file_id, generation),
+                    _ => format!("file_{}", header.file_id)
+                };
+            }
+        }
+
+        self.warnings.push(Warning::BadHeader { file_id: header.file_id, error: error.to_string() });
+        Ok(Packet::Header(header))
+    }
+
+    /// The current time and the total bytes received so far
+    pub fn sample_throughput(&self) -> (Instant, u64) {
+        (self.clock.now(), self.bytes_received)
+    }
+
+    /// The most recent samples, oldest first, one taken per datagram
+    pub fn throughput_history(&self) -> impl Iterator<Item = &(Instant, u64)> {
+        self.throughput_history.iter()
+    }
+
+    /// How many throughput samples to keep, 64 by default
+    pub fn set_throughput_history_len(&mut self, len: usize) {
+        self.throughput_history_len = len.max(1);
+        while self.throughput_history.len() > self.throughput_history_len {
+            self.throughput_history.pop_front();
+        }
+    }
+
+    /// File a packet away, returning how many files it completed
+    fn process_packet(&mut self, packet: Packet) -> Result<usize, SegFsError> {
+        let now = self.clock.now();
+        self.seen_file_ids.insert(match &packet {
+            Packet::Data(packet) => packet.file_id,
+            Packet::Header(packet) => packet.file_id
+        });
+
+        let skipped = match packet {
+            Packet::Data(packet) => {
+                self.process_data_packet(packet, now)?;
+                0
+            }
+            Packet::Header(packet) => self.process_header_packet(packet, now)?
+        };
+
+        Ok(skipped + self.move_complete_files()?)
+    }
+
+    fn process_data_packet(&mut self, mut packet: DataPacket, now: Instant) -> Result<(), SegFsError> {
+        self.max_packet_number = self.max_packet_number.max(Some(packet.packet_number));
+        if self.skipped_files.contains_key(&packet.file_id)
+            || self.rejected_files.contains_key(&packet.file_id)
+            || self.bad_headers.contains(&packet.file_id)
+            || self.prefixed_files.contains(&packet.file_id)
+            || self.failed_files.contains_key(&packet.file_id) {
+            return Ok(());
+        }
+
+        if let Some(transform) = self.payload_transform.as_mut() {
+            packet.data = transform(packet.file_id, packet.packet_number, &packet.data);
+        }
+
+        let window = self.reorder_window;
+        let conflict_policy = self.segment_conflict_policy;
+        #[cfg(feature = "tracing")]
+        tracing::trace!(parent: &self.span, file_id = packet.file_id, packet_number = packet.packet_number, bytes = packet.data.len(), is_last = packet.is_last, "data packet");
+
+        let file = self.get_mut_file_id(packet.file_id);
+        let (file_id, packet_number, size) = (packet.file_id, packet.packet_number, packet.data.len());
+
+        if let Some(window) = window {
+            if packet_number as usize > file.contiguous_segments() + window {
+                file.last_activity = Some(now);
+                self.window_drops += 1;
+                return Ok(());
+            }
+        }
+
+        let existing = file.segments.get(&packet_number);
+        let duplicate = existing.is_some();
+        let conflicting = existing.is_some_and(|existing| *existing != packet.data);
+        if conflicting && conflict_policy != SegmentConflictPolicy::Overwrite {
+            file.last_activity = Some(now);
+            self.duplicate_packets += 1;
+            self.warnings.push(Warning::ConflictingSegment { file_id, packet_number });
+            if conflict_policy == SegmentConflictPolicy::Error {
+                return Err(format!("file id {} sent packet {} again with different contents", file_id, packet_number).into());
+            }
+            return Ok(());
+        }
+
+        let had_anomaly = file.size_anomaly;
+        let past_last = file.max_segments.filter(|&last| packet_number > last);
+        let below_received = file.highest_packet_number().filter(|&highest| packet.is_last && highest > packet_number);
+
+        file.report_data_packet(packet);
+        file.last_activity = Some(now);
+
+        if !had_anomaly && file.size_anomaly {
+            self.warnings.push(Warning::SegmentSizeAnomaly { file_id, packet_number, size });
+        }
+        if let Some(last) = past_last {
+            self.warnings.push(Warning::PastLastPacket { file_id, packet_number, last });
+        }
+        if let Some(highest) = below_received {
+            self.warnings.push(Warning::LastBelowReceived { file_id, last: packet_number, highest });
+        }
+        if conflicting {
+            self.duplicate_packets += 1;
+            self.warnings.push(Warning::ConflictingSegment { file_id, packet_number });
+        }
+        else if duplicate {
+            self.duplicate_packets += 1;
+            self.warnings.push(Warning::DuplicatePacket { file_id, packet_number });
+        }
+        Ok(())
+    }
+
+    /// Returns 1 if the header's file is skipped because it's already on
+    /// disk, since that counts as it completing
+    fn process_header_packet(&mut self, mut packet: HeaderPacket, now: Instant) -> Result<usize, SegFsError> {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(parent: &self.span, file_id = packet.file_id, name = packet.name, expected_size = packet.expected_size, "header packet");
+
+        if self.bad_headers.contains(&packet.file_id) {
+            self.in_progress_files.remove(&packet.file_id);
+            return Ok(0);
+        }
+        if self.failed_files.contains_key(&packet.file_id) || self.prefixed_files.contains(&packet.file_id) {
+            return Ok(0);
+        }
+
+        if packet.name.chars().any(char::is_control) {
+            match self.control_char_policy {
+                ControlCharPolicy::Replace => {
+                    let replaced = packet.name.chars().map(|c| if c.is_control() { '_' } else { c }).collect();
+                    let name = std::mem::replace(&mut packet.name, replaced);
+                    self.warnings.push(Warning::ControlCharsReplaced { file_id: packet.file_id, name });
+                }
+                ControlCharPolicy::Reject => return Err(format!("file id {} has control characters in its name {:?}", packet.file_id, packet.name).into()),
+                ControlCharPolicy::Allow => {}
+            }
+        }
+
+        if let Some(expected) = &self.expected_files {
+            if !expected.contains(&packet.name) {
+                match self.unexpected_file_policy {
+                    UnexpectedFilePolicy::Error => return Err(SegFsError::UnexpectedFile(packet.name)),
+                    UnexpectedFilePolicy::Warn => {
+                        self.unexpected_files.push(packet.name.clone());
+                        self.warnings.push(Warning::UnexpectedFile { name: packet.name.clone() });
+                    }
+                }
+            }
+        }
+
+        match self.rejected_files.get(&packet.file_id) {
+            Some(name) if *name == packet.name => return Ok(0),
+            Some(_) => {
+                self.rejected_files.remove(&packet.file_id);
+            }
+            None => {}
+        }
+
+        if !self.extension_allowed(&packet.name) {
+            match self.disallowed_extension_policy {
+                DisallowedExtensionPolicy::Error => return Err(format!("file id {} {} doesn't have an allowed extension", packet.file_id, packet.name).into()),
+                DisallowedExtensionPolicy::Drop => {
+                    self.in_progress_files.remove(&packet.file_id);
+                    self.warnings.push(Warning::DisallowedExtension { file_id: packet.file_id, name: packet.name.clone() });
+                    self.rejected_files.insert(packet.file_id, packet.name);
+                    return Ok(0);
+                }
+            }
+        }
+
+        match self.skipped_files.get(&packet.file_id) {
+            Some(name) if *name == packet.name => return Ok(0),
+            Some(_) => {
+                self.skipped_files.remove(&packet.file_id);
+            }
+            None => {}
+        }
+
+        // A different name under an id that's in use means the server
+        // has reused it, so don't mix segments from the two files
+        let current_name = self.in_progress_files.get(&packet.file_id).and_then(|file| file.name.clone());
+        match current_name {
+            Some(name) if name == packet.name => {
+                self.warnings.push(Warning::DuplicateHeader { file_id: packet.file_id, name });
+            }
+            Some(old_name) => {
+                self.in_progress_files.remove(&packet.file_id);
+                self.retire_file_id(packet.file_id);
+                self.warnings.push(Warning::FileIdReused { file_id: packet.file_id, old_name, new_name: packet.name.clone() });
+            }
+            None => {}
+        }
+
+        if self.skip_existing && self.already_written(&packet) {
+            self.in_progress_files.remove(&packet.file_id);
+            self.retire_file_id(packet.file_id);
+            self.skipped_files.insert(packet.file_id, packet.name);
+            return Ok(1);
+        }
+
+        let file = self.get_mut_file_id(packet.file_id);
+        file.report_header_packet(packet);
+        file.last_activity = Some(now);
+        Ok(0)
+    }
+
+    /// Whether the file a header describes is already in the output
+    /// directory, with the right size if the header gives one
+    fn already_written(&self, packet: &HeaderPacket) -> bool {
+        // The file would be given the next index if it finished now
+        let path = self.templated_name(packet.file_id, Some(&packet.name), self.files_completed)
+            .and_then(|name| self.output_path(&name));
+        let Ok(path) = path else {
+            return false;
+        };
+
+        match self.filesystem.metadata(&path) {
+            Ok(metadata) => metadata.is_file && packet.expected_size.map(|size| size == metadata.len).unwrap_or(true),
+            Err(_) => false
+        }
+    }
+
+    /// Receive packets one by one as an iterator, which never ends by itself
+    pub fn packets(&mut self) -> Packets<'_> {
+        Packets { client: self, target: None, failed: false }
+    }
+
+    /// Receive packets until `file_count` files are complete
+    pub fn packets_until(&mut self, file_count: usize) -> Packets<'_> {
+        Packets { client: self, target: Some(file_count), failed: false }
+    }
+
+    /// Files that have received data but no last packet, and have heard
+    /// nothing for at least `idle`
+    pub fn never_finalized(&self, idle: Duration) -> Vec<NeverFinalized> {
+        let now = self.clock.now();
+        let mut files: Vec<_> = self.in_progress_files.values()
+            .filter(|file| file.max_segments.is_none() && !file.segments.is_empty())
+            .filter(|file| file.last_activity.map(|last| now.duration_since(last) >= idle).unwrap_or(false))
+            .map(|file| NeverFinalized { file_id: file.file_id, segments: file.segments.len() })
+            .collect();
+        files.sort_by_key(|file| file.file_id);
+        files
+    }
+
+    /// Files that have a name but no data at all, and have heard nothing for
+    /// at least `idle`. They can't complete by themselves, and are most
+    /// likely empty files whose one zero length packet went missing.
+    pub fn stalled_header_only(&self, idle: Duration) -> Vec<(FileId, String)> {
+        let now = self.clock.now();
+        let mut files: Vec<_> = self.in_progress_files.values()
+            .filter(|file| file.segments.is_empty())
+            .filter(|file| file.last_activity.map(|last| now.duration_since(last) >= idle).unwrap_or(false))
+            .filter_map(|file| Some((file.file_id, file.name.clone()?)))
+            .collect();
+        files.sort();
+        files
+    }
+
+    /// Segments still to arrive across every in-progress file, or `None`
+    /// while any of them is waiting on its last packet to say how many it has
+    pub fn segments_remaining(&self) -> Option<usize> {
+        self.in_progress_files.values()
+            .map(|file| file.max_segments.map(|max| (max as usize + 1).saturating_sub(file.segments.len())))
+            .sum()
+    }
+
+    /// Segments received for every file, finished or not
+    pub fn segments_received(&self) -> usize {
+        self.in_progress_files.values()
+            .chain(self.final_files.iter())
+            .map(|file| file.segments.len())
+            .sum()
+    }
+
+    /// Gaps in the segments received for a file so far, see `File::current_gaps`
+    pub fn current_gaps(&self, id: FileId) -> Option<usize> {
+        self.file(id).map(|file| file.current_gaps())
+    }
+
+    /// How long since the last header or segment arrived for a file, `None`
+    /// if the id hasn't been seen
+    pub fn file_idle(&self, id: FileId) -> Option<Duration> {
+        let last = self.file(id)?.last_activity?;
+        Some(self.clock.now().duration_since(last))
+    }
+
+    /// Drop in-progress files which have heard nothing for longer than
+    /// `max_idle`, returning their ids
+    pub fn prune_stuck_files(&mut self, max_idle: Duration) -> Vec<FileId> {
+        let now = self.clock.now();
+        let mut stuck: Vec<FileId> = self.in_progress_files.values()
+            .filter(|file| file.last_activity.map(|last| now.duration_since(last) > max_idle).unwrap_or(false))
+            .map(|file| file.file_id)
+            .collect();
+        stuck.sort();
+
+        for id in stuck.iter() {
+            self.in_progress_files.remove(id);
+        }
+
+        stuck
+    }
+
+    /// What's left of every in-progress file, by id
+    pub fn incomplete_files(&self) -> Vec<IncompleteFile> {
+        let mut files: Vec<IncompleteFile> = self.in_progress_files.values()
+            .map(|file| IncompleteFile {
+                file_id: file.file_id,
+                name: file.name.clone(),
+                received_segments: file.segments.len(),
+                missing_segments: file.missing_segments()
+            })
+            .collect();
+        files.sort_by_key(|file| file.file_id);
+        files
+    }
+
+    /// Treat the highest packet received for a file as its last one, for
+    /// servers that never send it. Returns whether the file is now complete.
+    pub fn force_finalize(&mut self, file_id: FileId) -> Result<bool, SegFsError> {
+        let file = self.in_progress_files.get_mut(&file_id)
+            .ok_or_else(|| format!("no file in progress with id {}", file_id))?;
+
+        let highest = file.highest_packet_number()
+            .ok_or_else(|| format!("unable to finalize file id {}, no data received", file_id))?;
+        file.max_segments = Some(highest);
+
+        self.move_complete_files()?;
+        Ok(!self.in_progress_files.contains_key(&file_id))
+    }
+
+    /// Complete a file that has a name but no data as an empty file, see
+    /// `stalled_header_only`. Returns whether the file is now complete.
+    pub fn finalize_as_empty(&mut self, file_id: FileId) -> Result<bool, SegFsError> {
+        let file = self.in_progress_files.get_mut(&file_id)
+            .ok_or_else(|| format!("no file in progress with id {}", file_id))?;
+        if !file.segments.is_empty() {
+            return Err(format!("unable to finalize file id {} as empty, it has data", file_id).into());
+        }
+
+        file.insert_segment(0, Vec::new());
+        file.max_segments = Some(0);
+
+        self.move_complete_files()?;
+        Ok(!self.in_progress_files.contains_key(&file_id))
+    }
+
+    /// Look up a file by id. An id can be both completed and in progress if
+    /// the server sent more packets for it afterwards, in which case the in
+    /// progress file is the one returned since it's the most recent.
+    pub fn file(&self, id: FileId) -> Option<&File> {
+        self.in_progress_files.get(&id)
+            .or_else(|| self.final_files.iter().find(|file| file.file_id == id))
+    }
+
+    pub fn stats(&self) -> ClientStats {
+        let missing_segments = self.in_progress_files.values()
+            .filter_map(|file| file.max_segments.map(|max| max as usize + 1 - file.segments.len().min(max as usize + 1)))
+            .sum();
+
+        ClientStats {
+            files_completed: self.files_completed,
+            files_in_progress: self.in_progress_files.len(),
+            bytes_received: self.bytes_received,
+            missing_segments,
+            duplicate_packets: self.duplicate_packets,
+            window_drops: self.window_drops,
+            datagrams_received: self.datagrams_received,
+            elapsed: self.started.map(|started| self.clock.now().duration_since(started)).unwrap_or_default()
+        }
+    }
+
+    /// A single line summing up the transfer for logs, in the stable format
+    /// `segfs: 3 files, 12345 bytes, 0 missing, 2 duplicates, 1.23s`
+    pub fn summary_line(&self) -> String {
+        let stats = self.stats();
+        format!("segfs: {} files, {} bytes, {} missing, {} duplicates, {:.2}s",
+                stats.files_completed,
+                stats.bytes_received,
+                stats.missing_segments,
+                stats.duplicate_packets,
+                stats.elapsed.as_secs_f64())
+    }
+
+    /// A JSON document listing the completed and incomplete files along with
+    /// the transfer stats, for scripts that can't read the progress display
+    pub fn to_json(&self) -> String {
+        let stats = self.stats();
+
+        let completed: Vec<String> = self.final_files.iter()
+            .map(|file| format!("{{\"id\":{},\"name\":{},\"size\":{},\"hash\":\"{}\"}}",
+                                file.file_id,
+                                json_string(file.name.as_deref()),
+                                file.data_len(),
+                                file.content_hash(self.hash_algorithm).iter().map(|b| format!("{:02x}", b)).collect::<String>()))
+            .collect();
+
+        let mut in_progress: Vec<&File> = self.in_progress_files.values().collect();
+        in_progress.sort_by_key(|file| file.file_id);
+        let incomplete: Vec<String> = in_progress.iter()
+            .map(|file| format!("{{\"id\":{},\"name\":{},\"segments\":{},\"total_segments\":{}}}",
+                                file.file_id,
+                                json_string(file.name.as_deref()),
+                                file.segments.len(),
+                                file.max_segments.map(|max| (max as usize + 1).to_string()).unwrap_or_else(|| String::from("null"))))
+            .collect();
+
+        let failed: Vec<String> = self.failed_files().iter()
+            .map(|file| format!("{{\"id\":{},\"name\":{},\"missing_segments\":{}}}",
+                                file.file_id,
+                                json_string(file.name.as_deref()),
+                                file.missing_segments.as_ref()
+                                    .map(|missing| format!("[{}]", missing.iter().map(|number| number.to_string()).collect::<Vec<_>>().join(",")))
+                                    .unwrap_or_else(|| String::from("null"))))
+            .collect();
+
+        format!("{{\"completed\":[{}],\"incomplete\":[{}],\"failed\":[{}],\"bytes_received\":{},\"missing_segments\":{},\"duplicate_packets\":{},\"elapsed_secs\":{:.3}}}",
+                completed.join(","),
+                incomplete.join(","),
+                failed.join(","),
+                stats.bytes_received,
+                stats.missing_segments,
+                stats.duplicate_packets,
+                stats.elapsed.as_secs_f64())
+    }
+
+    /// How long without any packets before the server is assumed to have
+    /// sent everything it's going to, 5 seconds by default
+    pub fn set_quiet_period(&mut self, quiet_period: Duration) {
+        self.quiet_period = quiet_period;
+    }
+
+    /// A best guess at how many files the server is sending, once there's
+    /// reason to think it has finished: either every file seen is complete,
+    /// or nothing has arrived for the quiet period. Until then it's `None`.
+    pub fn likely_total_files(&self) -> Option<usize> {
+        let seen = self.files_completed + self.skipped_files.len() + self.in_progress_files.len();
+        let quiet = self.last_datagram
+            .map(|last| self.clock.now().duration_since(last) >= self.quiet_period)
+            .unwrap_or(false);
+
+        if seen > 0 && (self.in_progress_files.is_empty() || quiet) {
+            Some(seen)
+        }
+        else {
+            None
+        }
+    }
+
+    /// The id and name of each completed file waiting to be written, in the
+    /// order they finished. Files can't complete without a name, so none are
+    /// left out.
+    pub fn completed_files(&self) -> impl Iterator<Item = (FileId, &str)> {
+        self.final_files.iter().filter_map(|file| Some((file.file_id, file.name.as_deref()?)))
+    }
+
+    /// The ids of the completed files held in memory waiting to be written,
+    /// in the order they finished, nameless ones included. Each can be
+    /// written with `finalize_file`, or all of them with `finalize_files_ref`.
+    pub fn pending_finalize(&self) -> Vec<FileId> {
+        self.final_files.iter().map(|file| file.file_id).collect()
+    }
+
+    /// The contents of the completed file called `name`, or `None` if there
+    /// isn't one. If the server sent the name more than once, the file that
+    /// finished first is used. A file whose size doesn't match its header
+    /// also gives `None` unless the short file policy allows it, as writing
+    /// it would fail.
+    pub fn completed_file_bytes(&self, name: &str) -> Option<Vec<u8>> {
+        let file = self.final_files.iter().find(|file| file.name.as_deref() == Some(name))?;
+        file.assemble_with(self.short_file_policy).ok()
+    }
+
+    /// Check that every file id seen ended up complete, failing with
+    /// `SegFsError::Incomplete` listing the ones that didn't. An id with a
+    /// completed file doesn't count against this even if stray packets
+    /// started it over.
+    pub fn reconcile(&self) -> Result<(), SegFsError> {
+        let stragglers: Vec<IncompleteFile> = self.incomplete_files().into_iter()
+            .filter(|file| !self.final_files.iter().any(|done| done.file_id == file.file_id))
+            .collect();
+
+        if stragglers.is_empty() {
+            return Ok(());
+        }
+        Err(SegFsError::Incomplete {
+            completed: self.file_count(),
+            expected: self.file_count() + stragglers.len(),
+            files: stragglers
+        })
+    }
+
+    /// Files that are complete, including any skipped as already on disk
+    pub fn file_count(&self) -> usize {
+        self.final_files.len() + self.evicted_files.len() + self.skipped_files.len()
+    }
+
+    /// Whether at least `target` files are complete. More than `target`
+    /// counts too, in case the server sends extra files.
+    pub fn is_batch_complete(&self, target: usize) -> bool {
+        self.file_count() >= target
+    }
+
+    /// Whether every file the server announced in its handshake is complete.
+    /// Without an announced count there's nothing to go on, so this stays
+    /// false and the caller has to pick a target for `is_batch_complete`.
+    pub fn is_complete(&self) -> bool {
+        self.announced_file_count().is_some_and(|count| self.is_batch_complete(count))
+    }
+
+    /// How many more files are needed to reach `target`, never less than 0
+    pub fn remaining(&self, target: usize) -> usize {
+        target.saturating_sub(self.file_count())
+    }
+
+    pub fn finalize_files(mut self) -> Result<(), SegFsError> {
+        self.finalize_files_ref()
+    }
+
+    /// Write out every completed file, dropping them from the client as they
+    /// are written so it can keep receiving. A file that fails to write is
+    /// kept, along with any not tried yet, so they can be tried again. With
+    /// a write concurrency above 1 the files are shared out between that
+    /// many threads, and a failed write doesn't stop the others; the first
+    /// failure in completion order is the one returned.
+    pub fn finalize_files_ref(&mut self) -> Result<(), SegFsError> {
+        self.skip_nameless();
+        if self.write_concurrency <= 1 || self.final_files.len() <= 1 {
+            let mut written = 0;
+            let result = loop {
+                match self.final_files.get(written) {
+                    Some(file) => match self.finalize(file) {
+                        Ok(_) => written += 1,
+                        Err(e) => break Err(e)
+                    },
+                    None => break Ok(())
+                }
+            };
+            self.final_files.drain(..written);
+            return result;
+        }
+
+        let files = std::mem::take(&mut self.final_files);
+        let results = self.finalize_concurrently(&files);
+        let mut first_error = None;
+        for (file, result) in files.into_iter().zip(results) {
+            if let Err(e) = result {
+                first_error.get_or_insert(e);
+                self.final_files.push(file);
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+
+    /// Write out every completed file, carrying on past failures, and report
+    /// what happened to each, along with any skipped because they were
+    /// already there or by the nameless policy. A file that fails to write is
+    /// kept so it can be tried again. Files skipped for already being there
+    /// are listed every time, until a reset.
+    pub fn finalize_batch(&mut self) -> BatchResult {
+        let mut batch = BatchResult::default();
+        for file in self.final_files.iter().filter(|file| self.skips_nameless(file)) {
+            batch.files.push(FileResult { file_id: file.file_id, path: None, bytes: file.data_len(), crc: None, status: FileStatus::Skipped });
+        }
+        self.skip_nameless();
+
+        let files = std::mem::take(&mut self.final_files);
+        let results = match self.write_concurrency <= 1 {
+            true => files.iter().map(|file| self.finalize(file)).collect(),
+            false => self.finalize_concurrently(&files)
+        };
+        for (file, result) in files.into_iter().zip(results) {
+            let (path, crc, status) = match result {
+                Ok(path) => (Some(path), Some(file.crc32()), FileStatus::Written),
+                Err(e) => (None, None, FileStatus::Failed(e.to_string()))
+            };
+            batch.files.push(FileResult { file_id: file.file_id, path, bytes: file.data_len(), crc, status: status.clone() });
+            if status != FileStatus::Written {
+                self.final_files.push(file);
+            }
+        }
+
+        let mut existing: Vec<_> = self.skipped_files.iter().collect();
+        existing.sort();
+        for (&file_id, name) in existing {
+            batch.files.push(FileResult { file_id, path: self.output_path(name).ok(), bytes: 0, crc: None, status: FileStatus::Skipped });
+        }
+        batch
+    }
+
+    /// Write `files` out on as many threads as the write concurrency allows,
+    /// giving where each went or why it failed, in the same order
+    fn finalize_concurrently(&self, files: &[File]) -> Vec<Result<PathBuf, SegFsError>> {
+        let mut results = Vec::with_capacity(files.len());
+        let mut jobs = Vec::with_capacity(files.len());
+        for (index, file) in files.iter().enumerate() {
+            match self.prepare_path(file) {
+                Ok(path) => jobs.push((index, file, path)),
+                Err(e) => results.push((index, Err(e)))
+            }
+        }
+
+        let results = std::sync::Mutex::new(results);
+        let next = std::sync::atomic::AtomicUsize::new(0);
+        let durable = self.durable;
+        let timeout = self.write_timeout;
+        let policy = self.short_file_policy;
+        let journal = self.journal.as_ref();
+        let filesystem = &self.filesystem;
+
+        std::thread::scope(|scope| {
+            for _ in 0..self.write_concurrency.min(jobs.len()) {
+                scope.spawn(|| {
+                    while let Some((index, file, path)) = jobs.get(next.fetch_add(1, std::sync::atomic::Ordering::SeqCst)) {
+                        let result = file.assemble_with(policy)
+                            .and_then(|data| write_journaled(filesystem, journal, file.file_id, data, path, durable, timeout))
+                            .map(|_| path.clone());
+                        results.lock().expect("no writer panics holding the lock").push((*index, result));
+                    }
+                });
+            }
+        });
+
+        let mut results = results.into_inner().expect("no writer panics holding the lock");
+        results.sort_by_key(|(index, _)| *index);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+
+    /// Write out one completed file straight away and drop it from the
+    /// client, returning where it went. `None` means no complete file with
+    /// that id is waiting; if the write fails the file is kept.
+    pub fn finalize_file(&mut self, file_id: FileId) -> Result<Option<PathBuf>, SegFsError> {
+        self.skip_nameless();
+        let index = match self.final_files.iter().position(|file| file.file_id == file_id) {
+            Some(index) => index,
+            None => return Ok(None)
+        };
+
+        let path = self.finalize(&self.final_files[index])?;
+        self.final_files.remove(index);
+        Ok(Some(path))
+    }
+
+    /// Write a completed file to its output path, cleaning up after a failed write
+    fn finalize(&self, file: &File) -> Result<PathBuf, SegFsError> {
+        let path = self.prepare_path(file)?;
+        write_journaled(&self.filesystem, self.journal.as_ref(), file.file_id, file.assemble_with(self.short_file_policy)?, &path, self.durable, self.write_timeout)?;
+        Ok(path)
+    }
+
+    /// Where the file the server named `name` will be written, with the
+    /// output directory, naming template and subdirectory policy applied,
+    /// without touching the disk. The template can use the file's id and
+    /// completion order, so the file has to be one the client knows about;
+    /// one still arriving is shown where it would go if it finished next.
+    pub fn output_path_for(&self, name: &str) -> Result<PathBuf, SegFsError> {
+        if let Some(file) = self.final_files.iter().find(|file| file.name.as_deref() == Some(name)) {
+            return self.planned_path(file, file.completion_index);
+        }
+        match self.in_progress_files.values().find(|file| file.name.as_deref() == Some(name)) {
+            Some(file) => self.planned_path(file, self.files_completed),
+            None => Err(format!("no file named {} has been received", name).into())
+        }
+    }
+
+    /// Where a file goes if it's the `index`th to complete
+    fn planned_path(&self, file: &File, index: usize) -> Result<PathBuf, SegFsError> {
+        let name = self.completed_name(file, index)?;
+        self.output_path(&name)
+    }
+
+    /// A file's name with the naming template applied, or for one that never
+    /// got a name, whatever the nameless policy gives it
+    fn completed_name(&self, file: &File, index: usize) -> Result<String, SegFsError> {
+        match (&file.name, self.nameless_policy) {
+            (Some(name), _) => self.templated_name(file.file_id, Some(name), index),
+            (None, NamelessPolicy::Synthesize) => self.templated_name(file.file_id, Some(&format!("file_{}.bin", file.file_id)), index),
+            (None, _) => Err(format!("unable to write file id {}, it has no name", file.file_id).into())
+        }
+    }
+
+    /// Whether a completed file is left out under the nameless policy
+    fn skips_nameless(&self, file: &File) -> bool {
+        file.name.is_none() && self.nameless_policy == NamelessPolicy::Skip
+    }
+
+    /// Drop the completed files the nameless policy leaves out, warning about each
+    fn skip_nameless(&mut self) {
+        let (skipped, kept): (Vec<File>, Vec<File>) = std::mem::take(&mut self.final_files).into_iter().partition(|file| self.skips_nameless(file));
+        self.final_files = kept;
+        for file in skipped.iter() {
+            self.warnings.push(Warning::NamelessFileSkipped { file_id: file.file_id });
+        }
+    }
+
+    /// Where a completed file goes, creating its directories if the subdir
+    /// policy calls for them
+    fn prepare_path(&self, file: &File) -> Result<PathBuf, SegFsError> {
+        let path = self.planned_path(file, file.completion_index)?;
+        if self.subdir_policy == SubdirPolicy::CreateDirs {
+            if let Some(parent) = path.parent() {
+                self.filesystem.create_dir_all(parent).map_err(|e| format!("unable to create directory {}: {}", parent.display(), e))?;
+            }
+        }
+        Ok(path)
+    }
+
+    /// Remove `.part` files left in `dir` by runs that were interrupted
+    /// partway through a write, returning how many went. Only ones untouched
+    /// for `STALE_PARTIAL_AGE` by the client's clock are removed, so another
+    /// run writing to the same directory isn't disturbed.
+    pub fn clean_partials(&self, dir: &std::path::Path) -> std::io::Result<usize> {
+        let now = self.clock.system_now();
+        let mut removed = 0;
+
+        for path in self.filesystem.list(dir)? {
+            if path.extension().is_none_or(|extension| extension != "part") {
+                continue;
+            }
+
+            let metadata = self.filesystem.metadata(&path)?;
+            let age = metadata.modified.and_then(|modified| now.duration_since(modified).ok()).unwrap_or_default();
+            if metadata.is_file && age >= STALE_PARTIAL_AGE {
+                self.filesystem.remove(&path)?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Bundle every completed file into a single tar archive
+    #[cfg(feature = "tar")]
+    pub fn write_tar<W: std::io::Write>(&self, writer: &mut W) -> Result<(), SegFsError> {
+        let mut builder = tar::Builder::new(writer);
+
+        for file in self.final_files.iter().filter(|file| !self.skips_nameless(file)) {
+            let name = self.completed_name(file, file.completion_index)?;
+            let name = self.relative_name(&name)?.to_string_lossy().to_string();
+
+            let data = file.assemble_with(self.short_file_policy)?;
+
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, &name, data.as_slice())
+                .map_err(|e| format!("unable to add {} to archive: {}", &name, e))?;
+        }
+
+        builder.finish().map_err(|e| format!("unable to finish archive: {}", e))?;
+        Ok(())
+    }
+
+    /// How `write_zip` compresses each entry, deflate by default
+    #[cfg(feature = "zip")]
+    pub fn set_zip_compression(&mut self, method: zip::CompressionMethod) {
+        self.zip_compression = method;
+    }
+
+    /// Bundle every completed file into a single zip archive
+    #[cfg(feature = "zip")]
+    pub fn write_zip<W: std::io::Write + std::io::Seek>(&self, writer: &mut W) -> Result<(), SegFsError> {
+        let mut archive = zip::ZipWriter::new(writer);
+        let options = zip::write::SimpleFileOptions::default().compression_method(self.zip_compression);
+
+        for file in self.final_files.iter().filter(|file| !self.skips_nameless(file)) {
+            let name = self.completed_name(file, file.completion_index)?;
+            let name = self.relative_name(&name)?.to_string_lossy().to_string();
+
+            archive.start_file(name.as_str(), options).map_err(|e| format!("unable to add {} to archive: {}", &name, e))?;
+            std::io::Write::write_all(&mut archive, &file.assemble_with(self.short_file_policy)?).map_err(|e| format!("unable to add {} to archive: {}", &name, e))?;
+        }
+
+        archive.finish().map_err(|e| format!("unable to finish archive: {}", e))?;
+        Ok(())
+    }
+
+    /// A rough estimate of how much memory the received segments are using
+    pub fn approx_memory_bytes(&self) -> usize {
+        self.in_progress_files.values()
+            .chain(self.final_files.iter())
+            .map(|file| file.approx_memory_bytes())
+            .sum()
+    }
+
+    pub fn print_line_length(&self) -> usize {
+        2 + self.in_progress_files.len() + self.final_files.len()
+    }
+}
+
+/// Write a file's data to `path` as `write_output` does, noting the write in
+/// the journal if there is one. Journaled writes always go through a `.part`
+/// file, so the one thing a crash can leave behind is that.
+fn write_journaled(filesystem: &Arc<dyn FileSystem>, journal: Option<&Journal>, file_id: FileId, data: Vec<u8>, path: &std::path::Path, durable: bool, timeout: Option<Duration>) -> Result<(), SegFsError> {
+    let Some(journal) = journal else {
+        return write_output(filesystem, data, path, durable, timeout);
+    };
+
+    journal.begin(file_id, path)?;
+    match timeout {
+        Some(_) => write_output(filesystem, data, path, durable, timeout)?,
+        None => {
+            let part = part_path(path);
+            write_or_remove(filesystem.as_ref(), &data, &part, durable)?;
+            filesystem.rename(&part, path).map_err(|e| {
+                let _ = filesystem.remove(&part);
+                format!("unable to move {} into place: {}", part.display(), e)
+            })?;
+        }
+    }
+    journal.done(file_id, path)
+}
+
+/// Write a file's data to `path`, in the background if there's a timeout
+fn write_output(filesystem: &Arc<dyn FileSystem>, data: Vec<u8>, path: &std::path::Path, durable: bool, timeout: Option<Duration>) -> Result<(), SegFsError> {
+    let Some(timeout) = timeout else {
+        return write_or_remove(filesystem.as_ref(), &data, path, durable);
+    };
+
+    let writer = filesystem.clone();
+    write_with_timeout(filesystem, path, timeout, move |part| write_file(writer.as_ref(), &data, part, durable))
+}
+
+/// Run `write` on its own thread against `path` with `.part` added, then
+/// rename that into place. Past `timeout` the `.part` file is removed and
+/// the rename is called off.
+fn write_with_timeout(filesystem: &Arc<dyn FileSystem>, path: &std::path::Path, timeout: Duration, write: impl FnOnce(&std::path::Path) -> Result<(), SegFsError> + Send + 'static) -> Result<(), SegFsError> {
+    let part = part_path(path);
+
+    // Held while renaming and while giving up, so only one of them happens
+    let cancelled = Arc::new(std::sync::Mutex::new(false));
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    let (thread_path, thread_part, thread_cancelled, thread_filesystem) = (path.to_path_buf(), part.clone(), cancelled.clone(), filesystem.clone());
+    std::thread::spawn(move || {
+        let mut result = write(&thread_part);
+        let cancelled = thread_cancelled.lock().expect("nothing panics holding the lock");
+        if result.is_ok() && !*cancelled {
+            result = thread_filesystem.rename(&thread_part, &thread_path)
+                .map_err(|e| format!("unable to move {} into place: {}", thread_part.display(), e).into());
+        }
+        if result.is_err() || *cancelled {
+            let _ = thread_filesystem.remove(&thread_part);
+        }
+        let _ = sender.send(result);
+    });
+
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+            let mut cancelled = cancelled.lock().expect("nothing panics holding the lock");
+            // It may have finished while the lock was being taken
+            if let Ok(result) = receiver.try_recv() {
+                return result;
+            }
+            *cancelled = true;
+            let _ = filesystem.remove(&part);
+            Err(SegFsError::WriteTimeout { path: path.to_path_buf(), timeout })
+        }
+        Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => Err(format!("writer for {} stopped without finishing", path.display()).into())
+    }
+}
+
+/// Write a file's data to `path`, removing whatever was written if it fails
+fn write_or_remove(filesystem: &dyn FileSystem, data: &[u8], path: &std::path::Path, durable: bool) -> Result<(), SegFsError> {
+    let result = write_file(filesystem, data, path, durable);
+    if result.is_err() {
+        let _ = filesystem.remove(path);
+    }
+    result
+}
+
+fn write_file(filesystem: &dyn FileSystem, data: &[u8], path: &std::path::Path, durable: bool) -> Result<(), SegFsError> {
+    filesystem.write(path, data, durable).map_err(|e| format!("unable to write to file {}: {}", path.display(), e))?;
+    Ok(())
+}
+
+/// A span for one transfer, named after the server it's from
+#[cfg(feature = "tracing")]
+fn transfer_span(transport: &dyn PacketTransport) -> tracing::Span {
+    tracing::info_span!("transfer", remote = transport.peer_addr().ok().map(tracing::field::display))
+}
+
+const CRC32_INIT: u32 = 0xffff_ffff;
+
+/// Run more data through a CRC-32, bit by bit since files are only hashed
+/// once. The result still needs inverting at the end.
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+/// Quote a string for JSON output, or write `null` for `None`
+fn json_string(value: Option<&str>) -> String {
+    let Some(value) = value else {
+        return String::from("null");
+    };
+
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Fill in the placeholders described in `Client::set_naming_template`
+fn render_template(template: &str, file_id: FileId, name: &str, index: usize) -> Result<String, SegFsError> {
+    let mut rendered = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find(['{', '}']) {
+        rendered.push_str(&rest[..start]);
+        let tail = &rest[start..];
+
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            rendered.push_str(&tail[..1]);
+            rest = &tail[2..];
+        }
+        else if tail.starts_with('{') {
+            let end = tail.find('}').ok_or_else(|| format!("unclosed placeholder in naming template {}", template))?;
+            match &tail[1..end] {
+                "id" => rendered.push_str(&file_id.to_string()),
+                "name" => rendered.push_str(name),
+                "index" => rendered.push_str(&index.to_string()),
+                "ext" => rendered.push_str(&std::path::Path::new(name).extension().unwrap_or_default().to_string_lossy()),
+                other => return Err(format!("unknown placeholder {{{}}} in naming template {}", other, template).into())
+            }
+            rest = &tail[end + 1..];
+        }
+        else {
+            return Err(format!("unmatched }} in naming template {}", template).into());
+        }
+    }
+
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Iterator over received packets, see `Client::packets`. It stops after
+/// yielding an error.
+pub struct Packets<'a> {
+    client: &'a mut Client,
+    target: Option<usize>,
+    failed: bool
+}
+
+impl Iterator for Packets<'_> {
+    type Item = Result<Packet, SegFsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.target.map(|target| self.client.is_batch_complete(target)).unwrap_or(false) {
+            return None;
+        }
+
+        let packet = self.client.recv_packet_parsed();
+        self.failed = packet.is_err();
+        Some(packet)
+    }
+}
+
+impl std::fmt::Display for Client {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "In Progress:")?;
+        for file in self.in_progress_files.values() {
+            writeln!(f, "  {}", file)?;
+        }
+        writeln!(f, "Done:")?;
+        for file in self.final_files.iter() {
+            if let Some(name) = &file.name {
+                writeln!(f, "  {}", name)?;
+            }
+            else {
+                writeln!(f, "  <BAD FILE>")?;
+            }
+        }
+        Ok(())
+    }   
+}
+
+#[test]
+fn segment_size_anomaly() {
+    let packet = |packet_number: PacketNumber, is_last: bool, size: usize| DataPacket {
+        is_last,
+        file_id: 1,
+        packet_number,
+        data: vec![0; size]
+    };
+
+    let mut file = File::new(1);
+    file.report_data_packet(packet(0, false, 1024));
+    file.report_data_packet(packet(1, false, 1024));
+    file.report_data_packet(packet(3, true, 10));
+    assert_eq!(file.expected_segment_size(), Some(1024));
+    assert!(!file.has_size_anomaly());
+
+    // A short interior packet
+    file.report_data_packet(packet(2, false, 512));
+    assert!(file.has_size_anomaly());
+
+    // A last packet which is bigger than the interior ones, arriving first
+    let mut file = File::new(2);
+    file.report_data_packet(packet(1, true, 1024));
+    assert!(!file.has_size_anomaly());
+    file.report_data_packet(packet(0, false, 512));
+    assert!(file.has_size_anomaly());
+}
+
+#[cfg(test)]
+fn test_client() -> Client {
+    Client::new(std::net::UdpSocket::bind("127.0.0.1:0").unwrap())
+}
+
+#[cfg(test)]
+fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("seg-fs-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn memory_estimate() {
+    let dir = test_dir("memory_estimate");
+
+    let mut client = test_client();
+    client.set_output_dir(&dir);
+    let empty = client.approx_memory_bytes();
+
+    client.process_datagram(DataPacket { is_last: false, file_id: 7, packet_number: 0, data: vec![1; 1024] }.to_bytes()).unwrap();
+    let one = client.approx_memory_bytes();
+    assert!(one >= empty + 1024);
+
+    client.process_datagram(DataPacket { is_last: true, file_id: 7, packet_number: 1, data: vec![2; 100] }.to_bytes()).unwrap();
+    client.process_datagram(HeaderPacket { file_id: 7, name: String::from("out.txt"), expected_size: None }.to_bytes()).unwrap();
+    assert_eq!(client.file_count(), 1);
+    assert!(client.approx_memory_bytes() >= one + 100);
+
+    client.finalize_files_ref().unwrap();
+    assert_eq!(client.approx_memory_bytes(), 0);
+    assert_eq!(std::fs::read(dir.join("out.txt")).unwrap().len(), 1124);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn filename_transform() {
+    let dir = test_dir("filename_transform");
+
+    let mut client = test_client();
+    client.set_output_dir(&dir);
+    client.set_filename_transform(|name| name.to_uppercase());
+
+    client.process_datagram(HeaderPacket { file_id: 3, name: String::from("small.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 3, packet_number: 0, data: b"hello".to_vec() }.to_bytes()).unwrap();
+    client.finalize_files_ref().unwrap();
+
+    assert_eq!(std::fs::read(dir.join("SMALL.TXT")).unwrap(), b"hello");
+    assert!(!dir.join("small.txt").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn never_finalized_detection() {
+    let clock = ManualClock::new();
+    let mut client = test_client();
+    client.set_clock(clock.clone());
+
+    client.process_datagram(HeaderPacket { file_id: 4, name: String::from("a.txt"), expected_size: None }.to_bytes()).unwrap();
+    for packet_number in 0..3 {
+        client.process_datagram(DataPacket { is_last: false, file_id: 4, packet_number, data: vec![1; 8] }.to_bytes()).unwrap();
+    }
+
+    // A file with only a header is waiting for data, not a last packet
+    client.process_datagram(HeaderPacket { file_id: 5, name: String::from("b.txt"), expected_size: None }.to_bytes()).unwrap();
+
+    assert!(client.never_finalized(Duration::from_secs(5)).is_empty());
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(client.never_finalized(Duration::from_secs(5)), vec![NeverFinalized { file_id: 4, segments: 3 }]);
+
+    assert!(client.force_finalize(4).unwrap());
+    assert_eq!(client.file_count(), 1);
+    assert!(client.never_finalized(Duration::from_secs(5)).is_empty());
+    assert!(client.force_finalize(5).is_err());
+}
+
+#[cfg(test)]
+fn loopback_client() -> (Client, std::net::UdpSocket) {
+    let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.connect(server.local_addr().unwrap()).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    (Client::new(socket), server)
+}
+
+#[test]
+fn handshake_with_caps() {
+    let (mut client, server) = loopback_client();
+    let caps = ServerCaps { flags: 1, file_count: Some(2), segment_size: Some(512) };
+
+    let mut buf = [0; 16];
+    let handle = std::thread::spawn(move || {
+        let (_, peer) = server.recv_from(&mut buf).unwrap();
+        server.send_to(&caps.to_bytes(), peer).unwrap();
+    });
+
+    assert_eq!(client.handshake().unwrap(), caps);
+    assert_eq!(client.announced_file_count(), Some(2));
+    assert_eq!(client.target_file_count(5), 2);
+    // Never smaller than a default segment
+    assert_eq!(client.recv_buffer.len(), 1028);
+    handle.join().unwrap();
+
+    for (segment_size, buffer_len) in [(Some(4096), Some(4100)), (Some(65_535), None)] {
+        let (mut client, server) = loopback_client();
+        let caps = ServerCaps { flags: 1, file_count: Some(2), segment_size };
+        let handle = std::thread::spawn(move || {
+            let (_, peer) = server.recv_from(&mut buf).unwrap();
+            server.send_to(&caps.to_bytes(), peer).unwrap();
+        });
+        let result = client.handshake();
+        assert_eq!(result.is_ok(), buffer_len.is_some());
+        assert_eq!(client.recv_buffer.len(), buffer_len.unwrap_or(1028));
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn handshake_without_caps() {
+    let (mut client, server) = loopback_client();
+
+    let mut buf = [0; 16];
+    let handle = std::thread::spawn(move || {
+        let (_, peer) = server.recv_from(&mut buf).unwrap();
+        server.send_to(&HeaderPacket { file_id: 1, name: String::from("a.txt"), expected_size: None }.to_bytes(), peer).unwrap();
+    });
+
+    assert_eq!(client.handshake().unwrap(), ServerCaps::default());
+    assert!(client.server_caps().is_none());
+    assert_eq!(client.announced_file_count(), None);
+    assert_eq!(client.target_file_count(5), 5);
+    assert_eq!(client.in_progress_files[&1].name.as_deref(), Some("a.txt"));
+    handle.join().unwrap();
+}
+
+#[test]
+fn announced_count_completes() {
+    let (mut client, server) = loopback_client();
+    let caps = ServerCaps { flags: 0, file_count: Some(2), segment_size: None };
+    assert!(!client.is_complete());
+
+    let mut buf = [0; 16];
+    let handle = std::thread::spawn(move || {
+        let (_, peer) = server.recv_from(&mut buf).unwrap();
+        server.send_to(&caps.to_bytes(), peer).unwrap();
+        for (id, name) in [(1, "a.txt"), (2, "b.txt")] {
+            server.send_to(&HeaderPacket { file_id: id, name: String::from(name), expected_size: None }.to_bytes(), peer).unwrap();
+            server.send_to(&DataPacket { is_last: true, file_id: id, packet_number: 0, data: b"x".to_vec() }.to_bytes(), peer).unwrap();
+        }
+    });
+
+    client.handshake().unwrap();
+    let mut received = 0;
+    while !client.is_complete() {
+        client.recv_packet().unwrap();
+        received += 1;
+        assert_eq!(client.is_complete(), received == 4);
+    }
+    assert_eq!(client.file_count(), 2);
+    handle.join().unwrap();
+}
+
+#[cfg(feature = "tar")]
+#[test]
+fn tar_archive() {
+    use std::io::Read;
+
+    let mut client = test_client();
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("one.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 1, data: b"world".to_vec() }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 0, data: b"hello ".to_vec() }.to_bytes()).unwrap();
+    client.process_datagram(HeaderPacket { file_id: 2, name: String::from("two.bin"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 2, packet_number: 0, data: vec![0, 1, 2] }.to_bytes()).unwrap();
+
+    let mut archive = Vec::new();
+    client.write_tar(&mut archive).unwrap();
+
+    let mut entries = HashMap::new();
+    for entry in tar::Archive::new(archive.as_slice()).entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let name = entry.path().unwrap().to_string_lossy().to_string();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).unwrap();
+        entries.insert(name, data);
+    }
+
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries["one.txt"], b"hello world");
+    assert_eq!(entries["two.bin"], vec![0, 1, 2]);
+}
+
+#[test]
+fn nested_file_names() {
+    let write_nested = |policy: SubdirPolicy, name: &str| {
+        let dir = test_dir(&format!("nested_file_names_{:?}", policy));
+        let mut client = test_client();
+        client.set_output_dir(&dir);
+        client.set_subdir_policy(policy);
+        client.process_datagram(HeaderPacket { file_id: 1, name: name.to_string(), expected_size: None }.to_bytes()).unwrap();
+        client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 0, data: b"hi".to_vec() }.to_bytes()).unwrap();
+        (client.finalize_files_ref(), dir)
+    };
+
+    let (result, dir) = write_nested(SubdirPolicy::Flatten, "docs/readme.txt");
+    assert!(result.is_ok());
+    assert_eq!(std::fs::read(dir.join("readme.txt")).unwrap(), b"hi");
+    assert!(!dir.join("docs").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let (result, dir) = write_nested(SubdirPolicy::CreateDirs, "docs/readme.txt");
+    assert!(result.is_ok());
+    assert_eq!(std::fs::read(dir.join("docs").join("readme.txt")).unwrap(), b"hi");
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let (result, dir) = write_nested(SubdirPolicy::Reject, "docs/readme.txt");
+    assert!(result.is_err());
+    assert!(std::fs::read_dir(&dir).unwrap().next().is_none());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    for name in ["../readme.txt", "docs/../../readme.txt", "/tmp/readme.txt"] {
+        let (result, dir) = write_nested(SubdirPolicy::CreateDirs, name);
+        assert!(result.is_err(), "{} was accepted", name);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    let (result, dir) = write_nested(SubdirPolicy::Flatten, "..");
+    assert!(result.is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn reset_between_batches() {
+    let dir = test_dir("reset_between_batches");
+    let mut client = test_client();
+    client.set_output_dir(&dir);
+
+    for (batch, name) in ["first.txt", "second.txt"].iter().enumerate() {
+        // A leftover partial file shouldn't carry into the next batch
+        client.process_datagram(DataPacket { is_last: false, file_id: 2, packet_number: 0, data: vec![9] }.to_bytes()).unwrap();
+
+        client.process_datagram(HeaderPacket { file_id: 1, name: name.to_string(), expected_size: None }.to_bytes()).unwrap();
+        client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 0, data: vec![batch as u8] }.to_bytes()).unwrap();
+        assert_eq!(client.file_count(), 1);
+
+        client.finalize_files_ref().unwrap();
+        client.reset();
+        assert_eq!(client.file_count(), 0);
+        assert!(client.in_progress_files.is_empty());
+    }
+
+    assert_eq!(std::fs::read(dir.join("first.txt")).unwrap(), vec![0]);
+    assert_eq!(std::fs::read(dir.join("second.txt")).unwrap(), vec![1]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// Windows reports the refused connection differently
+#[cfg(unix)]
+#[test]
+fn closed_port_is_unreachable() {
+    let closed = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let remote = closed.local_addr().unwrap();
+    drop(closed);
+
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.connect(remote).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    let mut client = Client::new(socket);
+
+    client.send_request().unwrap();
+    match client.recv_packet() {
+        Err(SegFsError::ServerUnreachable(addr)) => assert_eq!(addr, Some(remote)),
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("received a packet from a closed port")
+    }
+}
+
+#[test]
+fn packet_iterator() {
+    let (mut client, server) = loopback_client();
+    client.send_request().unwrap();
+    let mut buf = [0; 16];
+    let (_, peer) = server.recv_from(&mut buf).unwrap();
+
+    let packets = [
+        Packet::Data(DataPacket { is_last: true, file_id: 1, packet_number: 1, data: b"b".to_vec() }),
+        Packet::Header(HeaderPacket { file_id: 1, name: String::from("one.txt"), expected_size: None }),
+        Packet::Data(DataPacket { is_last: false, file_id: 1, packet_number: 0, data: b"a".to_vec() }),
+        Packet::Header(HeaderPacket { file_id: 2, name: String::from("two.txt"), expected_size: None })
+    ];
+    for packet in packets.iter() {
+        server.send_to(&packet.to_bytes(), peer).unwrap();
+    }
+
+    let received: Vec<Packet> = client.packets().take(2).map(|packet| packet.unwrap()).collect();
+    assert_eq!(received, packets[..2]);
+    assert_eq!(client.file_count(), 0);
+
+    // Stops as soon as the first file completes, leaving the last packet unread
+    let received: Vec<Packet> = client.packets_until(1).map(|packet| packet.unwrap()).collect();
+    assert_eq!(received, packets[2..3]);
+    assert_eq!(client.file_count(), 1);
+    assert!(client.packets_until(1).next().is_none());
+}
+
+#[test]
+fn throughput_samples() {
+    let clock = ManualClock::new();
+    let start = clock.now();
+    let mut client = test_client();
+    client.set_clock(clock.clone());
+    client.set_throughput_history_len(3);
+
+    for packet_number in 0..5 {
+        clock.advance(Duration::from_secs(1));
+        client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number, data: vec![0; 96] }.to_bytes()).unwrap();
+    }
+
+    assert_eq!(client.sample_throughput(), (start + Duration::from_secs(5), 500));
+
+    let history: Vec<_> = client.throughput_history().copied().collect();
+    assert_eq!(history, vec![
+        (start + Duration::from_secs(3), 300),
+        (start + Duration::from_secs(4), 400),
+        (start + Duration::from_secs(5), 500)
+    ]);
+}
+
+#[test]
+fn prune_stuck_files() {
+    let clock = ManualClock::new();
+    let mut client = test_client();
+    client.set_clock(clock.clone());
+
+    client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 0, data: vec![1] }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: false, file_id: 2, packet_number: 0, data: vec![2] }.to_bytes()).unwrap();
+
+    // File 2 keeps going while file 1 stops
+    for packet_number in 1..3 {
+        clock.advance(Duration::from_secs(4));
+        client.process_datagram(DataPacket { is_last: false, file_id: 2, packet_number, data: vec![2] }.to_bytes()).unwrap();
+        assert!(client.prune_stuck_files(Duration::from_secs(10)).is_empty());
+    }
+
+    clock.advance(Duration::from_secs(4));
+    client.process_datagram(DataPacket { is_last: false, file_id: 2, packet_number: 3, data: vec![2] }.to_bytes()).unwrap();
+    assert_eq!(client.prune_stuck_files(Duration::from_secs(10)), vec![1]);
+
+    assert!(!client.in_progress_files.contains_key(&1));
+    assert!(client.in_progress_files.contains_key(&2));
+    assert!(client.prune_stuck_files(Duration::from_secs(10)).is_empty());
+}
+
+#[test]
+fn durable_writes() {
+    let dir = test_dir("durable_writes");
+    let mut client = test_client();
+    client.set_output_dir(&dir);
+    client.set_durable(true);
+
+    let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("durable.bin"), expected_size: None }.to_bytes()).unwrap();
+    for (packet_number, chunk) in data.chunks(1024).enumerate() {
+        let is_last = packet_number == 2;
+        client.process_datagram(DataPacket { is_last, file_id: 1, packet_number: packet_number as PacketNumber, data: chunk.to_vec() }.to_bytes()).unwrap();
+    }
+    client.finalize_files_ref().unwrap();
+
+    assert_eq!(std::fs::read(dir.join("durable.bin")).unwrap(), data);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn completion_callback() {
+    let completed = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+    let mut client = test_client();
+    let log = completed.clone();
+    client.set_on_complete(move |id, name, size| log.borrow_mut().push((id, name.to_string(), size)));
+
+    client.process_datagram(DataPacket { is_last: true, file_id: 9, packet_number: 1, data: vec![0; 3] }.to_bytes()).unwrap();
+    client.process_datagram(HeaderPacket { file_id: 2, name: String::from("two"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(HeaderPacket { file_id: 9, name: String::from("nine"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 2, packet_number: 0, data: vec![0; 5] }.to_bytes()).unwrap();
+    assert_eq!(*completed.borrow(), vec![(2, String::from("two"), 5)]);
+
+    client.process_datagram(DataPacket { is_last: false, file_id: 9, packet_number: 0, data: vec![0; 4] }.to_bytes()).unwrap();
+    assert_eq!(*completed.borrow(), vec![(2, String::from("two"), 5), (9, String::from("nine"), 7)]);
+
+    // Forcing a file along reports it too, exactly once
+    client.process_datagram(HeaderPacket { file_id: 4, name: String::from("four"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: false, file_id: 4, packet_number: 0, data: vec![0; 1] }.to_bytes()).unwrap();
+    client.force_finalize(4).unwrap();
+    client.move_complete_files().unwrap();
+    assert_eq!(completed.borrow().len(), 3);
+    assert_eq!(completed.borrow()[2], (4, String::from("four"), 1));
+}
+
+#[test]
+fn record_then_replay() {
+    let dir = test_dir("record_then_replay");
+    let (mut client, server) = loopback_client();
+    client.record_to(dir.join("trace.bin")).unwrap();
+
+    client.send_request().unwrap();
+    let mut buf = [0; 16];
+    let (_, peer) = server.recv_from(&mut buf).unwrap();
+
+    let packets = [
+        DataPacket { is_last: true, file_id: 3, packet_number: 1, data: b"lo".to_vec() }.to_bytes(),
+        HeaderPacket { file_id: 3, name: String::from("hello.txt"), expected_size: None }.to_bytes(),
+        DataPacket { is_last: false, file_id: 3, packet_number: 0, data: b"hel".to_vec() }.to_bytes()
+    ];
+    for packet in packets.iter() {
+        server.send_to(packet, peer).unwrap();
+        client.recv_packet().unwrap();
+    }
+    assert_eq!(client.file_count(), 1);
+
+    let mut replayed = test_client();
+    replayed.set_output_dir(&dir);
+    replayed.replay_from(dir.join("trace.bin")).unwrap();
+    assert_eq!(replayed.file_count(), 1);
+    replayed.finalize_files_ref().unwrap();
+    assert_eq!(std::fs::read(dir.join("hello.txt")).unwrap(), b"hello");
+
+    assert_eq!(read_trace(&dir.join("trace.bin")).unwrap(), packets);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn expected_file_names() {
+    let mut client = test_client();
+    client.expect_files(&["small.txt", "binary.jpg"]);
+
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("small.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 0, data: vec![1] }.to_bytes()).unwrap();
+
+    match client.process_datagram(HeaderPacket { file_id: 2, name: String::from("virus.exe"), expected_size: None }.to_bytes()) {
+        Err(SegFsError::UnexpectedFile(name)) => assert_eq!(name, "virus.exe"),
+        _ => panic!("unexpected file was accepted")
+    }
+    assert!(!client.in_progress_files.contains_key(&2));
+
+    client.set_unexpected_file_policy(UnexpectedFilePolicy::Warn);
+    client.process_datagram(HeaderPacket { file_id: 2, name: String::from("virus.exe"), expected_size: None }.to_bytes()).unwrap();
+    assert_eq!(client.unexpected_files(), ["virus.exe"]);
+
+    assert_eq!(client.missing_expected_files(), vec![String::from("binary.jpg")]);
+}
+
+#[test]
+fn file_lookup() {
+    let mut client = test_client();
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("done.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 0, data: vec![1] }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: false, file_id: 2, packet_number: 0, data: vec![2] }.to_bytes()).unwrap();
+
+    let done = client.file(1).unwrap();
+    assert_eq!(done.name(), Some("done.txt"));
+    assert!(done.is_done());
+
+    let partial = client.file(2).unwrap();
+    assert_eq!(partial.name(), None);
+    assert_eq!(partial.segment_count(), 1);
+    assert_eq!(partial.last_packet_number(), None);
+
+    assert!(client.file(3).is_none());
+
+    // A stray packet for a completed id shadows the completed file
+    client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 5, data: vec![3] }.to_bytes()).unwrap();
+    assert!(!client.file(1).unwrap().is_done());
+}
+
+#[test]
+fn reused_file_ids() {
+    let mut client = test_client();
+
+    // A completed id starts over as a new file
+    client.process_datagram(HeaderPacket { file_id: 5, name: String::from("a.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 5, packet_number: 0, data: b"a".to_vec() }.to_bytes()).unwrap();
+    client.process_datagram(HeaderPacket { file_id: 5, name: String::from("b.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 5, packet_number: 0, data: b"b".to_vec() }.to_bytes()).unwrap();
+    assert_eq!(client.file_count(), 2);
+    assert_eq!(client.final_files[0].generation(), 0);
+    assert_eq!(client.final_files[1].generation(), 1);
+    assert_eq!(client.final_files[1].segments[&0], b"b");
+
+    // A new name for an id still in progress drops the old file's segments
+    client.process_datagram(HeaderPacket { file_id: 6, name: String::from("x.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: false, file_id: 6, packet_number: 0, data: b"x".to_vec() }.to_bytes()).unwrap();
+    client.process_datagram(HeaderPacket { file_id: 6, name: String::from("y.txt"), expected_size: None }.to_bytes()).unwrap();
+    let file = client.file(6).unwrap();
+    assert_eq!(file.name(), Some("y.txt"));
+    assert_eq!(file.generation(), 1);
+    assert_eq!(file.segment_count(), 0);
+
+    // Repeating the same header is not a reuse
+    client.process_datagram(HeaderPacket { file_id: 6, name: String::from("y.txt"), expected_size: None }.to_bytes()).unwrap();
+    assert_eq!(client.file(6).unwrap().generation(), 1);
+}
+
+#[test]
+fn rate_limit_pacing() {
+    let clock = ManualClock::new();
+    let start = clock.now();
+    let (mut client, server) = loopback_client();
+    client.set_clock(clock.clone());
+    client.process_datagram(DataPacket { is_last: false, file_id: 2, packet_number: 0, data: vec![0; 1000] }.to_bytes()).unwrap();
+    client.set_rate_limit(Some(1000));
+
+    client.send_request().unwrap();
+    let mut buf = [0; 16];
+    let (_, peer) = server.recv_from(&mut buf).unwrap();
+
+    // Each 500 byte datagram is worth half a second at 1000 bytes a second
+    for packet_number in 0..4 {
+        server.send_to(&DataPacket { is_last: false, file_id: 1, packet_number, data: vec![0; 496] }.to_bytes(), peer).unwrap();
+        client.recv_packet().unwrap();
+        assert_eq!(clock.now() - start, Duration::from_millis(500) * (packet_number as u32 + 1));
+    }
+
+    // Time passing by itself counts towards the allowance
+    clock.advance(Duration::from_secs(10));
+    server.send_to(&DataPacket { is_last: false, file_id: 1, packet_number: 4, data: vec![0; 496] }.to_bytes(), peer).unwrap();
+    client.recv_packet().unwrap();
+    assert_eq!(clock.now() - start, Duration::from_secs(12));
+}
+
+#[test]
+fn summary_line() {
+    let dir = test_dir("summary_line");
+    let clock = ManualClock::new();
+    let mut client = test_client();
+    client.set_clock(clock.clone());
+    client.set_output_dir(&dir);
+
+    let data = DataPacket { is_last: true, file_id: 1, packet_number: 0, data: vec![0; 96] }.to_bytes();
+    client.process_datagram(data.clone()).unwrap();
+    client.process_datagram(data).unwrap();
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("a.bin"), expected_size: None }.to_bytes()).unwrap();
+
+    // One of four segments has arrived
+    client.process_datagram(DataPacket { is_last: true, file_id: 2, packet_number: 3, data: vec![0; 96] }.to_bytes()).unwrap();
+
+    clock.advance(Duration::from_millis(1234));
+    client.finalize_files_ref().unwrap();
+    assert_eq!(client.summary_line(), "segfs: 1 files, 307 bytes, 3 missing, 1 duplicates, 1.23s");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn expected_file_size() {
+    let dir = test_dir("expected_file_size");
+    let mut client = test_client();
+    client.set_output_dir(&dir);
+
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("sized.txt"), expected_size: Some(8) }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 0, data: b"abcd".to_vec() }.to_bytes()).unwrap();
+    assert_eq!(client.file(1).unwrap().expected_size(), Some(8));
+    assert_eq!(client.file(1).unwrap().fraction_complete(), Some(0.5));
+    client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 1, data: b"efgh".to_vec() }.to_bytes()).unwrap();
+    client.finalize_files_ref().unwrap();
+    assert_eq!(std::fs::read(dir.join("sized.txt")).unwrap(), b"abcdefgh");
+
+    // The server claimed more than it sent
+    client.process_datagram(HeaderPacket { file_id: 2, name: String::from("short.txt"), expected_size: Some(100) }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 2, packet_number: 0, data: b"abcd".to_vec() }.to_bytes()).unwrap();
+    assert!(client.finalize_files_ref().is_err());
+    assert!(!dir.join("short.txt").exists());
+    assert_eq!(client.pending_finalize(), vec![2]);
+
+    // Headers without a size still work
+    client.process_datagram(HeaderPacket { file_id: 3, name: String::from("plain.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 3, packet_number: 0, data: b"abcd".to_vec() }.to_bytes()).unwrap();
+    assert_eq!(client.file(3).unwrap().fraction_complete(), None);
+    client.finalize_file(3).unwrap();
+    assert_eq!(std::fs::read(dir.join("plain.txt")).unwrap(), b"abcd");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn completed_count_per_packet() {
+    let (mut client, server) = loopback_client();
+    client.send_request().unwrap();
+    let mut buf = [0; 16];
+    let (_, peer) = server.recv_from(&mut buf).unwrap();
+
+    server.send_to(&DataPacket { is_last: true, file_id: 1, packet_number: 0, data: vec![1] }.to_bytes(), peer).unwrap();
+    assert_eq!(client.recv_packet_counted().unwrap(), 0);
+
+    // A file that's done but hasn't been moved yet, as if it had been put
+    // together by hand
+    let mut ready = File::new(8);
+    ready.report_header_packet(HeaderPacket { file_id: 8, name: String::from("ready.txt"), expected_size: None });
+    ready.report_data_packet(DataPacket { is_last: true, file_id: 8, packet_number: 0, data: vec![8] });
+    client.in_progress_files.insert(8, ready);
+
+    server.send_to(&HeaderPacket { file_id: 1, name: String::from("one.txt"), expected_size: None }.to_bytes(), peer).unwrap();
+    assert_eq!(client.recv_packet_counted().unwrap(), 2);
+    assert_eq!(client.file_count(), 2);
+}
+
+#[test]
+fn skip_existing_files() {
+    let dir = test_dir("skip_existing_files");
+    std::fs::write(dir.join("have.txt"), b"old").unwrap();
+    std::fs::write(dir.join("resized.txt"), b"old").unwrap();
+
+    let mut client = test_client();
+    client.set_output_dir(&dir);
+    client.set_skip_existing(true);
+
+    // Data before the header is dropped once the header shows it's on disk
+    client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 0, data: b"new".to_vec() }.to_bytes()).unwrap();
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("have.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 1, data: b"new".to_vec() }.to_bytes()).unwrap();
+    assert_eq!(client.file_count(), 1);
+    assert!(client.file(1).is_none());
+    assert_eq!(client.skipped_files().collect::<Vec<_>>(), vec!["have.txt"]);
+
+    // A file whose size doesn't match is downloaded again
+    client.process_datagram(HeaderPacket { file_id: 2, name: String::from("resized.txt"), expected_size: Some(5) }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 2, packet_number: 0, data: b"newer".to_vec() }.to_bytes()).unwrap();
+    assert_eq!(client.file_count(), 2);
+
+    client.finalize_files_ref().unwrap();
+    assert_eq!(std::fs::read(dir.join("have.txt")).unwrap(), b"old");
+    assert_eq!(std::fs::read(dir.join("resized.txt")).unwrap(), b"newer");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn likely_total_files() {
+    let clock = ManualClock::new();
+    let mut client = test_client();
+    client.set_clock(clock.clone());
+    assert_eq!(client.likely_total_files(), None);
+
+    for file_id in 0..2 {
+        client.process_datagram(HeaderPacket { file_id, name: format!("{}.txt", file_id), expected_size: None }.to_bytes()).unwrap();
+        client.process_datagram(DataPacket { is_last: true, file_id, packet_number: 0, data: vec![file_id] }.to_bytes()).unwrap();
+    }
+    assert_eq!(client.likely_total_files(), Some(2));
+
+    client.process_datagram(DataPacket { is_last: false, file_id: 7, packet_number: 0, data: vec![7] }.to_bytes()).unwrap();
+    assert_eq!(client.likely_total_files(), None);
+
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(client.likely_total_files(), Some(3));
+}
+
+#[test]
+fn recv_buffer_size() {
+    let mut client = test_client();
+
+    client.set_recv_buffer_size(64 * 1024).unwrap();
+    let granted = client.recv_buffer_size().unwrap();
+
+    // Linux reports double what was asked for, others may round or clamp
+    if cfg!(target_os = "linux") {
+        assert!(granted >= 64 * 1024);
+    }
+    else {
+        assert!(granted > 0);
+    }
+}
+
+#[test]
+fn finalize_single_file() {
+    let dir = test_dir("finalize_single_file");
+    let mut client = test_client();
+    client.set_output_dir(&dir);
+
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("one.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 0, data: b"one".to_vec() }.to_bytes()).unwrap();
+    client.process_datagram(HeaderPacket { file_id: 2, name: String::from("two.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 2, packet_number: 0, data: b"two".to_vec() }.to_bytes()).unwrap();
+    client.process_datagram(HeaderPacket { file_id: 3, name: String::from("three.txt"), expected_size: None }.to_bytes()).unwrap();
+
+    assert_eq!(client.finalize_file(2).unwrap(), Some(dir.join("two.txt")));
+    assert_eq!(std::fs::read(dir.join("two.txt")).unwrap(), b"two");
+    assert!(!dir.join("one.txt").exists());
+
+    // Already written, still in progress, or never seen
+    assert_eq!(client.finalize_file(2).unwrap(), None);
+    assert_eq!(client.finalize_file(3).unwrap(), None);
+    assert_eq!(client.finalize_file(9).unwrap(), None);
+
+    client.finalize_files_ref().unwrap();
+    assert_eq!(std::fs::read(dir.join("one.txt")).unwrap(), b"one");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn control_chars_in_names() {
+    let header = HeaderPacket { file_id: 1, name: String::from("bad\nname\x1b[2J.txt"), expected_size: None };
+
+    let mut client = test_client();
+    client.process_datagram(header.to_bytes()).unwrap();
+    assert_eq!(client.file(1).unwrap().name(), Some("bad_name_[2J.txt"));
+    assert!(!client.to_string().contains('\x1b'));
+
+    client.set_control_char_policy(ControlCharPolicy::Reject);
+    assert!(client.process_datagram(HeaderPacket { file_id: 2, ..header.clone() }.to_bytes()).is_err());
+    assert!(client.file(2).is_none());
+
+    client.set_control_char_policy(ControlCharPolicy::Allow);
+    client.process_datagram(HeaderPacket { file_id: 3, ..header.clone() }.to_bytes()).unwrap();
+    assert_eq!(client.file(3).unwrap().name(), Some(header.name.as_str()));
+}
+
+#[test]
+fn file_idle() {
+    let clock = ManualClock::new();
+    let mut client = test_client();
+    client.set_clock(clock.clone());
+
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("slow.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: false, file_id: 2, packet_number: 0, data: vec![2] }.to_bytes()).unwrap();
+    assert_eq!(client.file_idle(1), Some(Duration::ZERO));
+    assert_eq!(client.file_idle(3), None);
+
+    for packet_number in 1..4 {
+        clock.advance(Duration::from_secs(2));
+        client.process_datagram(DataPacket { is_last: false, file_id: 2, packet_number, data: vec![2] }.to_bytes()).unwrap();
+        assert_eq!(client.file_idle(1), Some(Duration::from_secs(2 * packet_number as u64)));
+        assert_eq!(client.file_idle(2), Some(Duration::ZERO));
+    }
+}
+
+#[test]
+fn naming_template() {
+    assert_eq!(render_template("{name}", 1, "a.txt", 0).unwrap(), "a.txt");
+    assert_eq!(render_template("{id}_{name}", 7, "a.txt", 0).unwrap(), "7_a.txt");
+    assert_eq!(render_template("dl_{index}.bin", 7, "a.txt", 3).unwrap(), "dl_3.bin");
+    assert_eq!(render_template("{{{id}}}.{ext}", 2, "photo.jpg", 0).unwrap(), "{2}.jpg");
+    assert_eq!(render_template("{ext}", 2, "README", 0).unwrap(), "");
+    assert!(render_template("{size}", 2, "a.txt", 0).is_err());
+    assert!(render_template("{name", 2, "a.txt", 0).is_err());
+    assert!(render_template("name}", 2, "a.txt", 0).is_err());
+
+    let mut client = test_client();
+    assert!(client.set_naming_template("{nope}").is_err());
+    assert!(client.set_naming_template("out/{name}").is_err());
+
+    client.set_naming_template("{id}_{name}").unwrap();
+    assert_eq!(client.templated_name(3, Some("dir/a.txt"), 0).unwrap(), "dir/3_a.txt");
+    assert_eq!(client.templated_name(3, None, 0).unwrap(), "3_file_3");
+
+    client.set_naming_template("{ext}").unwrap();
+    assert!(client.templated_name(3, Some("README"), 0).is_err());
+
+    let dir = test_dir("naming_template");
+    client.set_output_dir(&dir);
+    client.set_naming_template("dl_{index}.{ext}").unwrap();
+    client.process_datagram(HeaderPacket { file_id: 5, name: String::from("second.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(HeaderPacket { file_id: 6, name: String::from("first.jpg"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 6, packet_number: 0, data: b"first".to_vec() }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 5, packet_number: 0, data: b"second".to_vec() }.to_bytes()).unwrap();
+    client.finalize_files_ref().unwrap();
+
+    assert_eq!(std::fs::read(dir.join("dl_0.jpg")).unwrap(), b"first");
+    assert_eq!(std::fs::read(dir.join("dl_1.txt")).unwrap(), b"second");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn zero_byte_file() {
+    let dir = test_dir("zero_byte_file");
+    let mut client = test_client();
+    client.set_output_dir(&dir);
+
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("empty.txt"), expected_size: Some(0) }.to_bytes()).unwrap();
+    client.process_datagram(vec![3, 1, 0, 0]).unwrap();
+    assert_eq!(client.file(1).unwrap().data_len(), 0);
+    assert!(client.file(1).unwrap().is_done());
+
+    client.finalize_files_ref().unwrap();
+    assert_eq!(std::fs::metadata(dir.join("empty.txt")).unwrap().len(), 0);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn json_report() {
+    assert_eq!(json_string(None), "null");
+    assert_eq!(json_string(Some("a \"b\"\\\n\u{1}")), "\"a \\\"b\\\"\\\\\\n\\u0001\"");
+
+    let clock = ManualClock::new();
+    let mut client = test_client();
+    client.set_clock(clock.clone());
+
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("done.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 0, data: b"hello".to_vec() }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 2, packet_number: 2, data: b"xy".to_vec() }.to_bytes()).unwrap();
+    clock.advance(Duration::from_millis(1500));
+
+    assert_eq!(client.to_json(),
+               "{\"completed\":[{\"id\":1,\"name\":\"done.txt\",\"size\":5,\"hash\":\"3610a686\"}],\
+                \"incomplete\":[{\"id\":2,\"name\":null,\"segments\":1,\"total_segments\":3}],\"failed\":[],\
+                \"bytes_received\":25,\"missing_segments\":2,\"duplicate_packets\":0,\"elapsed_secs\":1.500}");
+}
+
+#[test]
+fn batch_completion() {
+    let mut client = test_client();
+    assert!(client.is_batch_complete(0));
+    assert!(!client.is_batch_complete(2));
+    assert_eq!(client.remaining(2), 2);
+
+    for file_id in 0..3 {
+        client.process_datagram(HeaderPacket { file_id, name: format!("{}.txt", file_id), expected_size: None }.to_bytes()).unwrap();
+        client.process_datagram(DataPacket { is_last: true, file_id, packet_number: 0, data: vec![file_id] }.to_bytes()).unwrap();
+    }
+
+    // One more than asked for
+    assert!(client.is_batch_complete(2));
+    assert_eq!(client.remaining(2), 0);
+    assert_eq!(client.remaining(5), 2);
+}
+
+#[test]
+fn warnings() {
+    let mut client = test_client();
+
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("a\tb"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("a_b"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 0, data: vec![1, 2] }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 0, data: vec![1, 2] }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 3, data: vec![3, 4, 5] }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 5, data: vec![6, 7] }.to_bytes()).unwrap();
+
+    assert_eq!(client.warnings(), &[
+        Warning::ControlCharsReplaced { file_id: 1, name: String::from("a\tb") },
+        Warning::DuplicateHeader { file_id: 1, name: String::from("a_b") },
+        Warning::DuplicatePacket { file_id: 1, packet_number: 0 },
+        Warning::SegmentSizeAnomaly { file_id: 1, packet_number: 3, size: 3 },
+        Warning::PastLastPacket { file_id: 1, packet_number: 5, last: 3 }
+    ]);
+
+    let taken = client.take_warnings();
+    assert_eq!(taken.len(), 5);
+    assert_eq!(taken[2].to_string(), "file id 1 sent packet 0 more than once");
+    assert!(client.warnings().is_empty());
+
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("other"), expected_size: None }.to_bytes()).unwrap();
+    assert_eq!(client.take_warnings(), vec![Warning::FileIdReused { file_id: 1, old_name: String::from("a_b"), new_name: String::from("other") }]);
+}
+
+#[test]
+fn past_last_packet_dropped() {
+    let mut client = test_client();
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("a.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 1, data: b"b".to_vec() }.to_bytes()).unwrap();
+
+    // Two segments with 0 still missing, which mustn't look like a whole file
+    client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 2, data: b"c".to_vec() }.to_bytes()).unwrap();
+    assert_eq!(client.take_warnings(), vec![Warning::PastLastPacket { file_id: 1, packet_number: 2, last: 1 }]);
+    assert_eq!(client.file_count(), 0);
+    assert_eq!(client.file(1).unwrap().segment_count(), 1);
+
+    client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 0, data: b"a".to_vec() }.to_bytes()).unwrap();
+    assert_eq!(client.file_count(), 1);
+    assert_eq!(client.completed_file_bytes("a.txt").unwrap(), b"ab");
+    assert!(client.incomplete_files().is_empty());
+}
+
+#[test]
+fn current_gaps() {
+    let mut client = test_client();
+    assert_eq!(client.current_gaps(1), None);
+
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("a.txt"), expected_size: None }.to_bytes()).unwrap();
+    assert_eq!(client.current_gaps(1), Some(0));
+
+    for (packet_number, gaps) in [(0, 0), (3, 2), (1, 1), (6, 3)] {
+        client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number, data: vec![0] }.to_bytes()).unwrap();
+        assert_eq!(client.current_gaps(1), Some(gaps));
+    }
+
+    // A repeat doesn't change anything
+    client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 3, data: vec![0] }.to_bytes()).unwrap();
+    assert_eq!(client.current_gaps(1), Some(3));
+}
+
+#[cfg(test)]
+fn send_with_bad_header(client: &mut Client) -> Result<(), SegFsError> {
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("good.txt"), expected_size: None }.to_bytes())?;
+    client.process_datagram(DataPacket { is_last: false, file_id: 2, packet_number: 0, data: b"early".to_vec() }.to_bytes())?;
+    client.process_datagram(vec![0, 2, b'b', b'a', b'd', 0xff])?;
+    client.process_datagram(DataPacket { is_last: true, file_id: 2, packet_number: 1, data: b"late".to_vec() }.to_bytes())?;
+    client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 0, data: b"good".to_vec() }.to_bytes())?;
+    Ok(())
+}
+
+#[test]
+fn bad_header_policies() {
+    let mut client = test_client();
+    assert!(send_with_bad_header(&mut client).is_err());
+    assert!(client.warnings().is_empty());
+
+    let mut client = test_client();
+    client.set_bad_header_policy(BadHeaderPolicy::SkipFile);
+    send_with_bad_header(&mut client).unwrap();
+    assert_eq!(client.file_count(), 1);
+    assert!(client.file(2).is_none());
+    assert!(matches!(client.warnings(), [Warning::BadHeader { file_id: 2, .. }]));
+
+    // A good header for the id ends the skipping
+    client.process_datagram(HeaderPacket { file_id: 2, name: String::from("fixed.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 2, packet_number: 0, data: b"ok".to_vec() }.to_bytes()).unwrap();
+    assert_eq!(client.file_count(), 2);
+
+    let mut client = test_client();
+    client.set_bad_header_policy(BadHeaderPolicy::Synthesize);
+    send_with_bad_header(&mut client).unwrap();
+    assert_eq!(client.file_count(), 2);
+    assert_eq!(client.file(2).unwrap().name(), Some("file_2"));
+    assert_eq!(client.file(2).unwrap().data_len(), 9);
+}
+
+#[cfg(feature = "hmac")]
+#[test]
+fn hmac_signed_packets() {
+    use hmac::Mac;
+
+    let sign = |packet: Vec<u8>| {
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(&packet);
+        let mut signed = packet;
+        signed.extend_from_slice(&mac.finalize().into_bytes());
+        signed
+    };
+
+    let mut client = test_client();
+    client.set_hmac_key(Some(b"secret".to_vec()));
+
+    client.process_datagram(sign(HeaderPacket { file_id: 1, name: String::from("a.txt"), expected_size: None }.to_bytes())).unwrap();
+    assert_eq!(client.file(1).unwrap().name(), Some("a.txt"));
+
+    let mut tampered = sign(DataPacket { is_last: true, file_id: 1, packet_number: 0, data: b"abc".to_vec() }.to_bytes());
+    tampered[4] = b'x';
+    assert!(client.process_datagram(tampered).is_err());
+    assert!(client.process_datagram(vec![1, 1, 0]).is_err());
+    assert_eq!(client.file(1).unwrap().data_len(), 0);
+
+    client.process_datagram(sign(DataPacket { is_last: true, file_id: 1, packet_number: 0, data: b"abc".to_vec() }.to_bytes())).unwrap();
+    assert_eq!(client.file_count(), 1);
+}
+
+#[cfg(feature = "hmac")]
+#[test]
+fn hmac_full_segment() {
+    use hmac::Mac;
+
+    let (mut client, server) = loopback_client();
+    client.set_hmac_key(Some(b"secret".to_vec()));
+    client.send_request().unwrap();
+    let mut buf = [0; 16];
+    let (_, peer) = server.recv_from(&mut buf).unwrap();
+
+    // A full segment leaves the MAC past the usual end of a datagram
+    let mut packet = DataPacket { is_last: false, file_id: 1, packet_number: 0, data: vec![7; 1024] }.to_bytes();
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(b"secret").unwrap();
+    mac.update(&packet);
+    packet.extend_from_slice(&mac.finalize().into_bytes());
+    server.send_to(&packet, peer).unwrap();
+
+    client.recv_packet().unwrap();
+    assert_eq!(client.file(1).unwrap().data_len(), 1024);
+}
+
+#[test]
+fn completed_files() {
+    let mut client = test_client();
+    assert_eq!(client.completed_files().count(), 0);
+
+    client.process_datagram(HeaderPacket { file_id: 4, name: String::from("later.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(HeaderPacket { file_id: 9, name: String::from("first.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 9, packet_number: 0, data: vec![1] }.to_bytes()).unwrap();
+    client.process_datagram(HeaderPacket { file_id: 2, name: String::from("waiting.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 4, packet_number: 0, data: vec![2] }.to_bytes()).unwrap();
+
+    assert_eq!(client.completed_files().collect::<Vec<_>>(), vec![(9, "first.txt"), (4, "later.txt")]);
+    assert_eq!(client.completed_files().count(), client.file_count());
+}
+
+#[cfg(feature = "pcap")]
+#[test]
+fn pcap_capture() {
+    let path = std::env::temp_dir().join(format!("seg-fs-pcap_capture-{}.pcap", std::process::id()));
+    let (mut client, server) = loopback_client();
+    client.capture_pcap(&path).unwrap();
+
+    let packet = DataPacket { is_last: true, file_id: 1, packet_number: 0, data: b"hi".to_vec() }.to_bytes();
+    server.send_to(&packet, client.transport.local_addr().unwrap()).unwrap();
+    client.recv_packet().unwrap();
+
+    // One record after the 24 byte file header, with 28 bytes of IP and UDP
+    let data = std::fs::read(&path).unwrap();
+    assert_eq!(data.len(), 24 + 16 + 28 + packet.len());
+    assert_eq!(data[24 + 16 + 28..], packet[..]);
+    assert_eq!(data[24 + 16 + 20..24 + 16 + 22], server.local_addr().unwrap().port().to_be_bytes());
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn max_total_bytes() {
+    let mut client = test_client();
+    client.set_max_total_bytes(Some(30));
+
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("big.bin"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 0, data: vec![0; 10] }.to_bytes()).unwrap();
+    assert_eq!(client.stats().bytes_received, 23);
+
+    match client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 1, data: vec![0; 10] }.to_bytes()) {
+        Err(SegFsError::TransferTooLarge { limit: 30, received: 37 }) => {}
+        other => panic!("expected the limit to trip, got {:?}", other)
+    }
+    assert_eq!(client.file(1).unwrap().segment_count(), 1);
+}
+
+#[test]
+fn completed_file_bytes() {
+    let mut client = test_client();
+
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("a.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 1, data: b"world".to_vec() }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 0, data: b"hello ".to_vec() }.to_bytes()).unwrap();
+
+    // The same name again under another id
+    client.process_datagram(HeaderPacket { file_id: 2, name: String::from("a.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 2, packet_number: 0, data: b"second".to_vec() }.to_bytes()).unwrap();
+
+    assert_eq!(client.completed_file_bytes("a.txt"), Some(b"hello world".to_vec()));
+    assert_eq!(client.completed_file_bytes("b.txt"), None);
+}
+
+#[test]
+fn resume_transfer() {
+    let dir = test_dir("resume_transfer");
+    let trace = dir.join("trace.bin");
+
+    // An earlier run got all of b.txt but only the start of a.txt
+    let mut writer = TraceWriter::open(&trace).unwrap();
+    writer.record(&HeaderPacket { file_id: 1, name: String::from("a.txt"), expected_size: None }.to_bytes()).unwrap();
+    writer.record(&DataPacket { is_last: false, file_id: 1, packet_number: 0, data: b"ab".to_vec() }.to_bytes()).unwrap();
+    writer.record(&DataPacket { is_last: false, file_id: 1, packet_number: 2, data: b"ef".to_vec() }.to_bytes()).unwrap();
+    writer.record(&HeaderPacket { file_id: 2, name: String::from("b.txt"), expected_size: None }.to_bytes()).unwrap();
+    writer.record(&DataPacket { is_last: true, file_id: 2, packet_number: 0, data: b"gh".to_vec() }.to_bytes()).unwrap();
+    drop(writer);
+
+    let (mut client, server) = loopback_client();
+    let handle = std::thread::spawn(move || {
+        let mut buf = [0; 64];
+        let (size, peer) = server.recv_from(&mut buf).unwrap();
+        let request = ResumeRequest::parse(&buf[..size]).unwrap();
+
+        let a_txt = [b"ab", b"cd", b"ef", b"gh"];
+        for point in request.files.iter().filter(|point| point.file_id == 1) {
+            for packet_number in point.next_packet..a_txt.len() as u16 {
+                let packet = DataPacket { is_last: packet_number == 3, file_id: 1, packet_number, data: a_txt[packet_number as usize].to_vec() };
+                server.send_to(&packet.to_bytes(), peer).unwrap();
+            }
+        }
+        request
+    });
+
+    client.resume_from(&trace).unwrap();
+    while !client.is_batch_complete(2) {
+        client.recv_packet().unwrap();
+    }
+
+    assert_eq!(handle.join().unwrap().files, vec![
+        ResumePoint { file_id: 1, next_packet: 1 },
+        ResumePoint { file_id: 2, next_packet: RESUME_DONE }
+    ]);
+    assert_eq!(client.completed_file_bytes("a.txt"), Some(b"abcdefgh".to_vec()));
+    assert_eq!(client.stats().duplicate_packets, 1);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn manual_finalize() {
+    let mut client = test_client();
+    client.set_auto_finalize(false);
+
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("a.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 0, data: b"a".to_vec() }.to_bytes()).unwrap();
+    assert_eq!(client.file_count(), 0);
+    assert!(client.in_progress_files[&1].is_done());
+
+    assert_eq!(client.promote_complete().unwrap(), 1);
+    assert_eq!(client.file_count(), 1);
+    assert!(client.in_progress_files.is_empty());
+    assert_eq!(client.promote_complete().unwrap(), 0);
+}
+
+#[test]
+fn request_burst() {
+    let (mut client, server) = loopback_client();
+    let mut buf = [0; 16];
+
+    client.send_request_burst(3).unwrap();
+    for _ in 0..3 {
+        assert_eq!(server.recv_from(&mut buf).unwrap().0, 1);
+    }
+
+    client.set_request_burst(2);
+    client.send_request().unwrap();
+    for _ in 0..2 {
+        assert_eq!(server.recv_from(&mut buf).unwrap().0, 1);
+    }
+
+    // Nothing more was sent
+    server.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+    assert!(server.recv_from(&mut buf).is_err());
+}
+
+#[test]
+fn last_packet_below_received() {
+    let mut client = test_client();
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("a.txt"), expected_size: None }.to_bytes()).unwrap();
+    for packet_number in [0, 1, 4] {
+        client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number, data: vec![1] }.to_bytes()).unwrap();
+    }
+
+    // Packet 4 has already arrived, so packet 2 can't be the last
+    client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 2, data: vec![1] }.to_bytes()).unwrap();
+    let file = client.file(1).unwrap();
+    assert!(file.has_last_conflict());
+    assert_eq!(file.last_packet_number(), None);
+    assert_eq!(file.segment_count(), 4);
+    assert_eq!(client.file_count(), 0);
+    assert_eq!(client.warnings(), &[Warning::LastBelowReceived { file_id: 1, last: 2, highest: 4 }]);
+}
+
+#[test]
+fn header_after_data() {
+    let (mut client, server) = loopback_client();
+    let completed = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+    let log = completed.clone();
+    client.set_on_complete(move |id, name, _| log.borrow_mut().push((id, name.to_string())));
+
+    client.send_request().unwrap();
+    let mut buf = [0; 16];
+    let (_, peer) = server.recv_from(&mut buf).unwrap();
+
+    // Every segment is in, but without a name the file has to wait
+    for packet_number in 0..2 {
+        server.send_to(&DataPacket { is_last: packet_number == 1, file_id: 3, packet_number, data: vec![3] }.to_bytes(), peer).unwrap();
+        assert_eq!(client.recv_packet_counted().unwrap(), 0);
+    }
+    assert!(!client.file(3).unwrap().is_done());
+    assert!(completed.borrow().is_empty());
+
+    server.send_to(&HeaderPacket { file_id: 3, name: String::from("late.txt"), expected_size: None }.to_bytes(), peer).unwrap();
+    assert_eq!(client.recv_packet_counted().unwrap(), 1);
+    assert_eq!(*completed.borrow(), vec![(3, String::from("late.txt"))]);
+    assert_eq!(client.completed_files().collect::<Vec<_>>(), vec![(3, "late.txt")]);
+}
+
+#[test]
+fn concurrent_writes() {
+    for concurrency in [1, 2, 4, 16] {
+        let dir = test_dir(&format!("concurrent_writes_{}", concurrency));
+        let mut client = test_client();
+        client.set_output_dir(&dir);
+        client.set_write_concurrency(concurrency);
+
+        for file_id in 0..10 {
+            client.process_datagram(HeaderPacket { file_id, name: format!("{}.bin", file_id), expected_size: None }.to_bytes()).unwrap();
+            client.process_datagram(DataPacket { is_last: true, file_id, packet_number: 0, data: vec![file_id; 100] }.to_bytes()).unwrap();
+        }
+
+        // One that can't be written, which shouldn't stop the rest
+        client.process_datagram(HeaderPacket { file_id: 20, name: String::from("short.bin"), expected_size: Some(5) }.to_bytes()).unwrap();
+        client.process_datagram(DataPacket { is_last: true, file_id: 20, packet_number: 0, data: vec![1] }.to_bytes()).unwrap();
+
+        assert!(client.finalize_files_ref().is_err());
+        assert_eq!(client.pending_finalize(), vec![20]);
+        for file_id in 0..10 {
+            assert_eq!(std::fs::read(dir.join(format!("{}.bin", file_id))).unwrap(), vec![file_id; 100]);
+        }
+        assert!(!dir.join("short.bin").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[test]
+fn remote_addr() {
+    let (client, server) = loopback_client();
+    assert_eq!(client.remote_addr().unwrap(), server.local_addr().unwrap());
+    assert!(test_client().remote_addr().is_err());
+}
+
+#[test]
+fn reorder_window() {
+    let mut client = test_client();
+    client.set_reorder_window(Some(2));
+    let send = |client: &mut Client, packet_number: PacketNumber, is_last: bool| {
+        client.process_datagram(DataPacket { is_last, file_id: 1, packet_number, data: vec![packet_number as u8] }.to_bytes()).unwrap();
+    };
+
+    // Waiting on packet 0, so 1 and 2 fit and 3 and 5 don't
+    for packet_number in [1, 2, 3, 5] {
+        send(&mut client, packet_number, packet_number == 5);
+    }
+    assert_eq!(client.file(1).unwrap().segment_count(), 2);
+    assert_eq!(client.file(1).unwrap().last_packet_number(), None);
+    assert_eq!(client.stats().window_drops, 2);
+
+    // Once 0 arrives the window moves up to 5
+    send(&mut client, 0, false);
+    send(&mut client, 5, true);
+    send(&mut client, 3, false);
+    assert_eq!(client.file(1).unwrap().segment_count(), 5);
+    assert_eq!(client.file(1).unwrap().missing_segments(), Some(vec![4]));
+    assert_eq!(client.file(1).unwrap().contiguous_segments(), 4);
+    assert_eq!(client.stats().window_drops, 2);
+}
+
+#[test]
+fn contiguous_segments() {
+    let mut file = File::new(1);
+    let report = |file: &mut File, packet_number: PacketNumber| {
+        file.report_data_packet(DataPacket { is_last: false, file_id: 1, packet_number, data: Vec::new() });
+    };
+
+    for (packet_number, contiguous) in [(2, 0), (1, 0), (0, 3), (0, 3), (4, 3), (3, 5)] {
+        report(&mut file, packet_number);
+        assert_eq!(file.contiguous_segments(), contiguous);
+    }
+
+    // Every packet number there is, without wrapping round to 0
+    for packet_number in 5..=PacketNumber::MAX {
+        file.insert_segment(packet_number, Vec::new());
+    }
+    assert_eq!(file.contiguous_segments(), PacketNumber::MAX as usize + 1);
+}
+
+/// Hands out the errors it's given, then the datagrams, one per receive
+#[cfg(test)]
+struct MockTransport {
+    errors: std::cell::RefCell<VecDeque<std::io::ErrorKind>>,
+    datagrams: std::cell::RefCell<VecDeque<Vec<u8>>>
+}
+
+#[cfg(test)]
+impl PacketTransport for MockTransport {
+    fn send(&self, data: &[u8]) -> std::io::Result<usize> {
+        Ok(data.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, Option<std::net::SocketAddr>)> {
+        if let Some(kind) = self.errors.borrow_mut().pop_front() {
+            return Err(kind.into());
+        }
+        let datagram = self.datagrams.borrow_mut().pop_front().ok_or(std::io::ErrorKind::WouldBlock)?;
+        buf[..datagram.len()].copy_from_slice(&datagram);
+        Ok((datagram.len(), None))
+    }
+
+    fn peer_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    fn local_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    fn set_read_timeout(&self, _: Option<Duration>) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn socket(&self) -> Option<socket2::SockRef<'_>> {
+        None
+    }
+}
+
+#[test]
+fn socket_errors() {
+    let mock = |errors: &[std::io::ErrorKind]| MockTransport {
+        errors: std::cell::RefCell::new(errors.iter().copied().collect()),
+        datagrams: std::cell::RefCell::new([DataPacket { is_last: true, file_id: 1, packet_number: 0, data: b"x".to_vec() }.to_bytes()].into())
+    };
+
+    // An interrupted read is tried again by default
+    let mut client = Client::with_transport(mock(&[std::io::ErrorKind::Interrupted, std::io::ErrorKind::Interrupted]));
+    client.recv_packet().unwrap();
+    assert_eq!(client.file(1).unwrap().segment_count(), 1);
+
+    let mut client = Client::with_transport(mock(&[std::io::ErrorKind::WouldBlock]));
+    assert!(matches!(client.recv_packet(), Err(SegFsError::Timeout)));
+    let mut client = Client::with_transport(mock(&[std::io::ErrorKind::PermissionDenied]));
+    assert!(matches!(client.recv_packet(), Err(SegFsError::Message(_))));
+
+    // A hook can change that
+    let seen = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+    let mut client = Client::with_transport(mock(&[std::io::ErrorKind::PermissionDenied, std::io::ErrorKind::Interrupted, std::io::ErrorKind::Other]));
+    let log = seen.clone();
+    client.set_on_socket_error(move |e| {
+        log.borrow_mut().push(e.kind());
+        match e.kind() {
+            std::io::ErrorKind::PermissionDenied => ErrorAction::Retry,
+            std::io::ErrorKind::Interrupted => ErrorAction::Abort,
+            _ => ErrorAction::Skip
+        }
+    });
+    assert!(matches!(client.recv_packet(), Err(SegFsError::Message(_))));
+    assert!(matches!(client.recv_packet(), Err(SegFsError::Timeout)));
+    client.recv_packet().unwrap();
+    assert_eq!(*seen.borrow(), vec![std::io::ErrorKind::PermissionDenied, std::io::ErrorKind::Interrupted, std::io::ErrorKind::Other]);
+    assert_eq!(client.stats().datagrams_received, 1);
+}
+
+#[test]
+fn retransmit_cap() {
+    let (mut client, server) = loopback_client();
+    client.set_max_retransmits_per_file(Some(2));
+    client.send_request().unwrap();
+    let mut buf = [0; 16];
+    let (_, peer) = server.recv_from(&mut buf).unwrap();
+
+    // Packet 1 of a.txt never comes, b.txt is fine
+    let packets = [
+        HeaderPacket { file_id: 1, name: String::from("a.txt"), expected_size: None }.to_bytes(),
+        DataPacket { is_last: false, file_id: 1, packet_number: 0, data: b"a".to_vec() }.to_bytes(),
+        DataPacket { is_last: true, file_id: 1, packet_number: 2, data: b"c".to_vec() }.to_bytes(),
+        HeaderPacket { file_id: 2, name: String::from("b.txt"), expected_size: None }.to_bytes(),
+        DataPacket { is_last: false, file_id: 2, packet_number: 0, data: b"b".to_vec() }.to_bytes()
+    ];
+    for packet in packets.iter() {
+        server.send_to(packet, peer).unwrap();
+        client.recv_packet().unwrap();
+    }
+
+    for attempt in 1..=2 {
+        assert!(client.request_retransmit().unwrap().is_empty());
+        let size = server.recv(&mut buf).unwrap();
+        assert_eq!(ResumeRequest::parse(&buf[..size]).unwrap().files[0], ResumePoint { file_id: 1, next_packet: 1 });
+        assert_eq!(client.file(1).unwrap().retransmits(), attempt);
+    }
+
+    assert_eq!(client.request_retransmit().unwrap(), vec![1]);
+    assert!(client.file(1).is_none());
+    assert_eq!(client.failed_files(), vec![IncompleteFile {
+        file_id: 1,
+        name: Some(String::from("a.txt")),
+        received_segments: 2,
+        missing_segments: Some(vec![1])
+    }]);
+    assert!(client.to_json().contains("\"failed\":[{\"id\":1,\"name\":\"a.txt\",\"missing_segments\":[1]}]"));
+
+    // Its packets are dropped from then on, and b.txt was never counted
+    server.send_to(&DataPacket { is_last: false, file_id: 1, packet_number: 1, data: b"b".to_vec() }.to_bytes(), peer).unwrap();
+    client.recv_packet().unwrap();
+    assert!(client.file(1).is_none());
+    assert_eq!(client.file(2).unwrap().retransmits(), 0);
+}
+
+#[test]
+fn datagrams_received() {
+    let (mut client, server) = loopback_client();
+    client.send_request().unwrap();
+    let mut buf = [0; 16];
+    let (_, peer) = server.recv_from(&mut buf).unwrap();
+
+    let data = DataPacket { is_last: true, file_id: 1, packet_number: 0, data: b"x".to_vec() }.to_bytes();
+    for datagram in [&[][..], &[7], &data, &data] {
+        server.send_to(datagram, peer).unwrap();
+    }
+
+    // Nothing, an unknown packet type, then a packet and its duplicate
+    assert!(client.recv_packet().is_err());
+    assert!(client.recv_packet().is_err());
+    client.recv_packet().unwrap();
+    client.recv_packet().unwrap();
+
+    let stats = client.stats();
+    assert_eq!(stats.datagrams_received, 4);
+    assert_eq!(stats.duplicate_packets, 1);
+}
+
+#[test]
+fn received_bitmap() {
+    let mut file = File::new(1);
+    assert!(file.received_bitmap().is_empty());
+
+    for packet_number in [0, 2, 5] {
+        file.report_data_packet(DataPacket { is_last: false, file_id: 1, packet_number, data: vec![1] });
+    }
+    assert_eq!(file.received_bitmap(), vec![true, false, true, false, false, true]);
+
+    file.report_data_packet(DataPacket { is_last: true, file_id: 1, packet_number: 7, data: vec![1] });
+    assert_eq!(file.received_bitmap(), vec![true, false, true, false, false, true, false, true]);
+}
+
+#[test]
+fn content_hash() {
+    let mut file = File::new(1);
+    file.report_data_packet(DataPacket { is_last: true, file_id: 1, packet_number: 1, data: b"56789".to_vec() });
+    file.report_data_packet(DataPacket { is_last: false, file_id: 1, packet_number: 0, data: b"1234".to_vec() });
+
+    assert_eq!(file.content_hash(HashAlgorithm::Crc32), vec![0xcb, 0xf4, 0x39, 0x26]);
+    assert_eq!(File::new(2).content_hash(HashAlgorithm::Crc32), vec![0, 0, 0, 0]);
+
+    #[cfg(feature = "sha256")]
+    assert_eq!(file.content_hash(HashAlgorithm::Sha256),
+               b"\x15\xe2\xb0\xd3\xc3\x38\x91\xeb\xb0\xf1\xef\x60\x9e\xc4\x19\x42\x0c\x20\xe3\x20\xce\x94\xc6\x5f\xbc\x8c\x33\x12\x44\x8e\xb2\x25".to_vec());
+}
+
+#[test]
+fn assemble() {
+    let mut file = File::new(1);
+    assert!(file.assemble().is_err());
+
+    file.report_header_packet(HeaderPacket { file_id: 1, name: String::from("a.txt"), expected_size: Some(6) });
+    file.report_data_packet(DataPacket { is_last: true, file_id: 1, packet_number: 2, data: b"ef".to_vec() });
+    file.report_data_packet(DataPacket { is_last: false, file_id: 1, packet_number: 0, data: b"ab".to_vec() });
+    assert_eq!(file.assemble().unwrap_err().to_string(), "unable to assemble a.txt, missing packet id 1");
+
+    file.report_data_packet(DataPacket { is_last: false, file_id: 1, packet_number: 1, data: b"cd".to_vec() });
+    assert_eq!(file.assemble().unwrap(), b"abcdef");
+
+    file.expected_size = Some(7);
+    assert!(file.assemble().is_err());
+}
+
+#[test]
+fn short_file_policy() {
+    let dir = test_dir("short_file_policy");
+    let write = |policy: ShortFilePolicy| {
+        let mut client = test_client();
+        client.set_output_dir(&dir);
+        client.set_short_file_policy(policy);
+        client.process_datagram(HeaderPacket { file_id: 1, name: String::from("short.bin"), expected_size: Some(6) }.to_bytes()).unwrap();
+        client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 0, data: b"abcd".to_vec() }.to_bytes()).unwrap();
+        let _ = std::fs::remove_file(dir.join("short.bin"));
+        client.finalize_files_ref().map(|_| std::fs::read(dir.join("short.bin")).unwrap())
+    };
+
+    assert!(write(ShortFilePolicy::Error).is_err());
+    assert!(!dir.join("short.bin").exists());
+    assert_eq!(write(ShortFilePolicy::Truncate).unwrap(), b"abcd");
+    assert_eq!(write(ShortFilePolicy::PadZero).unwrap(), b"abcd\0\0");
+
+    // Too much data is never fine
+    let mut file = File::new(1);
+    file.report_header_packet(HeaderPacket { file_id: 1, name: String::from("long.bin"), expected_size: Some(2) });
+    file.report_data_packet(DataPacket { is_last: true, file_id: 1, packet_number: 0, data: b"abcd".to_vec() });
+    assert!(file.assemble_with(ShortFilePolicy::Truncate).is_err());
+    assert!(file.assemble_with(ShortFilePolicy::PadZero).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn arrival_order() {
+    let mut client = test_client();
+    client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 0, data: vec![0] }.to_bytes()).unwrap();
+    assert_eq!(client.file(1).unwrap().arrival_order(), None);
+
+    client.set_log_arrivals(true);
+    for packet_number in [3, 0, 2, 2, 1] {
+        client.process_datagram(DataPacket { is_last: packet_number == 3, file_id: 2, packet_number, data: vec![0] }.to_bytes()).unwrap();
+    }
+    client.process_datagram(HeaderPacket { file_id: 2, name: String::from("scrambled"), expected_size: None }.to_bytes()).unwrap();
+
+    assert_eq!(client.file(2).unwrap().arrival_order(), Some(&[3, 0, 2, 2, 1][..]));
+    assert_eq!(client.file(1).unwrap().arrival_order(), None);
+}
+
+#[test]
+fn segment_reader() {
+    use std::io::Read;
+
+    let mut file = File::new(1);
+    let segments: [&[u8]; 4] = [b"hello ", b"segmented ", b"", b"world"];
+    for (packet_number, data) in segments.iter().enumerate().rev() {
+        file.report_data_packet(DataPacket { is_last: packet_number == 3, file_id: 1, packet_number: packet_number as PacketNumber, data: data.to_vec() });
+    }
+
+    let mut reader = file.reader();
+    let mut read = Vec::new();
+    let mut chunk = [0; 4];
+    loop {
+        let count = reader.read(&mut chunk).unwrap();
+        if count == 0 {
+            break;
+        }
+        read.extend_from_slice(&chunk[..count]);
+    }
+    assert_eq!(read, segments.concat());
+    assert_eq!(reader.read(&mut chunk).unwrap(), 0);
+
+    let mut copied = Vec::new();
+    std::io::copy(&mut file.reader(), &mut copied).unwrap();
+    assert_eq!(copied, segments.concat());
+
+    // A gap stops the reader
+    let mut partial = File::new(2);
+    partial.report_data_packet(DataPacket { is_last: false, file_id: 2, packet_number: 0, data: b"ab".to_vec() });
+    partial.report_data_packet(DataPacket { is_last: true, file_id: 2, packet_number: 2, data: b"ef".to_vec() });
+    let mut read = Vec::new();
+    partial.reader().read_to_end(&mut read).unwrap();
+    assert_eq!(read, b"ab");
+}
+
+#[test]
+fn stalled_header_only() {
+    let dir = test_dir("stalled_header_only");
+    let clock = ManualClock::new();
+    let mut client = test_client();
+    client.set_clock(clock.clone());
+    client.set_output_dir(&dir);
+
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("empty.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(HeaderPacket { file_id: 2, name: String::from("data.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: false, file_id: 2, packet_number: 0, data: vec![2] }.to_bytes()).unwrap();
+    // Data with no name yet is waiting on its header instead
+    client.process_datagram(DataPacket { is_last: false, file_id: 3, packet_number: 0, data: vec![3] }.to_bytes()).unwrap();
+
+    assert!(client.stalled_header_only(Duration::from_secs(5)).is_empty());
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(client.stalled_header_only(Duration::from_secs(5)), vec![(1, String::from("empty.txt"))]);
+
+    assert!(client.finalize_as_empty(2).is_err());
+    assert!(client.finalize_as_empty(1).unwrap());
+    assert!(client.stalled_header_only(Duration::from_secs(5)).is_empty());
+
+    client.finalize_files_ref().unwrap();
+    assert_eq!(std::fs::metadata(dir.join("empty.txt")).unwrap().len(), 0);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn reconcile() {
+    let mut client = test_client();
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("a.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 0, data: vec![1] }.to_bytes()).unwrap();
+    assert!(client.reconcile().is_ok());
+
+    // A stray packet for the finished id isn't a straggler
+    client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 3, data: vec![1] }.to_bytes()).unwrap();
+    assert!(client.reconcile().is_ok());
+
+    client.process_datagram(DataPacket { is_last: true, file_id: 2, packet_number: 1, data: vec![2] }.to_bytes()).unwrap();
+    match client.reconcile() {
+        Err(SegFsError::Incomplete { completed: 1, expected: 2, files }) => {
+            assert_eq!(files, vec![IncompleteFile { file_id: 2, name: None, received_segments: 1, missing_segments: Some(vec![0]) }]);
+        }
+        other => panic!("expected file id 2 to be reported, got {:?}", other)
+    }
+}
+
+#[test]
+fn clean_partials() {
+    let dir = test_dir("clean_partials");
+    let old = std::time::SystemTime::now() - STALE_PARTIAL_AGE - Duration::from_secs(1);
+
+    for name in ["crashed.txt.part", "other.bin.part", "running.txt.part", "done.txt"] {
+        let file = std::fs::File::create(dir.join(name)).unwrap();
+        if name != "running.txt.part" {
+            file.set_modified(old).unwrap();
+        }
+    }
+
+    let clock = ManualClock::new();
+    let mut client = test_client();
+    client.set_clock(clock.clone());
+    assert_eq!(client.clean_partials(&dir).unwrap(), 2);
+    assert!(!dir.join("crashed.txt.part").exists());
+    assert!(!dir.join("other.bin.part").exists());
+    assert!(dir.join("running.txt.part").exists());
+    assert!(dir.join("done.txt").exists());
+    assert_eq!(client.clean_partials(&dir).unwrap(), 0);
+
+    // Left long enough by the client's clock, the running one goes too
+    clock.advance(STALE_PARTIAL_AGE);
+    assert_eq!(client.clean_partials(&dir).unwrap(), 1);
+    assert!(!dir.join("running.txt.part").exists());
+    assert!(dir.join("done.txt").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn write_timeout() {
+    /// The real disk, telling the test about each file removed
+    struct WatchedFileSystem(std::sync::mpsc::Sender<PathBuf>);
+
+    impl FileSystem for WatchedFileSystem {
+        fn create(&self, path: &std::path::Path) -> std::io::Result<Box<dyn OutputFile>> {
+            StdFileSystem.create(path)
+        }
+
+        fn rename(&self, from: &std::path::Path, to: &std::path::Path) -> std::io::Result<()> {
+            StdFileSystem.rename(from, to)
+        }
+
+        fn remove(&self, path: &std::path::Path) -> std::io::Result<()> {
+            let result = StdFileSystem.remove(path);
+            let _ = self.0.send(path.to_path_buf());
+            result
+        }
+
+        fn create_dir_all(&self, path: &std::path::Path) -> std::io::Result<()> {
+            StdFileSystem.create_dir_all(path)
+        }
+
+        fn metadata(&self, path: &std::path::Path) -> std::io::Result<Metadata> {
+            StdFileSystem.metadata(path)
+        }
+
+        fn list(&self, dir: &std::path::Path) -> std::io::Result<Vec<PathBuf>> {
+            StdFileSystem.list(dir)
+        }
+    }
+
+    let dir = test_dir("write_timeout");
+    let path = dir.join("slow.txt");
+    let (release, stalled) = std::sync::mpsc::channel();
+    let (removed_sender, removed) = std::sync::mpsc::channel();
+    let filesystem: Arc<dyn FileSystem> = Arc::new(WatchedFileSystem(removed_sender));
+
+    // The writer stalls part way through, until the test lets it go
+    std::fs::write(dir.join("slow.txt.part"), b"sl").unwrap();
+    let result = write_with_timeout(&filesystem, &path, Duration::from_millis(10), move |part| {
+        let _ = stalled.recv();
+        std::fs::write(part, b"slow").map_err(|e| e.to_string().into())
+    });
+    match result {
+        Err(SegFsError::WriteTimeout { path: timed_out, timeout }) => assert_eq!((timed_out, timeout), (path.clone(), Duration::from_millis(10))),
+        _ => panic!("expected a write timeout")
+    }
+    assert_eq!(removed.recv().unwrap(), dir.join("slow.txt.part"));
+    assert!(!dir.join("slow.txt.part").exists());
+
+    // Once the writer gets going again it cleans up after itself rather than
+    // putting the file in place
+    release.send(()).unwrap();
+    assert_eq!(removed.recv().unwrap(), dir.join("slow.txt.part"));
+    assert!(!path.exists());
+    assert!(!dir.join("slow.txt.part").exists());
+
+    // A timeout that isn't hit changes nothing
+    let mut client = test_client();
+    client.set_output_dir(&dir);
+    client.set_write_timeout(Some(Duration::from_secs(5)));
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("fast.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 0, data: b"fast".to_vec() }.to_bytes()).unwrap();
+    client.finalize_files_ref().unwrap();
+    assert_eq!(std::fs::read(dir.join("fast.txt")).unwrap(), b"fast");
+    assert!(!dir.join("fast.txt.part").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn buffered_bytes_limit() {
+    let dir = test_dir("buffered_bytes_limit");
+    let mut client = test_client();
+    client.set_output_dir(&dir);
+    client.set_buffered_bytes_limit(Some(10));
+
+    let complete = |client: &mut Client, file_id: FileId, size: usize| {
+        client.process_datagram(HeaderPacket { file_id, name: format!("{}.bin", file_id), expected_size: None }.to_bytes()).unwrap();
+        client.process_datagram(DataPacket { is_last: true, file_id, packet_number: 0, data: vec![file_id; size] }.to_bytes()).unwrap();
+    };
+
+    complete(&mut client, 1, 4);
+    complete(&mut client, 2, 4);
+    assert_eq!(client.evicted_files().count(), 0);
+    assert!(!dir.join("1.bin").exists());
+
+    // Over the limit, so the oldest goes to disk
+    complete(&mut client, 3, 4);
+    assert_eq!(client.evicted_files().collect::<Vec<_>>(), vec![(1, dir.join("1.bin").as_path())]);
+    assert_eq!(std::fs::read(dir.join("1.bin")).unwrap(), vec![1; 4]);
+    assert_eq!(client.file_count(), 3);
+    assert!(client.file(1).is_none());
+
+    // A big file can take everything before it out
+    complete(&mut client, 4, 9);
+    assert_eq!(client.evicted_files().map(|(id, _)| id).collect::<Vec<_>>(), vec![1, 2, 3]);
+    assert_eq!(client.file_count(), 4);
+
+    client.finalize_files_ref().unwrap();
+    for file_id in 1..=4 {
+        assert!(dir.join(format!("{}.bin", file_id)).exists());
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn output_path_for() {
+    let dir = test_dir("output_path_for");
+    let mut client = test_client();
+    client.set_output_dir(&dir);
+    client.set_subdir_policy(SubdirPolicy::CreateDirs);
+    client.set_naming_template("{index}_{id}_{name}").unwrap();
+
+    client.process_datagram(HeaderPacket { file_id: 4, name: String::from("first.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(HeaderPacket { file_id: 7, name: String::from("sub/second.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 4, packet_number: 0, data: b"1".to_vec() }.to_bytes()).unwrap();
+
+    let first = client.output_path_for("first.txt").unwrap();
+    let second = client.output_path_for("sub/second.txt").unwrap();
+    assert_eq!(first, dir.join("0_4_first.txt"));
+    assert_eq!(second, dir.join("sub").join("1_7_second.txt"));
+    assert!(!dir.join("sub").exists());
+    assert!(client.output_path_for("third.txt").is_err());
+
+    client.process_datagram(DataPacket { is_last: true, file_id: 7, packet_number: 0, data: b"2".to_vec() }.to_bytes()).unwrap();
+    assert_eq!(client.finalize_file(4).unwrap(), Some(first));
+    assert_eq!(client.output_path_for("sub/second.txt").unwrap(), second);
+    assert_eq!(client.finalize_file(7).unwrap(), Some(second.clone()));
+    assert!(second.exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn pause_receiving() {
+    let (mut client, server) = loopback_client();
+    client.send_request().unwrap();
+    let mut buf = [0; 16];
+    let (_, peer) = server.recv_from(&mut buf).unwrap();
+    server.send_to(&DataPacket { is_last: true, file_id: 1, packet_number: 0, data: vec![1] }.to_bytes(), peer).unwrap();
+
+    client.pause();
+    assert!(matches!(client.recv_packet(), Err(SegFsError::Paused)));
+    assert!(client.file(1).is_none());
+    assert_eq!(client.stats().bytes_received, 0);
+
+    // The datagram was left waiting on the socket
+    client.resume();
+    client.recv_packet().unwrap();
+    assert_eq!(client.file(1).unwrap().segment_count(), 1);
+}
+
+#[cfg(all(test, feature = "tracing"))]
+#[tracing_test::traced_test]
+#[test]
+fn tracing_events() {
+    let (mut client, server) = loopback_client();
+    client.send_request().unwrap();
+    let mut buf = [0; 16];
+    let (_, peer) = server.recv_from(&mut buf).unwrap();
+
+    server.send_to(&HeaderPacket { file_id: 4, name: String::from("traced.txt"), expected_size: None }.to_bytes(), peer).unwrap();
+    server.send_to(&DataPacket { is_last: true, file_id: 4, packet_number: 0, data: b"abc".to_vec() }.to_bytes(), peer).unwrap();
+    client.recv_packet().unwrap();
+    client.recv_packet().unwrap();
+
+    client.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+    assert!(matches!(client.recv_packet(), Err(SegFsError::Timeout)));
+
+    assert!(logs_contain("sending request count=1"));
+    assert!(logs_contain("header packet file_id=4 name=\"traced.txt\""));
+    assert!(logs_contain("data packet file_id=4 packet_number=0 bytes=3 is_last=true"));
+    assert!(logs_contain("file complete file_id=4 name=\"traced.txt\" bytes=3"));
+    assert!(logs_contain("timed out waiting for data"));
+    assert!(logs_contain(&format!("transfer{{remote={}}}", server.local_addr().unwrap())));
+}
+
+#[test]
+fn allowed_extensions() {
+    let mut client = test_client();
+    client.set_allowed_extensions(Some(&["txt", "JPG"]));
+
+    client.process_datagram(DataPacket { is_last: false, file_id: 2, packet_number: 0, data: b"MZ".to_vec() }.to_bytes()).unwrap();
+    client.process_datagram(HeaderPacket { file_id: 2, name: String::from("virus.EXE"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 2, packet_number: 1, data: b"!!".to_vec() }.to_bytes()).unwrap();
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("notes.TXT"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 0, data: b"hi".to_vec() }.to_bytes()).unwrap();
+    client.process_datagram(HeaderPacket { file_id: 3, name: String::from("photo.jpg"), expected_size: None }.to_bytes()).unwrap();
+
+    assert_eq!(client.completed_files().collect::<Vec<_>>(), vec![(1, "notes.TXT")]);
+    assert!(client.file(2).is_none());
+    assert!(client.file(3).is_some());
+    assert_eq!(client.rejected_files().collect::<Vec<_>>(), vec!["virus.EXE"]);
+    assert_eq!(client.warnings(), &[Warning::DisallowedExtension { file_id: 2, name: String::from("virus.EXE") }]);
+
+    client.set_disallowed_extension_policy(DisallowedExtensionPolicy::Error);
+    assert!(client.process_datagram(HeaderPacket { file_id: 4, name: String::from("README"), expected_size: None }.to_bytes()).is_err());
+}
+
+#[test]
+fn conflicting_segments() {
+    let send = |client: &mut Client, data: &[u8]| {
+        client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 0, data: data.to_vec() }.to_bytes())
+    };
+
+    let mut client = test_client();
+    send(&mut client, b"abc").unwrap();
+    send(&mut client, b"abc").unwrap();
+    send(&mut client, b"xyz").unwrap();
+    assert_eq!(client.warnings(), &[
+        Warning::DuplicatePacket { file_id: 1, packet_number: 0 },
+        Warning::ConflictingSegment { file_id: 1, packet_number: 0 }
+    ]);
+    assert_eq!(client.file(1).unwrap().segments[&0], b"xyz");
+    assert_eq!(client.stats().duplicate_packets, 2);
+
+    client.set_segment_conflict_policy(SegmentConflictPolicy::KeepFirst);
+    send(&mut client, b"abc").unwrap();
+    assert_eq!(client.file(1).unwrap().segments[&0], b"xyz");
+
+    client.set_segment_conflict_policy(SegmentConflictPolicy::Error);
+    assert!(send(&mut client, b"abc").is_err());
+    assert!(send(&mut client, b"xyz").is_ok());
+}
+
+#[test]
+fn segments_remaining() {
+    let mut client = test_client();
+    assert_eq!(client.segments_remaining(), Some(0));
+
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("a.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 0, data: vec![1] }.to_bytes()).unwrap();
+    assert_eq!(client.segments_remaining(), None);
+
+    let mut remaining = Vec::new();
+    for packet_number in [3, 1, 2] {
+        client.process_datagram(DataPacket { is_last: packet_number == 3, file_id: 1, packet_number, data: vec![1] }.to_bytes()).unwrap();
+        remaining.push(client.segments_remaining());
+    }
+    assert_eq!(remaining, vec![Some(2), Some(1), Some(0)]);
+    assert_eq!(client.file_count(), 1);
+    assert_eq!(client.segments_received(), 4);
+}
+
+#[cfg(feature = "zip")]
+#[test]
+fn zip_archive() {
+    use std::io::Read;
+
+    for method in [zip::CompressionMethod::Deflated, zip::CompressionMethod::Stored] {
+        let mut client = test_client();
+        client.set_zip_compression(method);
+        client.process_datagram(HeaderPacket { file_id: 1, name: String::from("docs/one.txt"), expected_size: None }.to_bytes()).unwrap();
+        client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 1, data: b"world".to_vec() }.to_bytes()).unwrap();
+        client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 0, data: b"hello ".to_vec() }.to_bytes()).unwrap();
+        client.process_datagram(HeaderPacket { file_id: 2, name: String::from("two.bin"), expected_size: None }.to_bytes()).unwrap();
+        client.process_datagram(DataPacket { is_last: true, file_id: 2, packet_number: 0, data: vec![0, 1, 2] }.to_bytes()).unwrap();
+
+        let mut archive = std::io::Cursor::new(Vec::new());
+        client.write_zip(&mut archive).unwrap();
+
+        let mut archive = zip::ZipArchive::new(archive).unwrap();
+        assert_eq!(archive.len(), 2);
+        let mut entries = HashMap::new();
+        for index in 0..archive.len() {
+            let mut entry = archive.by_index(index).unwrap();
+            assert_eq!(entry.compression(), method);
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            entries.insert(entry.name().to_string(), data);
+        }
+
+        // Flattened the same as files written to disk
+        assert_eq!(entries["one.txt"], b"hello world");
+        assert_eq!(entries["two.bin"], vec![0, 1, 2]);
+    }
+}
+
+#[test]
+fn nameless_policy() {
+    let dir = test_dir("nameless_policy");
+    let finalize = |policy: NamelessPolicy| {
+        let mut client = test_client();
+        client.set_output_dir(&dir);
+        client.set_nameless_policy(policy);
+
+        // Nothing completes without a header yet, so put one in by hand
+        let mut file = File::new(4);
+        file.report_data_packet(DataPacket { is_last: true, file_id: 4, packet_number: 0, data: b"data".to_vec() });
+        client.final_files.push(file);
+
+        let result = client.finalize_files_ref();
+        (client, result)
+    };
+
+    let (client, result) = finalize(NamelessPolicy::Error);
+    assert!(result.is_err());
+    assert!(client.warnings().is_empty());
+    assert!(!dir.join("file_4.bin").exists());
+
+    let (client, result) = finalize(NamelessPolicy::Skip);
+    result.unwrap();
+    assert_eq!(client.warnings(), &[Warning::NamelessFileSkipped { file_id: 4 }]);
+    assert_eq!(client.completed_files().count(), 0);
+    assert!(!dir.join("file_4.bin").exists());
+
+    let (client, result) = finalize(NamelessPolicy::Synthesize);
+    result.unwrap();
+    assert!(client.warnings().is_empty());
+    assert_eq!(std::fs::read(dir.join("file_4.bin")).unwrap(), b"data");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn payload_transform() {
+    let dir = test_dir("payload_transform");
+    let mut client = test_client();
+    client.set_output_dir(&dir);
+
+    // A toy stream cipher keyed by the file id, where each packet's key
+    // stream starts from its packet number
+    let cipher = |file_id: FileId, packet_number: PacketNumber, data: &[u8]| -> Vec<u8> {
+        data.iter().enumerate().map(|(i, byte)| byte ^ file_id ^ (packet_number as u8).wrapping_add(i as u8)).collect()
+    };
+    client.set_payload_transform(cipher);
+
+    // Out of order, and with a resend, to show neither upsets it
+    client.process_datagram(DataPacket { is_last: true, file_id: 3, packet_number: 1, data: cipher(3, 1, b"world") }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: false, file_id: 3, packet_number: 0, data: cipher(3, 0, b"hello ") }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: false, file_id: 3, packet_number: 0, data: cipher(3, 0, b"hello ") }.to_bytes()).unwrap();
+    client.process_datagram(HeaderPacket { file_id: 3, name: String::from("secret.txt"), expected_size: None }.to_bytes()).unwrap();
+    assert!(client.warnings().iter().all(|warning| !matches!(warning, Warning::ConflictingSegment { .. })));
+
+    client.finalize_files_ref().unwrap();
+    assert_eq!(std::fs::read(dir.join("secret.txt")).unwrap(), b"hello world");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn pending_finalize() {
+    let dir = test_dir("pending_finalize");
+    let mut client = test_client();
+    client.set_output_dir(&dir);
+
+    for (id, name) in [(2, "b.txt"), (1, "a.txt")] {
+        client.process_datagram(HeaderPacket { file_id: id, name: String::from(name), expected_size: None }.to_bytes()).unwrap();
+    }
+    client.process_datagram(DataPacket { is_last: true, file_id: 2, packet_number: 0, data: vec![2] }.to_bytes()).unwrap();
+    assert_eq!(client.pending_finalize(), vec![2]);
+
+    client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 0, data: vec![1] }.to_bytes()).unwrap();
+    assert_eq!(client.pending_finalize(), vec![2, 1]);
+
+    client.finalize_file(2).unwrap();
+    assert_eq!(client.pending_finalize(), vec![1]);
+
+    client.finalize_files_ref().unwrap();
+    assert!(client.pending_finalize().is_empty());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn finalize_failure_keeps_files() {
+    let dir = test_dir("finalize_failure_keeps_files");
+    let receive = |client: &mut Client, files: &[(FileId, &str)]| {
+        for &(id, name) in files {
+            client.process_datagram(HeaderPacket { file_id: id, name: String::from(name), expected_size: None }.to_bytes()).unwrap();
+            client.process_datagram(DataPacket { is_last: true, file_id: id, packet_number: 0, data: vec![id] }.to_bytes()).unwrap();
+        }
+    };
+
+    // The failed file and the one after it, never tried, are both kept
+    let mut client = test_client();
+    client.set_output_dir(&dir);
+    client.set_subdir_policy(SubdirPolicy::Reject);
+    receive(&mut client, &[(1, "a/b"), (2, "good.txt")]);
+    assert!(client.finalize_files_ref().is_err());
+    assert_eq!(client.pending_finalize(), vec![1, 2]);
+    assert!(!dir.join("good.txt").exists());
+
+    // Once the bad file is dealt with the rest go out
+    client.set_subdir_policy(SubdirPolicy::Flatten);
+    client.finalize_files_ref().unwrap();
+    assert!(client.pending_finalize().is_empty());
+    assert_eq!(std::fs::read(dir.join("good.txt")).unwrap(), vec![2]);
+    assert_eq!(std::fs::read(dir.join("b")).unwrap(), vec![1]);
+
+    // Written concurrently, the good file goes out and only the bad one stays
+    let mut client = test_client();
+    client.set_output_dir(&dir);
+    client.set_subdir_policy(SubdirPolicy::Reject);
+    client.set_write_concurrency(2);
+    receive(&mut client, &[(3, "c/d"), (4, "other.txt")]);
+    assert!(client.finalize_files_ref().is_err());
+    assert_eq!(client.pending_finalize(), vec![3]);
+    assert_eq!(std::fs::read(dir.join("other.txt")).unwrap(), vec![4]);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn journal_recovery() {
+    let dir = test_dir("journal_recovery");
+    let journal = dir.join("journal");
+
+    // A run that crashed after noting the write and before the rename
+    let crashed = Journal::new(&journal);
+    crashed.begin(1, &dir.join("a.txt")).unwrap();
+    std::fs::write(dir.join("a.txt.part"), b"hal").unwrap();
+
+    let mut client = test_client();
+    client.set_output_dir(&dir);
+    client.set_journal(Some(&journal));
+    assert_eq!(client.recover_journal().unwrap(), vec![InFlight { file_id: 1, path: dir.join("a.txt") }]);
+    assert!(!dir.join("a.txt.part").exists());
+    assert!(client.recover_journal().unwrap().is_empty());
+
+    // Downloading it again leaves nothing in flight
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("a.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 0, data: b"half".to_vec() }.to_bytes()).unwrap();
+    client.finalize_files_ref().unwrap();
+    assert_eq!(std::fs::read(dir.join("a.txt")).unwrap(), b"half");
+    assert!(!dir.join("a.txt.part").exists());
+    assert!(crashed.in_flight().unwrap().is_empty());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn max_packet_number_seen() {
+    let mut client = test_client();
+    assert_eq!(client.max_packet_number_seen(), None);
+
+    for (file_id, packet_number) in [(1, 3), (2, 40), (3, 7)] {
+        client.process_datagram(DataPacket { is_last: false, file_id, packet_number, data: vec![0] }.to_bytes()).unwrap();
+    }
+    assert_eq!(client.max_packet_number_seen(), Some(40));
+
+    client.reset();
+    assert_eq!(client.max_packet_number_seen(), None);
+}
+
+#[test]
+fn memory_filesystem() {
+    let dir = std::env::temp_dir().join(format!("seg-fs-memory_filesystem-{}", std::process::id()));
+    let filesystem = MemoryFileSystem::new();
+    let mut client = test_client();
+    client.set_output_dir(&dir);
+    client.set_subdir_policy(SubdirPolicy::CreateDirs);
+    client.set_filesystem(filesystem.clone());
+
+    for (id, name) in [(1, "a.txt"), (2, "sub/b.txt")] {
+        client.process_datagram(HeaderPacket { file_id: id, name: String::from(name), expected_size: None }.to_bytes()).unwrap();
+        client.process_datagram(DataPacket { is_last: true, file_id: id, packet_number: 0, data: name.as_bytes().to_vec() }.to_bytes()).unwrap();
+    }
+    client.finalize_files_ref().unwrap();
+
+    assert_eq!(filesystem.paths(), vec![dir.join("a.txt"), dir.join("sub/b.txt")]);
+    assert_eq!(filesystem.read(&dir.join("a.txt")).unwrap(), b"a.txt");
+    assert_eq!(filesystem.read(&dir.join("sub/b.txt")).unwrap(), b"sub/b.txt");
+    assert!(!dir.exists());
+
+    // Writes that go through a `.part` file leave only the finished file
+    let filesystem = MemoryFileSystem::new();
+    client.set_filesystem(filesystem.clone());
+    client.set_write_timeout(Some(Duration::from_secs(5)));
+    client.process_datagram(HeaderPacket { file_id: 3, name: String::from("c.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 3, packet_number: 0, data: b"c".to_vec() }.to_bytes()).unwrap();
+    client.finalize_files_ref().unwrap();
+    assert_eq!(filesystem.paths(), vec![dir.join("c.txt")]);
+}
+
+#[test]
+fn memory_filesystem_existing() {
+    let dir = std::env::temp_dir().join(format!("seg-fs-memory_filesystem_existing-{}", std::process::id()));
+    let old = std::time::SystemTime::now() - STALE_PARTIAL_AGE - Duration::from_secs(1);
+    let filesystem = MemoryFileSystem::new();
+    filesystem.insert(&dir.join("have.txt"), b"old", old);
+    filesystem.insert(&dir.join("crashed.txt.part"), b"", old);
+    filesystem.insert(&dir.join("running.txt.part"), b"", std::time::SystemTime::now());
+
+    let mut client = test_client();
+    client.set_output_dir(&dir);
+    client.set_skip_existing(true);
+    client.set_filesystem(filesystem.clone());
+
+    // Only what's on the client's filesystem counts, the disk is never looked at
+    assert_eq!(client.clean_partials(&dir).unwrap(), 1);
+    for (id, name) in [(1, "have.txt"), (2, "new.txt")] {
+        client.process_datagram(HeaderPacket { file_id: id, name: String::from(name), expected_size: None }.to_bytes()).unwrap();
+        client.process_datagram(DataPacket { is_last: true, file_id: id, packet_number: 0, data: b"new".to_vec() }.to_bytes()).unwrap();
+    }
+    client.finalize_files_ref().unwrap();
+
+    assert_eq!(filesystem.paths(), vec![dir.join("have.txt"), dir.join("new.txt"), dir.join("running.txt.part")]);
+    assert_eq!(filesystem.read(&dir.join("have.txt")).unwrap(), b"old");
+    assert!(!dir.exists());
+}
+
+#[test]
+fn finalize_batch() {
+    let dir = test_dir("finalize_batch");
+    std::fs::write(dir.join("have.txt"), b"old").unwrap();
+
+    let mut client = test_client();
+    client.set_output_dir(&dir);
+    client.set_skip_existing(true);
+    client.set_nameless_policy(NamelessPolicy::Skip);
+
+    let headers = [(1, "new.txt", None), (2, "have.txt", None), (3, "short.txt", Some(10))];
+    for (id, name, expected_size) in headers {
+        client.process_datagram(HeaderPacket { file_id: id, name: String::from(name), expected_size }.to_bytes()).unwrap();
+    }
+    for id in [1, 3] {
+        client.process_datagram(DataPacket { is_last: true, file_id: id, packet_number: 0, data: b"data".to_vec() }.to_bytes()).unwrap();
+    }
+    let mut nameless = File::new(4);
+    nameless.report_data_packet(DataPacket { is_last: true, file_id: 4, packet_number: 0, data: b"??".to_vec() });
+    client.final_files.push(nameless);
+
+    let batch = client.finalize_batch();
+    assert!(!batch.is_success());
+    assert_eq!(batch.written().collect::<Vec<_>>(), vec![dir.join("new.txt")]);
+    assert_eq!(batch.files.iter().map(|file| (file.file_id, file.bytes)).collect::<Vec<_>>(), vec![(4, 2), (1, 4), (3, 4), (2, 0)]);
+
+    assert_eq!(batch.files[0].status, FileStatus::Skipped);
+    assert_eq!(batch.files[1].crc, Some(0xadf3_f363));
+    assert!(matches!(batch.files[2].status, FileStatus::Failed(_)));
+    assert_eq!(batch.files[3], FileResult { file_id: 2, path: Some(dir.join("have.txt")), bytes: 0, crc: None, status: FileStatus::Skipped });
+
+    // The failed file is kept for another go
+    assert_eq!(client.pending_finalize(), vec![3]);
+    client.set_short_file_policy(ShortFilePolicy::Truncate);
+    assert_eq!(client.finalize_batch().written().collect::<Vec<_>>(), vec![dir.join("short.txt")]);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn prefix_bytes() {
+    let dir = test_dir("prefix_bytes");
+    let mut client = test_client();
+    client.set_output_dir(&dir);
+    client.set_prefix_bytes(Some(6));
+
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("big.bin"), expected_size: Some(12) }.to_bytes()).unwrap();
+    client.process_datagram(HeaderPacket { file_id: 2, name: String::from("small.bin"), expected_size: None }.to_bytes()).unwrap();
+
+    // The prefix ends part way through the second segment
+    client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 1, data: b"bbbb".to_vec() }.to_bytes()).unwrap();
+    assert_eq!(client.completed_files().count(), 0);
+    client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 0, data: b"aaaa".to_vec() }.to_bytes()).unwrap();
+    assert_eq!(client.pending_finalize(), vec![1]);
+
+    // The rest of it is ignored
+    client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 2, data: b"cccc".to_vec() }.to_bytes()).unwrap();
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("big.bin"), expected_size: Some(12) }.to_bytes()).unwrap();
+    assert!(client.file(1).is_some_and(|file| file.data_len() == 6));
+
+    client.process_datagram(DataPacket { is_last: true, file_id: 2, packet_number: 0, data: b"abc".to_vec() }.to_bytes()).unwrap();
+    client.finalize_files_ref().unwrap();
+    assert_eq!(std::fs::read(dir.join("big.bin")).unwrap(), b"aaaabb");
+    assert_eq!(std::fs::read(dir.join("small.bin")).unwrap(), b"abc");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unexpected_file_ids() {
+    let mut client = test_client();
+
+    // Five ids when three were asked for; 9 and 7 finish, so they're kept
+    for id in [9, 1, 7, 3, 5] {
+        client.process_datagram(HeaderPacket { file_id: id, name: format!("{}.txt", id), expected_size: None }.to_bytes()).unwrap();
+    }
+    for id in [9, 7] {
+        client.process_datagram(DataPacket { is_last: true, file_id: id, packet_number: 0, data: vec![id] }.to_bytes()).unwrap();
+    }
+    client.process_datagram(DataPacket { is_last: false, file_id: 5, packet_number: 0, data: vec![5] }.to_bytes()).unwrap();
+
+    assert_eq!(client.unexpected_file_ids(3), vec![3, 5]);
+    assert_eq!(client.unexpected_file_ids(1), vec![1, 3, 5, 9]);
+    assert!(client.unexpected_file_ids(5).is_empty());
+    assert!(client.unexpected_file_ids(8).is_empty());
+}
@@ -0,0 +1,57 @@
+use std::time::{Instant, SystemTime};
+
+/// Source of the current time, so timing logic can be tested without sleeping
+pub trait Clock {
+    fn now(&self) -> Instant;
+
+    /// The time of day, for comparing against when files were written
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: std::time::Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock which only moves when told to, shared between a test and a client
+#[cfg(test)]
+#[derive(Clone)]
+pub struct ManualClock(std::rc::Rc<std::cell::Cell<Instant>>, Instant, SystemTime);
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self(std::rc::Rc::new(std::cell::Cell::new(now)), now, SystemTime::now())
+    }
+
+    pub fn advance(&self, duration: std::time::Duration) {
+        self.0.set(self.0.get() + duration);
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.0.get()
+    }
+
+    /// The time of day it was made, moved along with it
+    fn system_now(&self) -> SystemTime {
+        self.2 + (self.0.get() - self.1)
+    }
+
+    /// Sleeping just moves the clock along
+    fn sleep(&self, duration: std::time::Duration) {
+        self.advance(duration);
+    }
+}
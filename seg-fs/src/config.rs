@@ -0,0 +1,191 @@
+use super::client::*;
+use super::error::*;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Settings read from a `segfs.toml` file. Every key is optional, anything
+/// left out keeps the client's default, and command line flags win over
+/// anything set here.
+///
+/// ```toml
+/// address = "normandy"
+/// port = 6014
+/// output_dir = "downloads"
+/// read_timeout_ms = 1000
+/// subdir_policy = "create_dirs"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    /// Servers the client may talk to, as host names or addresses
+    pub allowed_servers: Option<Vec<String>>,
+    pub bind: Option<String>,
+    pub files: Option<usize>,
+    pub json: Option<bool>,
+    pub plain: Option<bool>,
+    pub output_dir: Option<PathBuf>,
+    pub read_timeout_ms: Option<u64>,
+    pub max_total_bytes: Option<u64>,
+    pub prefix_bytes: Option<u64>,
+    pub rate_limit: Option<u64>,
+    pub request_burst: Option<usize>,
+    pub max_retransmits_per_file: Option<u32>,
+    pub durable: Option<bool>,
+    /// Where to keep a journal of the files being written
+    pub journal: Option<PathBuf>,
+    pub skip_existing: Option<bool>,
+    pub drop_repeated_files: Option<bool>,
+    pub naming_template: Option<String>,
+    pub expected_files: Option<Vec<String>>,
+    pub allowed_extensions: Option<Vec<String>>,
+    pub subdir_policy: Option<SubdirPolicy>,
+    pub unexpected_file_policy: Option<UnexpectedFilePolicy>,
+    pub disallowed_extension_policy: Option<DisallowedExtensionPolicy>,
+    pub control_char_policy: Option<ControlCharPolicy>,
+    pub segment_conflict_policy: Option<SegmentConflictPolicy>,
+    pub short_file_policy: Option<ShortFilePolicy>,
+    pub nameless_policy: Option<NamelessPolicy>,
+    pub bad_header_policy: Option<BadHeaderPolicy>,
+    pub hash_algorithm: Option<HashAlgorithm>
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, SegFsError> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("unable to read config {}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| format!("bad config {}: {}", path.display(), e).into())
+    }
+
+    pub fn parse(text: &str) -> Result<Self, SegFsError> {
+        Ok(toml::from_str(text).map_err(|e| e.message().to_string())?)
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout_ms.map(Duration::from_millis)
+    }
+
+    /// Apply the client settings, leaving alone any that aren't set
+    pub fn apply(&self, client: &mut Client) -> Result<(), SegFsError> {
+        if let Some(dir) = &self.output_dir {
+            client.set_output_dir(dir);
+        }
+        if let Some(timeout) = self.read_timeout() {
+            client.set_read_timeout(Some(timeout))?;
+        }
+        if self.max_total_bytes.is_some() {
+            client.set_max_total_bytes(self.max_total_bytes);
+        }
+        if self.prefix_bytes.is_some() {
+            client.set_prefix_bytes(self.prefix_bytes);
+        }
+        if self.rate_limit.is_some() {
+            client.set_rate_limit(self.rate_limit);
+        }
+        if let Some(count) = self.request_burst {
+            client.set_request_burst(count);
+        }
+        if self.max_retransmits_per_file.is_some() {
+            client.set_max_retransmits_per_file(self.max_retransmits_per_file);
+        }
+        if let Some(durable) = self.durable {
+            client.set_durable(durable);
+        }
+        if let Some(journal) = &self.journal {
+            client.set_journal(Some(journal));
+        }
+        if let Some(skip_existing) = self.skip_existing {
+            client.set_skip_existing(skip_existing);
+        }
+        if let Some(drop_repeated_files) = self.drop_repeated_files {
+            client.set_drop_repeated_files(drop_repeated_files);
+        }
+        if let Some(template) = &self.naming_template {
+            client.set_naming_template(template)?;
+        }
+        if let Some(names) = &self.expected_files {
+            client.expect_files(&names.iter().map(String::as_str).collect::<Vec<_>>());
+        }
+        if let Some(extensions) = &self.allowed_extensions {
+            client.set_allowed_extensions(Some(&extensions.iter().map(String::as_str).collect::<Vec<_>>()));
+        }
+        if let Some(policy) = self.subdir_policy {
+            client.set_subdir_policy(policy);
+        }
+        if let Some(policy) = self.unexpected_file_policy {
+            client.set_unexpected_file_policy(policy);
+        }
+        if let Some(policy) = self.disallowed_extension_policy {
+            client.set_disallowed_extension_policy(policy);
+        }
+        if let Some(policy) = self.control_char_policy {
+            client.set_control_char_policy(policy);
+        }
+        if let Some(policy) = self.segment_conflict_policy {
+            client.set_segment_conflict_policy(policy);
+        }
+        if let Some(policy) = self.short_file_policy {
+            client.set_short_file_policy(policy);
+        }
+        if let Some(policy) = self.nameless_policy {
+            client.set_nameless_policy(policy);
+        }
+        if let Some(policy) = self.bad_header_policy {
+            client.set_bad_header_policy(policy);
+        }
+        if let Some(algorithm) = self.hash_algorithm {
+            client.set_hash_algorithm(algorithm);
+        }
+        Ok(())
+    }
+}
+
+impl Client {
+    /// A client set up from a config file's settings
+    pub fn with_config(udp_socket: std::net::UdpSocket, config: &Config) -> Result<Self, SegFsError> {
+        let mut client = Client::new(udp_socket);
+        config.apply(&mut client)?;
+        Ok(client)
+    }
+}
+
+#[test]
+fn load_config() {
+    let dir = std::env::temp_dir().join(format!("seg-fs-load_config-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("segfs.toml");
+    std::fs::write(&path, "address = \"localhost\"\n\
+                           port = 7000\n\
+                           output_dir = \"downloads\"\n\
+                           read_timeout_ms = 250\n\
+                           max_total_bytes = 4096\n\
+                           naming_template = \"{id}_{name}\"\n\
+                           expected_files = [\"a.txt\"]\n\
+                           subdir_policy = \"create_dirs\"\n\
+                           unexpected_file_policy = \"warn\"\n").unwrap();
+
+    let config = Config::load(&path).unwrap();
+    assert_eq!(config, Config {
+        address: Some(String::from("localhost")),
+        port: Some(7000),
+        output_dir: Some(PathBuf::from("downloads")),
+        read_timeout_ms: Some(250),
+        max_total_bytes: Some(4096),
+        naming_template: Some(String::from("{id}_{name}")),
+        expected_files: Some(vec![String::from("a.txt")]),
+        subdir_policy: Some(SubdirPolicy::CreateDirs),
+        unexpected_file_policy: Some(UnexpectedFilePolicy::Warn),
+        ..Config::default()
+    });
+
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let client = Client::with_config(socket, &config).unwrap();
+    assert_eq!(client.missing_expected_files(), vec![String::from("a.txt")]);
+
+    assert!(Config::parse("port = \"not a port\"").is_err());
+    assert!(Config::parse("colour = true").is_err());
+    assert!(Config::parse("subdir_policy = \"sideways\"").is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,406 @@
+use super::client::Client;
+use super::error::*;
+use super::rng::SmallRng;
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+/// Settings for `download_reliable`
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Local address to bind to
+    pub bind: String,
+    /// How long a single receive waits before checking on the transfer
+    pub recv_timeout: Duration,
+    /// How many more times to send the request while the server hasn't
+    /// answered at all, 5 by default
+    pub initial_retries: usize,
+    /// How long to wait for the first answer before asking again, doubling
+    /// after each try. Starts at half a second.
+    pub initial_backoff: Duration,
+    /// How far each wait for the first answer may be moved at random, as a
+    /// fraction of it: 0.5 means anywhere from half to one and a half times
+    /// as long. Clients started together then don't all ask again at the
+    /// same moment. Off by default.
+    pub jitter: f64,
+    /// Seed for the client's random numbers, which the jitter comes from,
+    /// so a run can be repeated. Taken from the clock when not given.
+    pub jitter_seed: Option<u64>,
+    /// How many more times to send the request once packets stop arriving
+    /// partway through, 2 by default. Stalls often mean the server is done,
+    /// so this is lower than the initial retries.
+    pub stall_retries: usize,
+    /// How long without any packets counts as a stall, 5 seconds by default
+    pub stall_timeout: Duration,
+    /// When to give up on the whole download
+    pub deadline: Duration
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            bind: String::from("0.0.0.0:0"),
+            recv_timeout: Duration::from_secs(1),
+            initial_retries: 5,
+            initial_backoff: Duration::from_millis(500),
+            jitter: 0.0,
+            jitter_seed: None,
+            stall_retries: 2,
+            stall_timeout: Duration::from_secs(5),
+            deadline: Duration::from_secs(60)
+        }
+    }
+}
+
+/// When to ask the server again, and when to stop. `download_reliable` has
+/// one for getting a first answer and one for transfers that stall.
+pub trait RetryPolicy {
+    /// How long to wait for packets after try `attempt` before counting it
+    /// as lost, or `None` if there shouldn't be a try `attempt`. Try 0 is
+    /// the first request, which is always sent; `None` for it means waiting
+    /// on it for as long as the download is allowed. Anything random about
+    /// the wait comes from `rng`, the client's, so seeding it repeats a run.
+    fn next_delay(&mut self, attempt: usize, rng: &mut SmallRng) -> Option<Duration>;
+}
+
+/// The same wait every time, for `max_retries` more tries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedDelay {
+    pub delay: Duration,
+    pub max_retries: usize
+}
+
+impl RetryPolicy for FixedDelay {
+    fn next_delay(&mut self, attempt: usize, _rng: &mut SmallRng) -> Option<Duration> {
+        (attempt <= self.max_retries).then_some(self.delay)
+    }
+}
+
+/// Waits doubling from `initial`, for `max_retries` more tries, each moved
+/// at random by up to `jitter` of itself either way
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExponentialBackoff {
+    initial: Duration,
+    max_retries: usize,
+    jitter: f64
+}
+
+impl ExponentialBackoff {
+    pub fn new(initial: Duration, max_retries: usize) -> Self {
+        Self { initial, max_retries, jitter: 0.0 }
+    }
+
+    /// Move each wait by up to `jitter` of itself, 0.5 meaning anywhere from
+    /// half to one and a half times as long
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+}
+
+impl RetryPolicy for ExponentialBackoff {
+    fn next_delay(&mut self, attempt: usize, rng: &mut SmallRng) -> Option<Duration> {
+        if attempt > self.max_retries {
+            return None;
+        }
+
+        let spread = rng.next_f64() * 2.0 - 1.0;
+        let base = self.initial.saturating_mul(2u32.saturating_pow(attempt as u32));
+        Some(Duration::try_from_secs_f64(base.as_secs_f64() * (1.0 + spread * self.jitter)).unwrap_or(Duration::MAX))
+    }
+}
+
+/// Send the request once and wait on it as long as the download is allowed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NoRetry;
+
+impl RetryPolicy for NoRetry {
+    fn next_delay(&mut self, _attempt: usize, _rng: &mut SmallRng) -> Option<Duration> {
+        None
+    }
+}
+
+/// Download `expected` files from `server`, asking again whenever the
+/// transfer stalls. The protocol has no way to ask for single segments, so a
+/// repeated request makes the server start over: gaps in unfinished files
+/// are filled from the new copy and files already finished are dropped.
+///
+/// Hands back the client holding the finished files, ready to be finalized,
+/// or `SegFsError::Incomplete` listing what's missing once the retries or
+/// the deadline run out. A server that never answers at all is reported
+/// as unreachable.
+pub fn download_reliable(server: &str, port: u16, expected: usize, options: &DownloadOptions) -> Result<Client, SegFsError> {
+    let initial = ExponentialBackoff::new(options.initial_backoff, options.initial_retries).with_jitter(options.jitter);
+    let stall = FixedDelay { delay: options.stall_timeout, max_retries: options.stall_retries };
+    download_reliable_with(server, port, expected, options, Box::new(initial), Box::new(stall))
+}
+
+/// `download_reliable` with its own policies for asking again, `initial`
+/// while the server hasn't answered and `stall` once packets stop arriving
+/// partway. The retry, backoff and stall settings in `options` are left out
+/// in favour of them.
+pub fn download_reliable_with(server: &str, port: u16, expected: usize, options: &DownloadOptions, mut initial: Box<dyn RetryPolicy>, mut stall: Box<dyn RetryPolicy>) -> Result<Client, SegFsError> {
+    let remote = format!("{}:{}", server, port);
+    let socket = UdpSocket::bind(&options.bind).map_err(|e| format!("unable to bind udp socket to {}: {}", options.bind, e))?;
+    socket.connect(&remote).map_err(|e| format!("unable to connect to remote host {} : {}", remote, e))?;
+
+    let server_addr = socket.peer_addr().ok();
+
+    let mut client = Client::new(socket);
+    if let Some(seed) = options.jitter_seed {
+        client = client.with_rng_seed(seed);
+    }
+    client.set_drop_repeated_files(true);
+    client.set_read_timeout(Some(options.recv_timeout))?;
+
+    let started = Instant::now();
+    client.send_request()?;
+    let mut last_packet = Instant::now();
+    let mut answered = false;
+    let mut initial_retries = 0;
+    let mut wait = initial.next_delay(0, client.rng());
+    let mut stall_retries = 0;
+    let mut stall_wait = stall.next_delay(0, client.rng());
+
+    while !client.is_batch_complete(expected) {
+        if started.elapsed() >= options.deadline {
+            return Err(incomplete(&client, expected));
+        }
+
+        match client.recv_packet() {
+            Ok(()) => {
+                answered = true;
+                last_packet = Instant::now();
+            }
+            Err(SegFsError::Timeout) => {}
+            Err(e @ (SegFsError::ServerUnreachable(_) | SegFsError::TransferTooLarge { .. })) => return Err(e),
+            // A bad packet is just more loss, there's no need to stop
+            Err(_) => {
+                answered = true;
+                last_packet = Instant::now();
+            }
+        }
+
+        if !answered && wait.is_some_and(|wait| last_packet.elapsed() >= wait) {
+            wait = initial.next_delay(initial_retries + 1, client.rng());
+            if wait.is_none() {
+                return Err(SegFsError::ServerUnreachable(server_addr));
+            }
+            #[cfg(feature = "tracing")]
+            tracing::info!(parent: client.span(), retry = initial_retries + 1, "no answer, sending request again");
+            client.send_request()?;
+            initial_retries += 1;
+            last_packet = Instant::now();
+        }
+        else if answered && stall_wait.is_some_and(|wait| last_packet.elapsed() >= wait) {
+            stall_wait = stall.next_delay(stall_retries + 1, client.rng());
+            if stall_wait.is_none() {
+                return Err(incomplete(&client, expected));
+            }
+            #[cfg(feature = "tracing")]
+            tracing::info!(parent: client.span(), retry = stall_retries + 1, "transfer stalled, sending request again");
+            client.send_request()?;
+            stall_retries += 1;
+            last_packet = Instant::now();
+        }
+    }
+
+    Ok(client)
+}
+
+fn incomplete(client: &Client, expected: usize) -> SegFsError {
+    SegFsError::Incomplete {
+        completed: client.file_count(),
+        expected,
+        files: client.incomplete_files()
+    }
+}
+
+/// A server which ignores the first `ignored` requests, then sends two files
+/// for each request it gets, leaving out the packets `drop` picks given the
+/// request number and the packet. Hands back its port and a count of the
+/// requests it's had.
+#[cfg(test)]
+fn lossy_server(ignored: usize, drop: impl Fn(usize, &[u8]) -> bool + Send + 'static) -> (u16, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+    use super::packets::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    let port = socket.local_addr().unwrap().port();
+
+    let packets = [
+        HeaderPacket { file_id: 1, name: String::from("a.txt"), expected_size: None }.to_bytes(),
+        DataPacket { is_last: false, file_id: 1, packet_number: 0, data: b"ab".to_vec() }.to_bytes(),
+        DataPacket { is_last: false, file_id: 1, packet_number: 1, data: b"cd".to_vec() }.to_bytes(),
+        DataPacket { is_last: true, file_id: 1, packet_number: 2, data: b"ef".to_vec() }.to_bytes(),
+        HeaderPacket { file_id: 2, name: String::from("b.txt"), expected_size: None }.to_bytes(),
+        DataPacket { is_last: true, file_id: 2, packet_number: 0, data: b"gh".to_vec() }.to_bytes()
+    ];
+
+    let requests = std::sync::Arc::new(AtomicUsize::new(0));
+    let counter = requests.clone();
+    std::thread::spawn(move || {
+        let mut buf = [0; 16];
+        while let Ok((_, client)) = socket.recv_from(&mut buf) {
+            let count = counter.fetch_add(1, Ordering::SeqCst);
+            let Some(request) = count.checked_sub(ignored) else {
+                continue;
+            };
+            for packet in packets.iter().filter(|packet| !drop(request, packet)) {
+                socket.send_to(packet, client).unwrap();
+            }
+        }
+    });
+
+    (port, requests)
+}
+
+#[cfg(test)]
+fn test_options() -> DownloadOptions {
+    DownloadOptions {
+        bind: String::from("127.0.0.1:0"),
+        recv_timeout: Duration::from_millis(20),
+        initial_retries: 2,
+        initial_backoff: Duration::from_millis(50),
+        jitter: 0.0,
+        jitter_seed: None,
+        stall_retries: 2,
+        stall_timeout: Duration::from_millis(200),
+        deadline: Duration::from_secs(10)
+    }
+}
+
+#[test]
+fn reliable_download_recovers() {
+    // The middle of a.txt is lost the first time round
+    let (port, _) = lossy_server(0, |request, packet| request == 0 && packet[..4] == [1, 1, 0, 1]);
+
+    let mut client = download_reliable("127.0.0.1", port, 2, &test_options()).unwrap();
+    assert_eq!(client.file_count(), 2);
+
+    let dir = std::env::temp_dir().join(format!("seg-fs-reliable_download_recovers-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    client.set_output_dir(&dir);
+    client.finalize_files_ref().unwrap();
+
+    assert_eq!(std::fs::read(dir.join("a.txt")).unwrap(), b"abcdef");
+    assert_eq!(std::fs::read(dir.join("b.txt")).unwrap(), b"gh");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn reliable_download_gives_up() {
+    let (port, requests) = lossy_server(0, |_, packet| packet[..4] == [1, 1, 0, 1]);
+
+    match download_reliable("127.0.0.1", port, 2, &test_options()) {
+        Err(SegFsError::Incomplete { completed, expected, files }) => {
+            assert_eq!((completed, expected), (1, 2));
+            assert_eq!(files, vec![IncompleteFile {
+                file_id: 1,
+                name: Some(String::from("a.txt")),
+                received_segments: 2,
+                missing_segments: Some(vec![1])
+            }]);
+        }
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("download can't finish without a.txt")
+    }
+
+    // The first request and two more after stalls
+    assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 3);
+}
+
+#[test]
+fn initial_retries() {
+    // Two retries are enough for a server that misses two requests
+    let (port, requests) = lossy_server(2, |_, _| false);
+    let mut options = test_options();
+    options.stall_retries = 0;
+    assert!(download_reliable("127.0.0.1", port, 2, &options).is_ok());
+    assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 3);
+
+    // But not three, however many stall retries there are
+    let (port, requests) = lossy_server(3, |_, _| false);
+    options.stall_retries = 10;
+    assert!(matches!(download_reliable("127.0.0.1", port, 2, &options), Err(SegFsError::ServerUnreachable(_))));
+    assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 3);
+}
+
+#[test]
+fn jittered_backoff() {
+    let mut rng = SmallRng::seed_from_u64(42);
+    let mut backoff = ExponentialBackoff::new(Duration::from_millis(50), 10);
+    let plain: Vec<_> = (0..4).map(|attempt| backoff.next_delay(attempt, &mut rng).unwrap().as_millis()).collect();
+    assert_eq!(plain, vec![50, 100, 200, 400]);
+
+    let mut backoff = ExponentialBackoff::new(Duration::from_millis(50), 10).with_jitter(0.5);
+    let jittered: Vec<_> = (0..8).map(|attempt| backoff.next_delay(attempt, &mut rng).unwrap()).collect();
+    for (i, wait) in jittered.iter().enumerate() {
+        let base = Duration::from_millis(50) * 2u32.pow(i as u32);
+        assert!(*wait >= base / 2 && *wait <= base * 3 / 2, "wait {} of {:?} is out of bounds", i, wait);
+    }
+    assert!(jittered.iter().enumerate().any(|(i, wait)| *wait != Duration::from_millis(50) * 2u32.pow(i as u32)));
+
+    // Clients seeded the same give the same waits
+    let waits = |seed| {
+        let mut client = Client::new(UdpSocket::bind("127.0.0.1:0").unwrap()).with_rng_seed(seed);
+        let mut backoff = ExponentialBackoff::new(Duration::from_millis(50), 10).with_jitter(0.5);
+        (0..8).map(|attempt| backoff.next_delay(attempt, client.rng())).collect::<Vec<_>>()
+    };
+    assert_eq!(waits(7), waits(7));
+    assert_ne!(waits(7), waits(8));
+}
+
+#[test]
+fn retry_policies() {
+    let mut rng = SmallRng::seed_from_u64(1);
+    let mut sequence = |policy: &mut dyn RetryPolicy| (0..5).map(|attempt| policy.next_delay(attempt, &mut rng).map(|delay| delay.as_millis())).collect::<Vec<_>>();
+
+    assert_eq!(sequence(&mut FixedDelay { delay: Duration::from_millis(30), max_retries: 2 }), vec![Some(30), Some(30), Some(30), None, None]);
+    assert_eq!(sequence(&mut ExponentialBackoff::new(Duration::from_millis(10), 3)), vec![Some(10), Some(20), Some(40), Some(80), None]);
+    assert_eq!(sequence(&mut NoRetry), vec![None; 5]);
+
+    // Huge waits saturate rather than overflowing
+    let mut huge = ExponentialBackoff::new(Duration::from_secs(u64::MAX / 2), usize::MAX).with_jitter(1.0);
+    assert!((190..200).all(|attempt| huge.next_delay(attempt, &mut rng).is_some()));
+
+    // Without retries the first request is waited on until it's answered
+    let (port, requests) = lossy_server(0, |_, _| false);
+    assert!(download_reliable_with("127.0.0.1", port, 2, &test_options(), Box::new(NoRetry), Box::new(NoRetry)).is_ok());
+    assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    // A custom policy gives up straight after the first wait
+    struct GiveUp;
+    impl RetryPolicy for GiveUp {
+        fn next_delay(&mut self, attempt: usize, _rng: &mut SmallRng) -> Option<Duration> {
+            (attempt == 0).then_some(Duration::from_millis(50))
+        }
+    }
+    let (port, requests) = lossy_server(1, |_, _| false);
+    assert!(matches!(download_reliable_with("127.0.0.1", port, 2, &test_options(), Box::new(GiveUp), Box::new(NoRetry)), Err(SegFsError::ServerUnreachable(_))));
+    assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 1);
+}
+
+#[test]
+fn reliable_download_through_relay() {
+    use super::lossy::*;
+
+    // The middle of a.txt, exactly once
+    let (port, _) = lossy_server(0, |_, _| false);
+    let relay = LossyRelay::start(([127, 0, 0, 1], port).into(), Loss::Indices([2].into()));
+    let client = download_reliable("127.0.0.1", relay.addr().port(), 2, &test_options()).unwrap();
+    assert_eq!(client.completed_file_bytes("a.txt"), Some(b"abcdef".to_vec()));
+    assert_eq!(relay.dropped(), 1);
+
+    // A steady trickle of loss takes a few more requests
+    let (port, requests) = lossy_server(0, |_, _| false);
+    let relay = LossyRelay::start(([127, 0, 0, 1], port).into(), Loss::Fraction { fraction: 0.3, seed: 7 });
+    let mut options = test_options();
+    options.stall_retries = 10;
+    let client = download_reliable("127.0.0.1", relay.addr().port(), 2, &options).unwrap();
+    assert_eq!(client.completed_file_bytes("a.txt"), Some(b"abcdef".to_vec()));
+    assert_eq!(client.completed_file_bytes("b.txt"), Some(b"gh".to_vec()));
+    assert!(relay.dropped() > 0);
+    assert!(requests.load(std::sync::atomic::Ordering::SeqCst) > 1);
+}
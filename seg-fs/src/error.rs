@@ -0,0 +1,80 @@
+use super::packets::{FileId, PacketNumber};
+
+/// A file that hadn't finished when a download gave up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IncompleteFile {
+    pub file_id: FileId,
+    pub name: Option<String>,
+    pub received_segments: usize,
+    /// The segments still needed, `None` if the last packet never arrived
+    /// so the total isn't known
+    pub missing_segments: Option<Vec<PacketNumber>>
+}
+
+impl std::fmt::Display for IncompleteFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "file id {}", self.file_id)?;
+        if let Some(name) = &self.name {
+            write!(f, " ({})", name)?;
+        }
+        match &self.missing_segments {
+            Some(missing) => {
+                let missing: Vec<String> = missing.iter().map(|number| number.to_string()).collect();
+                write!(f, " is missing segments {}", missing.join(", "))
+            }
+            None => write!(f, " has {} segments but its last never arrived", self.received_segments)
+        }
+    }
+}
+
+/// Errors reported by the client
+#[derive(Debug)]
+pub enum SegFsError {
+    /// A failure described only by its message
+    Message(String),
+    /// Nothing is listening at the server's address, found out when the
+    /// socket reports the connection was refused
+    ServerUnreachable(Option<std::net::SocketAddr>),
+    /// The server sent a file name that wasn't in the expected set
+    UnexpectedFile(String),
+    /// Nothing arrived before the socket's read timeout
+    Timeout,
+    /// The client is paused, so the socket wasn't read
+    Paused,
+    /// More data arrived than the client's total limit allows
+    TransferTooLarge { limit: u64, received: u64 },
+    /// Writing a file took longer than the client's write timeout
+    WriteTimeout { path: std::path::PathBuf, timeout: std::time::Duration },
+    /// A download gave up before every file arrived
+    Incomplete { completed: usize, expected: usize, files: Vec<IncompleteFile> }
+}
+
+impl std::fmt::Display for SegFsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SegFsError::Message(message) => write!(f, "{}", message),
+            SegFsError::ServerUnreachable(Some(remote)) => write!(f, "server {} is unreachable, is it running?", remote),
+            SegFsError::ServerUnreachable(None) => write!(f, "server is unreachable, is it running?"),
+            SegFsError::UnexpectedFile(name) => write!(f, "server sent unexpected file {}", name),
+            SegFsError::Timeout => write!(f, "timed out waiting for data"),
+            SegFsError::Paused => write!(f, "receiving is paused"),
+            SegFsError::TransferTooLarge { limit, received } => write!(f, "received {} bytes, over the limit of {}", received, limit),
+            SegFsError::WriteTimeout { path, timeout } => write!(f, "gave up writing {} after {:.2}s", path.display(), timeout.as_secs_f64()),
+            SegFsError::Incomplete { completed, expected, files } => {
+                write!(f, "only {} of {} files downloaded", completed, expected)?;
+                for file in files {
+                    write!(f, "; {}", file)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for SegFsError {}
+
+impl From<String> for SegFsError {
+    fn from(message: String) -> Self {
+        SegFsError::Message(message)
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Where finished files are written. Writing them out doesn't care
+/// whether it's the real disk; `StdFileSystem` is the usual one.
+pub trait FileSystem: Send + Sync {
+    /// Create `path`, or empty it if it's already there, for writing
+    fn create(&self, path: &Path) -> std::io::Result<Box<dyn OutputFile>>;
+
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()>;
+
+    fn remove(&self, path: &Path) -> std::io::Result<()>;
+
+    fn create_dir_all(&self, path: &Path) -> std::io::Result<()>;
+
+    fn metadata(&self, path: &Path) -> std::io::Result<Metadata>;
+
+    /// The paths of everything directly inside `dir`
+    fn list(&self, dir: &Path) -> std::io::Result<Vec<PathBuf>>;
+
+    /// Write all of `data` to a new `path`, syncing it to disk afterwards
+    /// when it has to be `durable`
+    fn write(&self, path: &Path, data: &[u8], durable: bool) -> std::io::Result<()> {
+        let mut file = self.create(path)?;
+        file.write_all(data)?;
+        if durable {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// What a `FileSystem` knows about a path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub is_file: bool,
+    pub len: u64,
+    /// When it was last written, where that's known
+    pub modified: Option<SystemTime>
+}
+
+/// A file open for writing on a `FileSystem`
+pub trait OutputFile: Write + Send {
+    /// Make sure everything written so far would survive a crash
+    fn sync_all(&mut self) -> std::io::Result<()>;
+}
+
+impl OutputFile for std::fs::File {
+    fn sync_all(&mut self) -> std::io::Result<()> {
+        std::fs::File::sync_all(self)
+    }
+}
+
+/// The real disk
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdFileSystem;
+
+impl FileSystem for StdFileSystem {
+    fn create(&self, path: &Path) -> std::io::Result<Box<dyn OutputFile>> {
+        Ok(Box::new(std::fs::File::create(path)?))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    fn metadata(&self, path: &Path) -> std::io::Result<Metadata> {
+        let metadata = std::fs::metadata(path)?;
+        Ok(Metadata { is_file: metadata.is_file(), len: metadata.len(), modified: metadata.modified().ok() })
+    }
+
+    fn list(&self, dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+        std::fs::read_dir(dir)?.map(|entry| entry.map(|entry| entry.path())).collect()
+    }
+}
+
+/// Files kept in memory, for seeing what would be written without touching
+/// the disk. Clones share the same files, so one can be handed to a client
+/// and another kept to look at afterwards. Directories aren't tracked.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFileSystem {
+    files: Arc<Mutex<HashMap<PathBuf, MemoryEntry>>>
+}
+
+#[derive(Debug)]
+struct MemoryEntry {
+    data: Vec<u8>,
+    modified: SystemTime
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The contents of `path`, if it's there
+    pub fn read(&self, path: &Path) -> Option<Vec<u8>> {
+        self.lock().get(path).map(|entry| entry.data.clone())
+    }
+
+    /// Put a file at `path` as if it was last written at `modified`
+    pub fn insert(&self, path: &Path, data: &[u8], modified: SystemTime) {
+        self.lock().insert(path.to_path_buf(), MemoryEntry { data: data.to_vec(), modified });
+    }
+
+    /// Every file's path, sorted
+    pub fn paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<_> = self.lock().keys().cloned().collect();
+        paths.sort();
+        paths
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, MemoryEntry>> {
+        self.files.lock().expect("nothing panics holding the lock")
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn create(&self, path: &Path) -> std::io::Result<Box<dyn OutputFile>> {
+        self.insert(path, &[], SystemTime::now());
+        Ok(Box::new(MemoryFile { files: self.clone(), path: path.to_path_buf() }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        let mut files = self.lock();
+        let data = files.remove(from).ok_or(std::io::ErrorKind::NotFound)?;
+        files.insert(to.to_path_buf(), data);
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> std::io::Result<()> {
+        self.lock().remove(path).map(|_| ()).ok_or(std::io::ErrorKind::NotFound.into())
+    }
+
+    fn create_dir_all(&self, _path: &Path) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn metadata(&self, path: &Path) -> std::io::Result<Metadata> {
+        let files = self.lock();
+        let entry = files.get(path).ok_or(std::io::ErrorKind::NotFound)?;
+        Ok(Metadata { is_file: true, len: entry.data.len() as u64, modified: Some(entry.modified) })
+    }
+
+    fn list(&self, dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+        Ok(self.lock().keys().filter(|path| path.parent() == Some(dir)).cloned().collect())
+    }
+}
+
+/// A file being written on a `MemoryFileSystem`
+struct MemoryFile {
+    files: MemoryFileSystem,
+    path: PathBuf
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // Gone if it was removed or renamed while still open
+        let mut files = self.files.lock();
+        let entry = files.get_mut(&self.path).ok_or(std::io::ErrorKind::NotFound)?;
+        entry.data.extend_from_slice(buf);
+        entry.modified = SystemTime::now();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl OutputFile for MemoryFile {
+    fn sync_all(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
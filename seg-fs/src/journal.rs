@@ -0,0 +1,166 @@
+use super::error::*;
+use super::packets::FileId;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+// A journal is a sequence of lines, `begin <id> <path>` before a file is
+// written and `done <id> <path>` once it's renamed into place. Backslashes
+// and newlines in the path are escaped so each entry stays on one line.
+
+/// A record of the files being written out, so a run that crashed part way
+/// through a batch can tell which writes it left unfinished
+#[derive(Debug, Clone)]
+pub struct Journal {
+    path: PathBuf
+}
+
+/// A write that was started and never finished
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InFlight {
+    pub file_id: FileId,
+    /// Where the file was going, its `.part` file sits next to it
+    pub path: PathBuf
+}
+
+impl InFlight {
+    /// The partial file the write goes through before it's renamed
+    pub fn part_path(&self) -> PathBuf {
+        part_path(&self.path)
+    }
+}
+
+impl Journal {
+    /// A journal kept at `path`, which is created on the first write
+    pub fn new(path: &Path) -> Self {
+        Self { path: path.to_path_buf() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Note that file `file_id` is about to be written to `path`
+    pub fn begin(&self, file_id: FileId, path: &Path) -> Result<(), SegFsError> {
+        self.append("begin", file_id, path)
+    }
+
+    /// Note that file `file_id` is in place at `path`
+    pub fn done(&self, file_id: FileId, path: &Path) -> Result<(), SegFsError> {
+        self.append("done", file_id, path)
+    }
+
+    /// Each write begun and never marked done, in the order they began. A
+    /// journal that doesn't exist yet has none.
+    pub fn in_flight(&self) -> Result<Vec<InFlight>, SegFsError> {
+        let mut text = String::new();
+        match std::fs::File::open(&self.path) {
+            Ok(mut file) => file.read_to_string(&mut text).map_err(|e| format!("unable to read journal {}: {}", self.path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(format!("unable to read journal {}: {}", self.path.display(), e).into())
+        };
+
+        let mut in_flight: Vec<InFlight> = Vec::new();
+        for line in text.lines() {
+            // A crash part way through an append can leave the last line cut short
+            let mut parts = line.splitn(3, ' ');
+            let (Some(action), Some(Ok(file_id)), Some(path)) = (parts.next(), parts.next().map(str::parse), parts.next()) else {
+                continue;
+            };
+
+            let entry = InFlight { file_id, path: PathBuf::from(unescape(path)) };
+            match action {
+                "begin" => in_flight.push(entry),
+                "done" => in_flight.retain(|begun| *begun != entry),
+                _ => {}
+            }
+        }
+        Ok(in_flight)
+    }
+
+    /// Clean up after a crashed run, removing the `.part` file of each write
+    /// it left unfinished and starting the journal afresh. Hands back those
+    /// writes, so their files can be downloaded again.
+    pub fn recover(&self) -> Result<Vec<InFlight>, SegFsError> {
+        let in_flight = self.in_flight()?;
+        for entry in in_flight.iter() {
+            match std::fs::remove_file(entry.part_path()) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(format!("unable to remove {}: {}", entry.part_path().display(), e).into());
+                }
+                _ => {}
+            }
+        }
+
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(format!("unable to clear journal {}: {}", self.path.display(), e).into()),
+            _ => Ok(in_flight)
+        }
+    }
+
+    /// Add a line and make sure it's on disk before going on, since the
+    /// journal is no use if it's behind the files it describes
+    fn append(&self, action: &str, file_id: FileId, path: &Path) -> Result<(), SegFsError> {
+        let line = format!("{} {} {}\n", action, file_id, escape(&path.to_string_lossy()));
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&self.path)
+            .map_err(|e| format!("unable to open journal {}: {}", self.path.display(), e))?;
+        file.write_all(line.as_bytes())
+            .and_then(|_| file.sync_data())
+            .map_err(|e| format!("unable to write to journal {}: {}", self.path.display(), e))?;
+        Ok(())
+    }
+}
+
+/// `path` with `.part` added, where a file is written before it's renamed
+/// into place
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\')
+        }
+    }
+    unescaped
+}
+
+#[test]
+fn journal_entries() {
+    let dir = std::env::temp_dir().join(format!("seg-fs-journal_entries-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let journal = Journal::new(&dir.join("journal"));
+    assert!(journal.in_flight().unwrap().is_empty());
+
+    let odd = dir.join("new\nline \\ name");
+    journal.begin(1, &dir.join("a.txt")).unwrap();
+    journal.begin(2, &odd).unwrap();
+    journal.done(1, &dir.join("a.txt")).unwrap();
+    assert_eq!(journal.in_flight().unwrap(), vec![InFlight { file_id: 2, path: odd.clone() }]);
+
+    // A torn last line is ignored
+    std::fs::OpenOptions::new().append(true).open(journal.path()).unwrap().write_all(b"begin 3").unwrap();
+    assert_eq!(journal.in_flight().unwrap().len(), 1);
+
+    std::fs::write(part_path(&odd), b"partial").unwrap();
+    assert_eq!(journal.recover().unwrap(), vec![InFlight { file_id: 2, path: odd.clone() }]);
+    assert!(!part_path(&odd).exists());
+    assert!(journal.in_flight().unwrap().is_empty());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
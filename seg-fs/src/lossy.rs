@@ -0,0 +1,85 @@
+use super::rng::SmallRng;
+use std::collections::HashSet;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Which of the server's datagrams a `LossyRelay` drops, counting from 0
+pub enum Loss {
+    /// Exactly these ones
+    Indices(HashSet<usize>),
+    /// Roughly this fraction of them, picked the same way every time for
+    /// the same seed
+    Fraction { fraction: f64, seed: u64 }
+}
+
+impl Loss {
+    /// A function saying whether each datagram in turn is dropped
+    fn picker(self) -> Box<dyn FnMut(usize) -> bool + Send> {
+        match self {
+            Loss::Indices(indices) => Box::new(move |index| indices.contains(&index)),
+            Loss::Fraction { fraction, seed } => {
+                let mut rng = SmallRng::seed_from_u64(seed);
+                Box::new(move |_| rng.next_f64() < fraction)
+            }
+        }
+    }
+}
+
+/// Sits between a client and a server on the loopback interface, passing
+/// requests through and dropping datagrams on their way back, so tests can
+/// see the client cope with loss from a real server
+pub struct LossyRelay {
+    addr: SocketAddr,
+    dropped: Arc<AtomicUsize>
+}
+
+impl LossyRelay {
+    /// Relay to `server`. The relay stops once it's been idle a couple of
+    /// seconds.
+    pub fn start(server: SocketAddr, loss: Loss) -> Self {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let counter = dropped.clone();
+        let mut drop = loss.picker();
+
+        std::thread::spawn(move || {
+            let mut buf = [0; 2048];
+            let mut client = None;
+            let mut index = 0;
+
+            while let Ok((size, from)) = socket.recv_from(&mut buf) {
+                if from != server {
+                    client = Some(from);
+                    let _ = socket.send_to(&buf[..size], server);
+                    continue;
+                }
+
+                let dropping = drop(index);
+                index += 1;
+                if dropping {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                }
+                else if let Some(client) = client {
+                    let _ = socket.send_to(&buf[..size], client);
+                }
+            }
+        });
+
+        Self { addr, dropped }
+    }
+
+    /// The address clients should send to in place of the server's
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// How many of the server's datagrams have been dropped so far
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::SeqCst)
+    }
+}
@@ -1,7 +1,129 @@
 #![allow(dead_code)]
 
+#[cfg(feature = "tokio")]
+mod async_client;
 mod client;
+mod clock;
+#[cfg(feature = "config")]
+mod config;
+mod download;
+mod error;
+mod filesystem;
+mod journal;
+#[cfg(test)]
+mod lossy;
+mod multi_server;
 mod packets;
+#[cfg(feature = "pcap")]
+mod pcap;
+mod progress;
+mod rng;
+mod trace;
+mod transport;
+
+/// Settings taken from the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Options {
+    address: String,
+    port: u16,
+    bind: String,
+    /// Files to wait for when the server doesn't announce how many it has
+    files: usize,
+    /// Print a JSON report at the end instead of showing progress
+    json: bool,
+    /// Report progress as plain lines rather than redrawing, which is also
+    /// what happens when stdout isn't a terminal
+    plain: bool,
+    /// The `segfs.toml` settings were taken from, flags override anything
+    /// in it. It's only read the once, here.
+    #[cfg(feature = "config")]
+    config: Option<config::Config>,
+    /// Servers it's fine to talk to, any server if `None`. Only set from the
+    /// config file.
+    allowed_servers: Option<Vec<String>>,
+    /// A Unix datagram socket the server is listening on, used in place of
+    /// the address and port
+    unix: Option<std::path::PathBuf>
+}
+
+impl Options {
+    /// Parses `[--config FILE] [--bind ADDR] [--unix PATH] [--files N] [--json] [--plain] [server] [port]`,
+    /// with flags allowed anywhere
+    fn parse(args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let args: Vec<String> = args.collect();
+        let config = match args.iter().position(|arg| arg == "--config") {
+            Some(index) => Some(load_config(std::path::Path::new(args.get(index + 1).ok_or_else(|| "--config needs a file".to_string())?))?),
+            None => None
+        };
+        let defaults = config.clone().unwrap_or_default();
+
+        let mut positional = Vec::new();
+        let mut bind = defaults.bind.unwrap_or(String::from("0.0.0.0:0"));
+        let mut json = defaults.json.unwrap_or(false);
+        let mut plain = defaults.plain.unwrap_or(false);
+        let mut files = defaults.files.unwrap_or(3);
+        let mut unix = None;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => {
+                    args.next();
+                }
+                "--bind" => bind = args.next().ok_or_else(|| "--bind needs an address".to_string())?,
+                "--files" => {
+                    let count = args.next().ok_or_else(|| "--files needs a count".to_string())?;
+                    files = count.parse().map_err(|e| format!("invalid file count {}: {}", count, e))?;
+                }
+                #[cfg(unix)]
+                "--unix" => unix = Some(std::path::PathBuf::from(args.next().ok_or_else(|| "--unix needs a socket path".to_string())?)),
+                "--json" => json = true,
+                "--plain" => plain = true,
+                _ => positional.push(arg)
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        let address = positional.next().or(defaults.address).unwrap_or(String::from("normandy"));
+        let port = positional.next().map(|v| v.parse::<u16>().unwrap_or(6014)).or(defaults.port).unwrap_or(6014);
+
+        Ok(Options {
+            address,
+            port,
+            bind,
+            files,
+            json,
+            plain,
+            #[cfg(feature = "config")]
+            config,
+            allowed_servers: defaults.allowed_servers,
+            unix
+        })
+    }
+}
+
+#[cfg(feature = "config")]
+fn load_config(path: &std::path::Path) -> Result<config::Config, String> {
+    config::Config::load(path).map_err(|e| e.to_string())
+}
+
+/// The settings the command line falls back on without a config file
+#[cfg(not(feature = "config"))]
+#[derive(Clone, Default)]
+struct Defaults {
+    address: Option<String>,
+    port: Option<u16>,
+    allowed_servers: Option<Vec<String>>,
+    bind: Option<String>,
+    files: Option<usize>,
+    json: Option<bool>,
+    plain: Option<bool>
+}
+
+#[cfg(not(feature = "config"))]
+fn load_config(_path: &std::path::Path) -> Result<Defaults, String> {
+    Err("--config needs seg-fs built with the config feature".to_string())
+}
 
 fn main() {
     let mut args = std::env::args();
@@ -9,43 +131,219 @@ fn main() {
     // Step past the executable name
     args.next();
 
-    let address = args.next().map(|v| v.to_string()).unwrap_or(String::from("normandy"));
-    let port = args.next().map(|v| v.parse::<u16>().unwrap_or(6014)).unwrap_or(6014);
+    let result = Options::parse(args)
+        .map_err(error::SegFsError::from)
+        .and_then(|options| match &options.unix {
+            #[cfg(unix)]
+            Some(server) => unix_runner(server, &options),
+            _ => runner(&options)
+        });
 
-    if let Err(e) = runner(&address, port) {
+    if let Err(e) = result {
         eprintln!("error: {}", e);
     }
 }
 
-fn runner(server_name: &str, port: u16) -> Result<(), String> {
-    let remote = format!("{}:{}", server_name, port);
+/// Bind the local end of the socket to `bind` and connect it to `remote`
+fn connect_socket(bind: &str, remote: &str) -> Result<std::net::UdpSocket, error::SegFsError> {
+    let bind_addr = bind.parse::<std::net::SocketAddr>().map_err(|e| format!("invalid bind address {}: {}", bind, e))?;
 
-    let socket = std::net::UdpSocket::bind("0.0.0.0:0").map_err(|e| format!("unable to bind udp socket {}", e))?;
-    socket.connect(&remote).map_err(|e| format!("unable to connect to remote host {} : {}", &remote, e))?;
+    let socket = std::net::UdpSocket::bind(bind_addr).map_err(|e| format!("unable to bind udp socket to {}: {}", bind, e))?;
+    socket.connect(remote).map_err(|e| format!("unable to connect to remote host {} : {}", remote, e))?;
+    Ok(socket)
+}
+
+fn runner(options: &Options) -> Result<(), error::SegFsError> {
+    let remote = format!("{}:{}", options.address, options.port);
+    let socket = connect_socket(&options.bind, &remote)?;
+    if let Some(allowed) = &options.allowed_servers {
+        let peer = socket.peer_addr().map_err(|e| format!("unable to get remote address {}", e))?;
+        check_allowed_server(peer, allowed)?;
+    }
+    run_client(client::Client::new(socket), options)
+}
+
+/// Make sure the address a server's name resolved to belongs to one of the
+/// `allowed` servers, which may be addresses, with or without a port, or
+/// host names that are looked up again to compare. A name that no longer
+/// resolves to where we're connected, say because DNS was tampered with,
+/// doesn't count.
+fn check_allowed_server(peer: std::net::SocketAddr, allowed: &[String]) -> Result<(), error::SegFsError> {
+    use std::net::ToSocketAddrs;
+
+    let matches = |entry: &String| {
+        if let Ok(ip) = entry.parse::<std::net::IpAddr>() {
+            return ip == peer.ip();
+        }
+        if let Ok(addr) = entry.parse::<std::net::SocketAddr>() {
+            return addr == peer;
+        }
+        (entry.as_str(), peer.port()).to_socket_addrs()
+            .map(|mut addrs| addrs.any(|addr| addr.ip() == peer.ip()))
+            .unwrap_or(false)
+    };
 
-    let mut client = client::Client::new(socket);
+    if allowed.iter().any(matches) {
+        Ok(())
+    }
+    else {
+        Err(format!("refusing to talk to {}, it isn't one of the allowed servers", peer).into())
+    }
+}
+
+/// Like `runner`, but talking to a server listening on the Unix datagram
+/// socket at `server`. The address and port in `options` are ignored.
+#[cfg(unix)]
+fn unix_runner(server: &std::path::Path, options: &Options) -> Result<(), error::SegFsError> {
+    let socket = transport::TempUnixSocket::connect(server)?;
+    run_client(client::Client::with_transport(socket), options)
+}
+
+/// Receive the files with the settings from `options` and write them out
+fn run_client(mut client: client::Client, options: &Options) -> Result<(), error::SegFsError> {
+    #[cfg(feature = "config")]
+    let recv_timeout = match &options.config {
+        Some(config) => {
+            config.apply(&mut client)?;
+            config.read_timeout().unwrap_or(RECV_TIMEOUT)
+        }
+        None => RECV_TIMEOUT
+    };
+    #[cfg(not(feature = "config"))]
+    let recv_timeout = RECV_TIMEOUT;
 
-    display_progress_until_n_files(&mut client, 3)?;
+    client.handshake()?;
+    client.set_read_timeout(Some(recv_timeout))?;
+    let file_count = client.target_file_count(options.files);
+
+    let plain = progress::plain_output(options.plain, std::io::IsTerminal::is_terminal(&std::io::stdout()));
+    let received = receive_n_files(&mut client, file_count, !options.json, plain);
+    if options.json {
+        println!("{}", client.to_json());
+    }
+    received?;
 
     client.finalize_files()?;
     Ok(())
 }
 
-fn display_progress_until_n_files(client: &mut client::Client, file_count: usize) -> Result<(), String> {
-    client.send_request()?;
-    println!("{}", client);
-    let mut last_lines = client.print_line_length();
+/// How often to stop waiting on the socket and check whether the server is done
+const RECV_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// How long a file can go without a packet while others continue before it's
+/// given up on
+const MAX_FILE_IDLE: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Receive until `file_count` files are done, redrawing the progress
+/// display after each packet if `show_progress` is set, or printing a line
+/// as each file finishes if `plain` is too
+fn receive_n_files(client: &mut client::Client, file_count: usize, show_progress: bool, plain: bool) -> Result<(), error::SegFsError> {
+    let mut progress = progress::Progress::new(plain);
+    if show_progress {
+        progress.draw(client, file_count);
+    }
 
-    while client.file_count() < file_count {
-        client.recv_packet()?;
-        println!("\x1B[{}A", last_lines + 3);
-        for _ in 0..last_lines + 3 {
-            println!("                                                                ");
+    while !client.is_batch_complete(file_count) {
+        match client.recv_packet() {
+            Ok(()) => {}
+            Err(error::SegFsError::Timeout) => {
+                if let Some(total) = client.likely_total_files().filter(|&total| total < file_count) {
+                    return Err(format!("server seems to be done after sending {} of {} files", total, file_count).into());
+                }
+                continue;
+            }
+            Err(e) => return Err(e)
+        }
+        client.prune_stuck_files(MAX_FILE_IDLE);
+        if show_progress {
+            progress.draw(client, file_count);
         }
-        println!("\x1B[{}A", last_lines + 3);
-        println!("{}", client);
-        last_lines = client.print_line_length();
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+fn args(args: &[&str]) -> impl Iterator<Item = String> {
+    args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>().into_iter()
+}
+
+#[test]
+fn parse_bind_option() {
+    let options = Options::parse(args(&[])).unwrap();
+    assert_eq!(options, Options { address: String::from("normandy"), port: 6014, bind: String::from("0.0.0.0:0"), files: 3, json: false, plain: false, #[cfg(feature = "config")] config: None, allowed_servers: None, unix: None });
+
+    let options = Options::parse(args(&["localhost", "--bind", "127.0.0.1:4000", "7000"])).unwrap();
+    assert_eq!(options, Options { address: String::from("localhost"), port: 7000, bind: String::from("127.0.0.1:4000"), files: 3, json: false, plain: false, #[cfg(feature = "config")] config: None, allowed_servers: None, unix: None });
+
+    assert!(Options::parse(args(&["--json", "localhost"])).unwrap().json);
+    assert!(Options::parse(args(&["--plain"])).unwrap().plain);
+    assert_eq!(Options::parse(args(&["--files", "5"])).unwrap().files, 5);
+    assert!(Options::parse(args(&["--files", "many"])).is_err());
+
+    assert!(Options::parse(args(&["localhost", "--bind"])).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn parse_unix_option() {
+    let options = Options::parse(args(&["--unix", "/run/segfs.sock"])).unwrap();
+    assert_eq!(options.unix, Some(std::path::PathBuf::from("/run/segfs.sock")));
+    assert!(Options::parse(args(&["--unix"])).is_err());
+}
+
+#[cfg(feature = "config")]
+#[test]
+fn flags_override_config() {
+    let path = std::env::temp_dir().join(format!("seg-fs-flags_override_config-{}.toml", std::process::id()));
+    std::fs::write(&path, "address = \"localhost\"\nport = 7000\nfiles = 5\njson = true\n").unwrap();
+    let config = path.to_string_lossy().to_string();
+
+    let options = Options::parse(args(&["--config", &config])).unwrap();
+    assert_eq!(options, Options { address: String::from("localhost"), port: 7000, bind: String::from("0.0.0.0:0"), files: 5, json: true, plain: false, config: Some(config::Config::load(&path).unwrap()), allowed_servers: None, unix: None });
+
+    let options = Options::parse(args(&["--files", "2", "--config", &config, "remote", "8000"])).unwrap();
+    assert_eq!((options.address.as_str(), options.port, options.files), ("remote", 8000, 2));
+
+    assert!(Options::parse(args(&["--config"])).is_err());
+    std::fs::remove_file(&path).unwrap();
+    assert!(Options::parse(args(&["--config", &config])).is_err());
+
+    // What was read is kept, so the file going away afterwards changes nothing
+    assert_eq!(options.config.unwrap().files, Some(5));
+}
+
+#[test]
+fn allowed_servers() {
+    let peer: std::net::SocketAddr = "127.0.0.1:6014".parse().unwrap();
+    let allowed = |entries: &[&str]| check_allowed_server(peer, &entries.iter().map(|entry| entry.to_string()).collect::<Vec<_>>());
+
+    assert!(allowed(&["127.0.0.1"]).is_ok());
+    assert!(allowed(&["10.0.0.1", "127.0.0.1:6014"]).is_ok());
+    assert!(allowed(&["localhost"]).is_ok());
+
+    assert!(allowed(&[]).is_err());
+    assert!(allowed(&["10.0.0.1", "127.0.0.1:7000"]).is_err());
+    assert!(allowed(&["no-such-host.invalid"]).is_err());
+}
+
+#[test]
+fn bind_local_address() {
+    let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let remote = server.local_addr().unwrap().to_string();
+
+    assert!(connect_socket("not an address", &remote).is_err());
+
+    let socket = connect_socket("127.0.0.1:0", &remote).unwrap();
+    assert!(socket.local_addr().unwrap().ip().is_loopback());
+
+    socket.send(b"ping").unwrap();
+    let mut buf = [0; 4];
+    let (size, peer) = server.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..size], b"ping");
+    assert_eq!(peer, socket.local_addr().unwrap());
+
+    server.send_to(b"pong", peer).unwrap();
+    assert_eq!(socket.recv(&mut buf).unwrap(), 4);
+    assert_eq!(&buf, b"pong");
+}
@@ -0,0 +1,143 @@
+use super::client::{Client, DATA_HEADER_LEN, DEFAULT_SEGMENT_SIZE};
+use super::error::*;
+use std::collections::HashMap;
+use std::net::{SocketAddr, UdpSocket};
+
+/// Receives from many servers on one unconnected socket, keeping a separate
+/// `Client` per server so their file ids can't collide
+pub struct MultiServerReceiver {
+    udp_socket: UdpSocket,
+    clients: HashMap<SocketAddr, Client>,
+    requested_only: bool,
+    recv_buffer: Vec<u8>
+}
+
+impl MultiServerReceiver {
+    pub fn new(udp_socket: UdpSocket) -> Self {
+        Self {
+            udp_socket,
+            clients: HashMap::new(),
+            requested_only: false,
+            recv_buffer: Vec::new()
+        }
+    }
+
+    /// Only accept datagrams from servers a request was sent to, dropping
+    /// anything else that turns up on the socket
+    pub fn set_requested_only(&mut self, requested_only: bool) {
+        self.requested_only = requested_only;
+    }
+
+    fn client_for(&mut self, server: SocketAddr) -> Result<&mut Client, SegFsError> {
+        if !self.clients.contains_key(&server) {
+            let socket = self.udp_socket.try_clone().map_err(|e| format!("unable to share udp socket {}", e))?;
+            self.clients.insert(server, Client::new(socket));
+        }
+
+        Ok(self.clients.get_mut(&server).expect("client was just inserted"))
+    }
+
+    pub fn send_request(&mut self, server: SocketAddr) -> Result<(), SegFsError> {
+        self.client_for(server)?;
+        self.udp_socket.send_to(&[0], server).map_err(|e| format!("unable to send request to {}: {}", server, e))?;
+        Ok(())
+    }
+
+    /// Receive one datagram and hand it to the client for the server that
+    /// sent it. Returns the sender, or `None` if the datagram was dropped.
+    /// Which server it's from isn't known until it's read, so there's room
+    /// for the longest datagram any of their clients would take.
+    pub fn recv_packet(&mut self) -> Result<Option<SocketAddr>, SegFsError> {
+        let len = self.clients.values()
+            .map(Client::max_datagram_len)
+            .fold(DEFAULT_SEGMENT_SIZE + DATA_HEADER_LEN, usize::max);
+        self.recv_buffer.resize(len, 0);
+        let (size, server) = self.udp_socket.recv_from(&mut self.recv_buffer)
+            .map_err(|e| format!("unable to recieve data over socket {}", e))?;
+
+        if self.requested_only && !self.clients.contains_key(&server) {
+            return Ok(None);
+        }
+
+        let datagram = self.recv_buffer[..size].to_vec();
+        self.client_for(server)?.process_datagram(datagram)?;
+        Ok(Some(server))
+    }
+
+    pub fn client(&self, server: &SocketAddr) -> Option<&Client> {
+        self.clients.get(server)
+    }
+
+    pub fn client_mut(&mut self, server: &SocketAddr) -> Option<&mut Client> {
+        self.clients.get_mut(server)
+    }
+
+    pub fn servers(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.clients.keys()
+    }
+
+    /// How many files each server has completed so far
+    pub fn file_counts(&self) -> HashMap<SocketAddr, usize> {
+        self.clients.iter().map(|(server, client)| (*server, client.file_count())).collect()
+    }
+}
+
+#[test]
+fn two_servers_one_receiver() {
+    use super::packets::*;
+
+    let receiver_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let receiver_addr = receiver_socket.local_addr().unwrap();
+    let mut receiver = MultiServerReceiver::new(receiver_socket);
+    receiver.set_requested_only(true);
+
+    let first = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let second = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let stranger = UdpSocket::bind("127.0.0.1:0").unwrap();
+    receiver.send_request(first.local_addr().unwrap()).unwrap();
+    receiver.send_request(second.local_addr().unwrap()).unwrap();
+
+    // Both servers use file id 1 for different files
+    first.send_to(&HeaderPacket { file_id: 1, name: String::from("first.txt"), expected_size: None }.to_bytes(), receiver_addr).unwrap();
+    second.send_to(&DataPacket { is_last: true, file_id: 1, packet_number: 0, data: b"two".to_vec() }.to_bytes(), receiver_addr).unwrap();
+    stranger.send_to(&DataPacket { is_last: true, file_id: 1, packet_number: 0, data: b"bad".to_vec() }.to_bytes(), receiver_addr).unwrap();
+    first.send_to(&DataPacket { is_last: true, file_id: 1, packet_number: 0, data: b"one".to_vec() }.to_bytes(), receiver_addr).unwrap();
+
+    let mut senders = Vec::new();
+    for _ in 0..4 {
+        senders.push(receiver.recv_packet().unwrap());
+    }
+    assert!(senders.contains(&None));
+
+    let counts = receiver.file_counts();
+    assert_eq!(counts.len(), 2);
+    assert_eq!(counts[&first.local_addr().unwrap()], 1);
+    assert_eq!(counts[&second.local_addr().unwrap()], 0);
+    assert!(receiver.client(&stranger.local_addr().unwrap()).is_none());
+}
+
+#[cfg(feature = "hmac")]
+#[test]
+fn signed_full_segment() {
+    use super::packets::*;
+    use hmac::Mac;
+
+    let receiver_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let receiver_addr = receiver_socket.local_addr().unwrap();
+    let mut receiver = MultiServerReceiver::new(receiver_socket);
+
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    receiver.send_request(server_addr).unwrap();
+    receiver.client_mut(&server_addr).unwrap().set_hmac_key(Some(b"secret".to_vec()));
+
+    // The MAC goes past the end of a full segment's datagram
+    let mut packet = DataPacket { is_last: true, file_id: 1, packet_number: 0, data: vec![7; 1024] }.to_bytes();
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(b"secret").unwrap();
+    mac.update(&packet);
+    packet.extend_from_slice(&mac.finalize().into_bytes());
+    server.send_to(&packet, receiver_addr).unwrap();
+
+    assert_eq!(receiver.recv_packet().unwrap(), Some(server_addr));
+    assert_eq!(receiver.client(&server_addr).unwrap().file(1).unwrap().data_len(), 1024);
+}
@@ -1,12 +1,79 @@
+use super::error::SegFsError;
+
 pub type FileId = u8;
 pub type StatusByte = u8;
 pub type PacketNumber = u16;
 
 /// Header Packet Structure
+///
+/// If the second bit of the status byte is set, the file id is followed by
+/// the file's total size as a big endian u64, then the name. Headers without
+/// that bit are the original layout, and have no size.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct HeaderPacket {
     pub file_id: u8,
-    pub name: String
+    pub name: String,
+    pub expected_size: Option<u64>
+}
+
+/// How the bytes of a file name in a header are turned into a string
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FilenameEncoding {
+    /// UTF-8, rejecting headers whose name isn't valid
+    #[default]
+    Strict,
+    /// UTF-8, with invalid bytes replaced by U+FFFD
+    Lossy,
+    /// A specific charset, for servers using a local codepage such as
+    /// Windows-1252. Names that don't decode cleanly are rejected.
+    #[cfg(feature = "encoding")]
+    Charset(&'static encoding_rs::Encoding)
+}
+
+impl FilenameEncoding {
+    fn decode(self, name: &[u8]) -> Result<String, String> {
+        match self {
+            FilenameEncoding::Strict => std::str::from_utf8(name)
+                .map(|name| name.to_string())
+                .map_err(|e| format!("filename is not valid utf8: '{}'", e)),
+            FilenameEncoding::Lossy => Ok(String::from_utf8_lossy(name).into_owned()),
+            #[cfg(feature = "encoding")]
+            FilenameEncoding::Charset(encoding) => {
+                let (name, had_errors) = encoding.decode_without_bom_handling(name);
+                if had_errors {
+                    return Err(format!("filename is not valid {}", encoding.name()));
+                }
+                Ok(name.into_owned())
+            }
+        }
+    }
+}
+
+/// Byte order of the multi-byte fields in packets: a data packet's number
+/// and a header's size
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Endianness {
+    /// What the protocol specifies
+    #[default]
+    Big,
+    /// For server variants that send numbers the other way round
+    Little
+}
+
+impl Endianness {
+    fn u16(self, bytes: [u8; 2]) -> u16 {
+        match self {
+            Endianness::Big => u16::from_be_bytes(bytes),
+            Endianness::Little => u16::from_le_bytes(bytes)
+        }
+    }
+
+    fn u64(self, bytes: [u8; 8]) -> u64 {
+        match self {
+            Endianness::Big => u64::from_be_bytes(bytes),
+            Endianness::Little => u64::from_le_bytes(bytes)
+        }
+    }
 }
 
 /// Data Packet Structure
@@ -18,30 +85,217 @@ pub struct DataPacket {
     pub data: Vec<u8>
 }
 
+/// Any packet the server may send while transferring files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    Header(HeaderPacket),
+    Data(DataPacket)
+}
+
+impl Packet {
+    /// Parse a raw datagram, never panicking however malformed it is
+    pub fn parse(data: &[u8]) -> Result<Packet, SegFsError> {
+        Packet::parse_with_encoding(data, FilenameEncoding::Strict)
+    }
+
+    /// Like `parse`, decoding header names with `encoding`
+    pub fn parse_with_encoding(data: &[u8], encoding: FilenameEncoding) -> Result<Packet, SegFsError> {
+        Packet::parse_with(data, encoding, Endianness::Big)
+    }
+
+    /// Like `parse`, decoding header names with `encoding` and numbers in
+    /// the given byte order
+    pub fn parse_with(data: &[u8], encoding: FilenameEncoding, endianness: Endianness) -> Result<Packet, SegFsError> {
+        match data.first() {
+            None => Err("data packet has zero length".to_string().into()),
+            Some(status) if status & 0b1 > 0 => Ok(Packet::Data(DataPacket::parse_with(data, endianness)?)),
+            Some(_) => Ok(Packet::Header(HeaderPacket::parse_with(data, encoding, endianness)?))
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Packet::Header(header) => header.to_bytes(),
+            Packet::Data(data) => data.to_bytes()
+        }
+    }
+
+    pub fn file_id(&self) -> FileId {
+        match self {
+            Packet::Header(header) => header.file_id,
+            Packet::Data(data) => data.file_id
+        }
+    }
+}
+
+/// Capabilities a server may announce in reply to the initial request
+///
+/// The reply is 8 bytes: the magic `fe ca ff fe`, a flags byte, the number of
+/// files it will send, and the segment size as a big endian u16. The magic
+/// can never be a valid header packet since the name would not be utf8.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ServerCaps {
+    pub flags: u8,
+    pub file_count: Option<usize>,
+    pub segment_size: Option<usize>
+}
+
+const CAPS_MAGIC: [u8; 4] = [0xfe, 0xca, 0xff, 0xfe];
+
+impl ServerCaps {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() != 8 || data[..4] != CAPS_MAGIC {
+            return None;
+        }
+
+        let segment_size = u16::from_be_bytes([data[6], data[7]]) as usize;
+
+        Some(ServerCaps {
+            flags: data[4],
+            file_count: Some(data[5] as usize).filter(|&count| count > 0),
+            segment_size: Some(segment_size).filter(|&size| size > 0)
+        })
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = CAPS_MAGIC.to_vec();
+        bytes.push(self.flags);
+        bytes.push(self.file_count.unwrap_or(0) as u8);
+        bytes.extend_from_slice(&(self.segment_size.unwrap_or(0) as u16).to_be_bytes());
+        bytes
+    }
+}
+
+/// Where the server should pick up a file when resuming
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResumePoint {
+    pub file_id: FileId,
+    /// The first packet the client doesn't have, or `RESUME_DONE` when it
+    /// has the whole file and the server can skip it
+    pub next_packet: PacketNumber
+}
+
+pub const RESUME_DONE: PacketNumber = PacketNumber::MAX;
+
+/// A request asking the server to carry on from where an earlier transfer
+/// stopped, rather than sending everything again.
+///
+/// The request is a `1` byte, the number of files as a big endian u16, since
+/// every one of the 256 file ids can be in it, then for each file its id and
+/// next packet as a big endian u16. Servers which don't
+/// understand it are expected to treat it as a plain request and start over.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumeRequest {
+    pub files: Vec<ResumePoint>
+}
+
+impl ResumeRequest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // Ids are a byte, so there are never more than 256 distinct files
+        let mut bytes = vec![1];
+        bytes.extend_from_slice(&(self.files.len() as u16).to_be_bytes());
+        for point in self.files.iter() {
+            bytes.push(point.file_id);
+            bytes.extend_from_slice(&point.next_packet.to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let (&[1, high, low], rest) = data.split_at_checked(3)? else {
+            return None;
+        };
+        if rest.len() != u16::from_be_bytes([high, low]) as usize * 3 {
+            return None;
+        }
+
+        let files = rest.chunks(3)
+            .map(|point| ResumePoint { file_id: point[0], next_packet: u16::from_be_bytes([point[1], point[2]]) })
+            .collect();
+        Some(ResumeRequest { files })
+    }
+}
+
+impl HeaderPacket {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = match self.expected_size {
+            Some(size) => {
+                let mut bytes = vec![0b10, self.file_id];
+                bytes.extend_from_slice(&size.to_be_bytes());
+                bytes
+            }
+            None => vec![0, self.file_id]
+        };
+        bytes.extend_from_slice(self.name.as_bytes());
+        bytes
+    }
+}
+
+impl DataPacket {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let status = if self.is_last { 0b11 } else { 0b01 };
+        let mut bytes = vec![status, self.file_id];
+        bytes.extend_from_slice(&self.packet_number.to_be_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+}
+
 impl std::convert::TryFrom<Vec<u8>> for HeaderPacket {
     type Error = String;
 
     fn try_from(data: Vec<u8>) -> Result<Self, Self::Error> {
+        HeaderPacket::try_from(data.as_slice())
+    }
+}
+
+impl std::convert::TryFrom<&[u8]> for HeaderPacket {
+    type Error = String;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        HeaderPacket::parse_with_encoding(data, FilenameEncoding::Strict)
+    }
+}
+
+impl HeaderPacket {
+    pub fn parse_with_encoding(data: &[u8], encoding: FilenameEncoding) -> Result<Self, String> {
+        HeaderPacket::parse_with(data, encoding, Endianness::Big)
+    }
+
+    pub fn parse_with(data: &[u8], encoding: FilenameEncoding, endianness: Endianness) -> Result<Self, String> {
         if data.len() < 2 {
             return Err(format!("cannot parse header packet from data with length {}", data.len()));
         }
 
         if data[0] & 0b1 > 0 {
-            return Err(format!("cannot parse header packet from data packet"));
+            return Err("cannot parse header packet from data packet".to_string());
         }
 
         let file_id = data[1];
 
-        if data.len() == 2 {
-            return Err(format!("cannot parse header packet with empty file name"))
+        let (expected_size, name_start) = if data[0] & 0b10 > 0 {
+            if data.len() < 10 {
+                return Err(format!("cannot parse header packet size from data with length {}", data.len()));
+            }
+
+            let mut size = [0; 8];
+            size.copy_from_slice(&data[2..10]);
+            (Some(endianness.u64(size)), 10)
         }
+        else {
+            (None, 2)
+        };
 
-        let data = std::str::from_utf8(&data[2..])
-                        .map_err(|e| format!("filename is not valid utf8: '{}'", e))?;
+        if data.len() == name_start {
+            return Err("cannot parse header packet with empty file name".to_string())
+        }
+
+        let name = encoding.decode(&data[name_start..])?;
 
         Ok(HeaderPacket {
             file_id,
-            name: data.to_string()
+            name,
+            expected_size
         })
     }   
 }
@@ -50,25 +304,41 @@ impl std::convert::TryFrom<Vec<u8>> for DataPacket {
     type Error = String;
 
     fn try_from(data: Vec<u8>) -> Result<Self, Self::Error> {
-        if data.len() < 2 {
+        DataPacket::try_from(data.as_slice())
+    }
+}
+
+impl std::convert::TryFrom<&[u8]> for DataPacket {
+    type Error = String;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        DataPacket::parse_with(data, Endianness::Big)
+    }
+}
+
+impl DataPacket {
+    pub fn parse_with(data: &[u8], endianness: Endianness) -> Result<Self, String> {
+        if data.len() < 4 {
             return Err(format!("cannot parse data packet from data with length {}", data.len()));
         }
 
         if data[0] & 0b1 == 0 {
-            return Err(format!("cannot parse data packet from header packet"));
+            return Err("cannot parse data packet from header packet".to_string());
         }
 
         let file_id = data[1];
-        let packet_number = u16::from_be_bytes([data[2], data[3]]);
+        let packet_number = endianness.u16([data[2], data[3]]);
+        let is_last = data[0] & 0b10 > 0;
 
-        if data.len() == 4 {
-            return Err(format!("cannot parse data packet with empty data"))
+        // Only the last packet may be empty, which is how an empty file is sent
+        if data.len() == 4 && !is_last {
+            return Err("cannot parse data packet with empty data".to_string())
         }
 
         let file_data = data[4..].to_vec();
 
         Ok(DataPacket {
-            is_last: data[0] & 0b10 > 0,
+            is_last,
             file_id,
             packet_number,
             data: file_data
@@ -92,6 +362,16 @@ fn data_packet_decode() {
     // Test buffers which do not contain a file name
     assert!(DataPacket::try_from(vec![0, 5, 0, 0]).is_err());
 
+    // Slices parse the same as owned buffers
+    assert!(DataPacket::try_from(&[1, 2, 3][..]).is_err());
+    assert_eq!(DataPacket::try_from(&[1, 2, 0, 3, 9][..]).unwrap(),
+               DataPacket::try_from(vec![1, 2, 0, 3, 9]).unwrap());
+
+    // Only a last packet can be empty
+    assert!(DataPacket::try_from(vec![1, 5, 0, 0]).is_err());
+    assert_eq!(DataPacket::try_from(vec![3, 5, 0, 0]).unwrap(),
+               DataPacket { is_last: true, file_id: 5, packet_number: 0, data: vec![] });
+
     // Actually test some valid buffers
     assert_eq!(DataPacket::try_from(vec![3, 42, 0, 0, b'h', b'e', b'l', b'l', b'o']).unwrap(),
                DataPacket { is_last: true, file_id: 42, packet_number: 0, data: vec![b'h', b'e', b'l', b'l', b'o'] });
@@ -120,5 +400,109 @@ fn header_packet_decode() {
 
     // Actually test some valid buffers
     assert_eq!(HeaderPacket::try_from(vec![0, 5, b't', b'e', b's', b't']).unwrap(),
-               HeaderPacket { file_id: 5, name: String::from("test") });
-}
\ No newline at end of file
+               HeaderPacket { file_id: 5, name: String::from("test"), expected_size: None });
+}
+#[test]
+fn packet_encode_round_trip() {
+    let header = HeaderPacket { file_id: 9, name: String::from("small.txt"), expected_size: None };
+    assert_eq!(HeaderPacket::try_from(header.to_bytes()).unwrap(), header);
+
+    let data = DataPacket { is_last: true, file_id: 9, packet_number: 0x1234, data: vec![1, 2, 3] };
+    assert_eq!(data.to_bytes(), vec![3, 9, 0x12, 0x34, 1, 2, 3]);
+    assert_eq!(DataPacket::try_from(data.to_bytes()).unwrap(), data);
+}
+
+/// Feed bytes through the parser, which must reject bad input without panicking
+#[cfg(test)]
+fn assert_parse_doesnt_panic(data: &[u8]) {
+    let _ = Packet::parse(data);
+}
+
+/// Longer inputs are left to the `packet_parse` fuzz target in `fuzz/`
+#[test]
+fn parse_regressions() {
+    // Regression seeds taken from the decode tests, plus truncated data packets
+    let seeds: &[&[u8]] = &[
+        &[], &[1], &[5], &[6], &[0], &[6, 5], &[0, 5], &[0, 5, 0xff], &[0, 5, 0, 0],
+        &[1, 2], &[1, 2, 3], &[3, 42, 0, 0], &[3, 42, 0, 0, b'h'],
+        &[65, 0xaa, 0xaa, 0x55, 0, 1, 2, 3, 4, 5], &[0, 5, b't', b'e', b's', b't']
+    ];
+    for seed in seeds {
+        assert_parse_doesnt_panic(seed);
+    }
+
+    assert!(Packet::parse(&[1, 2]).is_err());
+    assert!(Packet::parse(&[3, 42, 0]).is_err());
+    assert_eq!(Packet::parse(&[0, 5, b't']).unwrap(),
+               Packet::Header(HeaderPacket { file_id: 5, name: String::from("t"), expected_size: None }));
+
+    // Every input of up to two bytes
+    assert_parse_doesnt_panic(&[]);
+    for first in 0..=255u8 {
+        assert_parse_doesnt_panic(&[first]);
+        for second in 0..=255u8 {
+            assert_parse_doesnt_panic(&[first, second]);
+        }
+    }
+}
+
+#[test]
+fn header_packet_with_size() {
+    let header = HeaderPacket::try_from(vec![2, 7, 0, 0, 0, 0, 0, 0, 0x10, 0x01, b'a']).unwrap();
+    assert_eq!(header, HeaderPacket { file_id: 7, name: String::from("a"), expected_size: Some(0x1001) });
+    assert_eq!(HeaderPacket::try_from(header.to_bytes()).unwrap(), header);
+
+    // Truncated sizes, and a size with no name
+    assert!(HeaderPacket::try_from(vec![2, 7, 0, 0, 0]).is_err());
+    assert!(HeaderPacket::try_from(vec![2, 7, 0, 0, 0, 0, 0, 0, 0, 1]).is_err());
+}
+
+#[test]
+fn filename_encodings() {
+    let header = [0, 1, b'c', b'a', b'f', 0xe9];
+    assert!(HeaderPacket::try_from(&header[..]).is_err());
+    assert_eq!(HeaderPacket::parse_with_encoding(&header, FilenameEncoding::Lossy).unwrap().name, "caf\u{fffd}");
+}
+
+#[cfg(feature = "encoding")]
+#[test]
+fn filename_charset() {
+    let header = [0, 1, b'c', b'a', b'f', 0xe9];
+    assert_eq!(HeaderPacket::parse_with_encoding(&header, FilenameEncoding::Charset(encoding_rs::WINDOWS_1252)).unwrap().name, "café");
+
+    // 0xff can't start a Shift_JIS character
+    assert!(HeaderPacket::parse_with_encoding(&[0, 1, 0xff], FilenameEncoding::Charset(encoding_rs::SHIFT_JIS)).is_err());
+}
+
+#[test]
+fn packet_endianness() {
+    let data = [1, 4, 0x01, 0x02, b'x'];
+    assert_eq!(DataPacket::parse_with(&data, Endianness::Big).unwrap().packet_number, 0x0102);
+    assert_eq!(DataPacket::parse_with(&data, Endianness::Little).unwrap().packet_number, 0x0201);
+
+    let header = [2, 4, 1, 0, 0, 0, 0, 0, 0, 0, b'a'];
+    let parse = |endianness| HeaderPacket::parse_with(&header, FilenameEncoding::Strict, endianness).unwrap().expected_size;
+    assert_eq!(parse(Endianness::Big), Some(1 << 56));
+    assert_eq!(parse(Endianness::Little), Some(1));
+
+    assert_eq!(Packet::parse_with(&data, FilenameEncoding::Strict, Endianness::Little).unwrap(),
+               Packet::Data(DataPacket { is_last: false, file_id: 4, packet_number: 0x0201, data: vec![b'x'] }));
+}
+
+#[test]
+fn resume_request_round_trip() {
+    let request = ResumeRequest { files: vec![
+        ResumePoint { file_id: 1, next_packet: 0x0102 },
+        ResumePoint { file_id: 7, next_packet: RESUME_DONE }
+    ] };
+
+    assert_eq!(request.to_bytes(), vec![1, 0, 2, 1, 1, 2, 7, 0xff, 0xff]);
+    assert_eq!(ResumeRequest::parse(&request.to_bytes()), Some(request));
+    assert_eq!(ResumeRequest::parse(&[0]), None);
+    assert_eq!(ResumeRequest::parse(&[1, 0, 2, 1, 1, 2]), None);
+
+    // Every id at once doesn't fit a byte's count
+    let request = ResumeRequest { files: (0..=FileId::MAX).map(|file_id| ResumePoint { file_id, next_packet: 3 }).collect() };
+    assert_eq!(request.to_bytes()[..3], [1, 1, 0]);
+    assert_eq!(ResumeRequest::parse(&request.to_bytes()), Some(request));
+}
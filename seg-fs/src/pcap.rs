@@ -0,0 +1,143 @@
+use super::error::*;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// A classic libpcap capture with the raw IP link type, so each record is a
+// made up IPv4 or IPv6 header, a UDP header, then the datagram. Wireshark
+// and tcpdump read these directly.
+
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+const UDP_PROTOCOL: u8 = 17;
+
+pub struct PcapWriter {
+    writer: std::io::BufWriter<std::fs::File>
+}
+
+impl PcapWriter {
+    /// Start a new capture at `path`, replacing anything already there
+    pub fn create(path: &Path) -> Result<Self, SegFsError> {
+        let file = std::fs::File::create(path).map_err(|e| format!("unable to create capture {}: {}", path.display(), e))?;
+        let mut writer = std::io::BufWriter::new(file);
+
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        writer.write_all(&header).and_then(|_| writer.flush())
+            .map_err(|e| format!("unable to write to capture {}", e))?;
+
+        Ok(Self { writer })
+    }
+
+    /// Add a datagram sent from `source` to `destination`
+    pub fn record(&mut self, source: SocketAddr, destination: SocketAddr, datagram: &[u8]) -> Result<(), SegFsError> {
+        let packet = ip_packet(source, destination, datagram);
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        let mut record = Vec::with_capacity(16 + packet.len());
+        record.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&timestamp.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&packet);
+
+        self.writer.write_all(&record).and_then(|_| self.writer.flush())
+            .map_err(|e| format!("unable to write to capture {}", e))?;
+        Ok(())
+    }
+}
+
+/// Wrap a datagram in UDP and IP headers. The UDP checksum is left as 0,
+/// meaning it isn't set, which Wireshark accepts.
+fn ip_packet(source: SocketAddr, destination: SocketAddr, datagram: &[u8]) -> Vec<u8> {
+    let udp_len = (8 + datagram.len()) as u16;
+    let mut udp = Vec::with_capacity(udp_len as usize);
+    udp.extend_from_slice(&source.port().to_be_bytes());
+    udp.extend_from_slice(&destination.port().to_be_bytes());
+    udp.extend_from_slice(&udp_len.to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(datagram);
+
+    let mut packet = Vec::new();
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            let total_len = 20 + udp_len;
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&total_len.to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, UDP_PROTOCOL, 0, 0]);
+            packet.extend_from_slice(&source.octets());
+            packet.extend_from_slice(&destination.octets());
+
+            let checksum = ipv4_checksum(&packet);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        (source, destination) => {
+            let as_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip
+            };
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&udp_len.to_be_bytes());
+            packet.extend_from_slice(&[UDP_PROTOCOL, 64]);
+            packet.extend_from_slice(&as_v6(source).octets());
+            packet.extend_from_slice(&as_v6(destination).octets());
+        }
+    }
+
+    packet.extend_from_slice(&udp);
+    packet
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header.chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[test]
+fn write_capture() {
+    let path = std::env::temp_dir().join(format!("seg-fs-write_capture-{}.pcap", std::process::id()));
+    let server: SocketAddr = "10.0.0.1:6014".parse().unwrap();
+    let local: SocketAddr = "10.0.0.2:50000".parse().unwrap();
+
+    let mut writer = PcapWriter::create(&path).unwrap();
+    writer.record(server, local, b"first").unwrap();
+    writer.record(server, local, b"second!").unwrap();
+    drop(writer);
+
+    let data = std::fs::read(&path).unwrap();
+    assert_eq!(data[..4], PCAP_MAGIC.to_le_bytes());
+    assert_eq!(data[20..24], LINKTYPE_RAW.to_le_bytes());
+
+    let mut rest = &data[24..];
+    let mut datagrams = Vec::new();
+    while !rest.is_empty() {
+        let len = u32::from_le_bytes([rest[8], rest[9], rest[10], rest[11]]) as usize;
+        let packet = &rest[16..16 + len];
+
+        // The IPv4 header checksums to zero when it's right
+        assert_eq!(packet[0], 0x45);
+        assert_eq!(ipv4_checksum(&packet[..20]), 0);
+        assert_eq!(packet[12..16], [10, 0, 0, 1]);
+        assert_eq!(u16::from_be_bytes([packet[20], packet[21]]), 6014);
+        assert_eq!(u16::from_be_bytes([packet[22], packet[23]]), 50000);
+
+        datagrams.push(packet[28..].to_vec());
+        rest = &rest[16 + len..];
+    }
+    assert_eq!(datagrams, vec![b"first".to_vec(), b"second!".to_vec()]);
+
+    std::fs::remove_file(&path).unwrap();
+}
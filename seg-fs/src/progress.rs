@@ -0,0 +1,282 @@
+use super::client::Client;
+use std::io::Write;
+
+/// How many characters wide the bar itself is
+const BAR_WIDTH: usize = 20;
+
+/// Redraws the progress display in place. Each frame overwrites the one
+/// before it line by line rather than blanking the screen first, so there's
+/// no flicker, and anything left over from a longer frame is cleared.
+#[derive(Default)]
+pub struct ProgressBar {
+    lines_drawn: usize
+}
+
+impl ProgressBar {
+    pub fn new() -> Self {
+        Self { lines_drawn: 0 }
+    }
+
+    /// Draw the client's state, counting completed files against `target`
+    pub fn draw(&mut self, client: &Client, target: usize) {
+        let mut stdout = std::io::stdout().lock();
+        let _ = stdout.write_all(self.render(&frame(client, target)).as_bytes());
+        let _ = stdout.flush();
+    }
+
+    /// The text and escape codes which replace the last frame with `frame`
+    fn render(&mut self, frame: &str) -> String {
+        let mut output = String::new();
+
+        // The cursor is left on the line after the last frame
+        if self.lines_drawn > 0 {
+            output.push_str(&format!("\x1B[{}A", self.lines_drawn));
+        }
+
+        let mut lines = 0;
+        for line in frame.lines() {
+            output.push('\r');
+            output.push_str(line);
+            output.push_str("\x1B[K\n");
+            lines += 1;
+        }
+
+        if lines < self.lines_drawn {
+            output.push_str("\x1B[J");
+        }
+
+        self.lines_drawn = lines;
+        output
+    }
+}
+
+/// The client's file listing followed by a bar for the whole transfer
+fn frame(client: &Client, target: usize) -> String {
+    let stats = client.stats();
+    let done = client.file_count().min(target);
+    let filled = (done * BAR_WIDTH).checked_div(target).unwrap_or(BAR_WIDTH);
+
+    format!("{}[{}{}] {}/{} files, {} bytes\n",
+            client,
+            "#".repeat(filled),
+            ".".repeat(BAR_WIDTH - filled),
+            done,
+            target,
+            stats.bytes_received)
+}
+
+/// The same display drawn with `indicatif`: a bar for each file in progress
+/// counting its segments, and one for the whole transfer counting files
+#[cfg(feature = "indicatif")]
+pub struct IndicatifProgress {
+    bars: indicatif::MultiProgress,
+    overall: indicatif::ProgressBar,
+    files: std::collections::HashMap<super::packets::FileId, indicatif::ProgressBar>
+}
+
+#[cfg(feature = "indicatif")]
+impl IndicatifProgress {
+    pub fn new() -> Self {
+        Self::with_draw_target(indicatif::ProgressDrawTarget::stderr())
+    }
+
+    fn with_draw_target(target: indicatif::ProgressDrawTarget) -> Self {
+        let bars = indicatif::MultiProgress::with_draw_target(target);
+        let overall = bars.add(indicatif::ProgressBar::new(0));
+        overall.set_style(indicatif::ProgressStyle::with_template("[{bar:20}] {pos}/{len} files, {msg} bytes")
+            .expect("progress template is valid")
+            .progress_chars("#>."));
+
+        Self { bars, overall, files: std::collections::HashMap::new() }
+    }
+
+    /// Bring the bars up to date with the client, counting completed files
+    /// against `target`. Bars for files that are no longer in progress are
+    /// cleared away.
+    pub fn draw(&mut self, client: &Client, target: usize) {
+        let incomplete = client.incomplete_files();
+
+        self.files.retain(|id, bar| {
+            let keep = incomplete.iter().any(|file| file.file_id == *id);
+            if !keep {
+                bar.finish_and_clear();
+            }
+            keep
+        });
+
+        for file in incomplete.iter() {
+            let bar = self.files.entry(file.file_id).or_insert_with(|| {
+                let bar = self.bars.insert_before(&self.overall, indicatif::ProgressBar::new(0));
+                bar.set_style(indicatif::ProgressStyle::with_template("{msg} [{bar:20}] {pos}/{len} segments")
+                    .expect("progress template is valid")
+                    .progress_chars("#>."));
+                bar
+            });
+
+            bar.set_message(file.name.clone().unwrap_or_else(|| format!("FileId {:02x}", file.file_id)));
+            // The total isn't known until the last packet arrives
+            let total = file.missing_segments.as_ref().map(|missing| file.received_segments + missing.len());
+            bar.set_length(total.unwrap_or(file.received_segments) as u64);
+            bar.set_position(file.received_segments as u64);
+        }
+
+        self.overall.set_length(target as u64);
+        self.overall.set_position(client.file_count().min(target) as u64);
+        self.overall.set_message(client.stats().bytes_received.to_string());
+    }
+}
+
+#[cfg(feature = "indicatif")]
+impl Default for IndicatifProgress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Progress as plain lines, one each time a file completes, for logs, CI
+/// and terminals that don't understand escape codes
+#[derive(Default)]
+pub struct PlainProgress {
+    files_reported: usize
+}
+
+impl PlainProgress {
+    pub fn new() -> Self {
+        Self { files_reported: 0 }
+    }
+
+    pub fn draw(&mut self, client: &Client, target: usize) {
+        let lines = self.lines(client, target);
+        if !lines.is_empty() {
+            let mut stdout = std::io::stdout().lock();
+            let _ = stdout.write_all(lines.as_bytes());
+            let _ = stdout.flush();
+        }
+    }
+
+    /// A line for each file completed since the last call
+    fn lines(&mut self, client: &Client, target: usize) -> String {
+        let done = client.file_count();
+        let lines = (self.files_reported + 1..=done)
+            .map(|file| format!("file {}/{} complete\n", file, target))
+            .collect();
+        self.files_reported = self.files_reported.max(done);
+        lines
+    }
+}
+
+/// Whichever display suits where the output is going
+pub enum Progress {
+    Plain(PlainProgress),
+    #[cfg(not(feature = "indicatif"))]
+    Bar(ProgressBar),
+    #[cfg(feature = "indicatif")]
+    Bar(IndicatifProgress)
+}
+
+impl Progress {
+    pub fn new(plain: bool) -> Self {
+        if plain {
+            Progress::Plain(PlainProgress::new())
+        }
+        else {
+            #[cfg(not(feature = "indicatif"))]
+            return Progress::Bar(ProgressBar::new());
+            #[cfg(feature = "indicatif")]
+            return Progress::Bar(IndicatifProgress::new());
+        }
+    }
+
+    pub fn draw(&mut self, client: &Client, target: usize) {
+        match self {
+            Progress::Plain(progress) => progress.draw(client, target),
+            Progress::Bar(progress) => progress.draw(client, target)
+        }
+    }
+}
+
+/// Whether to stick to plain lines: when asked to, or when the output isn't
+/// a terminal that could make sense of redrawing
+pub fn plain_output(forced: bool, is_terminal: bool) -> bool {
+    forced || !is_terminal
+}
+
+#[test]
+fn redraw_line_counts() {
+    let mut bar = ProgressBar::new();
+
+    assert_eq!(bar.render("a\nb\nc\n"), "\ra\x1B[K\n\rb\x1B[K\n\rc\x1B[K\n");
+
+    // Back up over all three lines, then clear the one no longer needed
+    assert_eq!(bar.render("long line\nd\n"), "\x1B[3A\rlong line\x1B[K\n\rd\x1B[K\n\x1B[J");
+
+    // Growing needs no clearing
+    assert_eq!(bar.render("e\nf\ng\n"), "\x1B[2A\re\x1B[K\n\rf\x1B[K\n\rg\x1B[K\n");
+}
+
+#[test]
+fn frame_matches_print_line_length() {
+    use super::packets::*;
+
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut client = Client::new(socket);
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("a.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 0, data: vec![1] }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: false, file_id: 2, packet_number: 0, data: vec![2] }.to_bytes()).unwrap();
+
+    let frame = frame(&client, 4);
+    assert_eq!(frame.lines().count(), client.print_line_length() + 1);
+    assert!(frame.ends_with("[#####...............] 1/4 files, 17 bytes\n"));
+}
+
+#[cfg(feature = "indicatif")]
+#[test]
+fn indicatif_bars() {
+    use super::packets::*;
+
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut client = Client::new(socket);
+    let mut progress = IndicatifProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden());
+
+    client.process_datagram(HeaderPacket { file_id: 1, name: String::from("a.txt"), expected_size: None }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: true, file_id: 1, packet_number: 2, data: vec![1] }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: false, file_id: 2, packet_number: 0, data: vec![2] }.to_bytes()).unwrap();
+    progress.draw(&client, 2);
+
+    assert_eq!(progress.files.len(), 2);
+    assert_eq!((progress.files[&1].position(), progress.files[&1].length()), (1, Some(3)));
+    assert_eq!(progress.files[&2].position(), 1);
+    assert_eq!((progress.overall.position(), progress.overall.length()), (0, Some(2)));
+
+    client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 0, data: vec![1] }.to_bytes()).unwrap();
+    client.process_datagram(DataPacket { is_last: false, file_id: 1, packet_number: 1, data: vec![1] }.to_bytes()).unwrap();
+    progress.draw(&client, 2);
+
+    assert!(!progress.files.contains_key(&1));
+    assert_eq!(progress.overall.position(), 1);
+    assert_eq!(progress.overall.message(), client.stats().bytes_received.to_string());
+}
+
+#[test]
+fn plain_lines() {
+    use super::packets::*;
+
+    assert!(plain_output(true, true));
+    assert!(plain_output(false, false));
+    assert!(!plain_output(false, true));
+
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut client = Client::new(socket);
+    let mut progress = PlainProgress::new();
+    assert_eq!(progress.lines(&client, 3), "");
+
+    for file_id in 1..=2 {
+        client.process_datagram(HeaderPacket { file_id, name: format!("{}.txt", file_id), expected_size: None }.to_bytes()).unwrap();
+        client.process_datagram(DataPacket { is_last: true, file_id, packet_number: 0, data: vec![file_id] }.to_bytes()).unwrap();
+    }
+    assert_eq!(progress.lines(&client, 3), "file 1/3 complete\nfile 2/3 complete\n");
+    assert_eq!(progress.lines(&client, 3), "");
+
+    client.process_datagram(DataPacket { is_last: false, file_id: 3, packet_number: 0, data: vec![3] }.to_bytes()).unwrap();
+    assert_eq!(progress.lines(&client, 3), "");
+}
@@ -0,0 +1,48 @@
+/// A small, fast xorshift generator for the few things that need to be
+/// random, like backoff jitter. Not for anything secret.
+#[derive(Debug, Clone)]
+pub struct SmallRng {
+    state: u64
+}
+
+impl SmallRng {
+    pub fn seed_from_u64(seed: u64) -> Self {
+        // xorshift gets stuck at 0
+        Self { state: seed.max(1) }
+    }
+
+    /// Seeded from the clock, mixed with a count so generators made in the
+    /// same instant still differ
+    pub fn from_entropy() -> Self {
+        static COUNT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+        let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        let count = COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        Self::seed_from_u64(nanos ^ count.wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ std::process::id() as u64)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// A number from 0 to 1
+    pub fn next_f64(&mut self) -> f64 {
+        self.next_u64() as f64 / u64::MAX as f64
+    }
+}
+
+#[test]
+fn seeded_rng() {
+    let mut first = SmallRng::seed_from_u64(3);
+    let mut second = SmallRng::seed_from_u64(3);
+    let numbers: Vec<u64> = (0..16).map(|_| first.next_u64()).collect();
+    assert_eq!(numbers, (0..16).map(|_| second.next_u64()).collect::<Vec<_>>());
+    assert!((0..16).all(|_| (0.0..=1.0).contains(&first.next_f64())));
+
+    // A zero seed doesn't get stuck
+    assert_ne!(SmallRng::seed_from_u64(0).next_u64(), 0);
+    assert_ne!(SmallRng::from_entropy().next_u64(), SmallRng::from_entropy().next_u64());
+}
@@ -0,0 +1,137 @@
+use super::client::Client;
+use super::error::*;
+use super::transport::Offline;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+// A trace is a sequence of datagrams, each written as its length in a big
+// endian u32 followed by its bytes.
+
+pub struct TraceWriter {
+    writer: std::io::BufWriter<std::fs::File>
+}
+
+impl TraceWriter {
+    /// Open a trace for appending, creating it if it doesn't exist
+    pub fn open(path: &Path) -> Result<Self, SegFsError> {
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(path)
+            .map_err(|e| format!("unable to open trace {}: {}", path.display(), e))?;
+
+        Ok(Self { writer: std::io::BufWriter::new(file) })
+    }
+
+    pub fn record(&mut self, datagram: &[u8]) -> Result<(), SegFsError> {
+        let len = datagram.len() as u32;
+        self.writer.write_all(&len.to_be_bytes())
+            .and_then(|_| self.writer.write_all(datagram))
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| format!("unable to write to trace {}", e))?;
+        Ok(())
+    }
+}
+
+/// Read back every datagram in a trace, in the order they were recorded
+pub fn read_trace(path: &Path) -> Result<Vec<Vec<u8>>, SegFsError> {
+    let mut data = Vec::new();
+    std::fs::File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut data))
+        .map_err(|e| format!("unable to read trace {}: {}", path.display(), e))?;
+
+    let mut datagrams = Vec::new();
+    let mut rest = data.as_slice();
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return Err(format!("trace {} ends partway through a length", path.display()).into());
+        }
+
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        rest = &rest[4..];
+        if rest.len() < len {
+            return Err(format!("trace {} ends partway through a datagram", path.display()).into());
+        }
+
+        datagrams.push(rest[..len].to_vec());
+        rest = &rest[len..];
+    }
+
+    Ok(datagrams)
+}
+
+/// Rebuild the files in a captured transfer without any network: replay the
+/// trace at `source`, or every trace in it if it's a directory in name
+/// order, then write whatever files completed to `output_dir`, creating it
+/// if need be. Hands back
+/// where they went, in the order they completed.
+pub fn reconstruct(source: &Path, output_dir: &Path) -> Result<Vec<PathBuf>, SegFsError> {
+    let traces = if source.is_dir() {
+        let mut traces = std::fs::read_dir(source)
+            .and_then(|entries| entries.map(|entry| entry.map(|entry| entry.path())).collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("unable to read directory {}: {}", source.display(), e))?;
+        traces.retain(|path| path.is_file());
+        traces.sort();
+        traces
+    }
+    else {
+        vec![source.to_path_buf()]
+    };
+
+    std::fs::create_dir_all(output_dir).map_err(|e| format!("unable to create directory {}: {}", output_dir.display(), e))?;
+    let mut client = Client::with_transport(Offline);
+    client.set_output_dir(output_dir);
+    for trace in traces.iter() {
+        client.replay_from(trace)?;
+    }
+
+    let ids: Vec<_> = client.completed_files().map(|(id, _)| id).collect();
+    let mut paths = Vec::with_capacity(ids.len());
+    for id in ids {
+        paths.extend(client.finalize_file(id)?);
+    }
+    Ok(paths)
+}
+
+#[test]
+fn reconstruct_from_traces() {
+    use super::packets::*;
+
+    let dir = std::env::temp_dir().join(format!("seg-fs-reconstruct_from_traces-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let (traces, output) = (dir.join("traces"), dir.join("output"));
+    std::fs::create_dir_all(&traces).unwrap();
+
+    // Record a transfer from a server over the loopback interface
+    let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.connect(server.local_addr().unwrap()).unwrap();
+    let mut client = Client::new(socket);
+    client.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+    client.record_to(traces.join("1.trace")).unwrap();
+    client.send_request().unwrap();
+    let (_, peer) = server.recv_from(&mut [0; 16]).unwrap();
+
+    let packets = [
+        DataPacket { is_last: true, file_id: 1, packet_number: 1, data: b"dump".to_vec() }.to_bytes(),
+        HeaderPacket { file_id: 1, name: String::from("a.txt"), expected_size: None }.to_bytes(),
+        HeaderPacket { file_id: 2, name: String::from("b.txt"), expected_size: None }.to_bytes(),
+        DataPacket { is_last: false, file_id: 1, packet_number: 0, data: b"packet ".to_vec() }.to_bytes()
+    ];
+    for packet in packets.iter() {
+        server.send_to(packet, peer).unwrap();
+        client.recv_packet().unwrap();
+    }
+
+    // The rest of it went to a second trace
+    client.record_to(traces.join("2.trace")).unwrap();
+    server.send_to(&DataPacket { is_last: true, file_id: 2, packet_number: 0, data: b"b".to_vec() }.to_bytes(), peer).unwrap();
+    client.recv_packet().unwrap();
+
+    assert_eq!(reconstruct(&traces.join("1.trace"), &output).unwrap(), vec![output.join("a.txt")]);
+    assert_eq!(std::fs::read(output.join("a.txt")).unwrap(), client.completed_file_bytes("a.txt").unwrap());
+    std::fs::remove_dir_all(&output).unwrap();
+
+    assert_eq!(reconstruct(&traces, &output).unwrap(), vec![output.join("a.txt"), output.join("b.txt")]);
+    assert_eq!(std::fs::read(output.join("a.txt")).unwrap(), b"packet dump");
+    assert_eq!(std::fs::read(output.join("b.txt")).unwrap(), b"b");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,282 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Something datagrams can be sent and received over, connected to one
+/// server. Parsing and reassembly don't care which; UDP is the usual one.
+pub trait PacketTransport {
+    fn send(&self, data: &[u8]) -> std::io::Result<usize>;
+
+    /// Receive one datagram, along with where it came from when that's an
+    /// IP address
+    fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, Option<SocketAddr>)>;
+
+    /// The server's IP address, for transports that have one
+    fn peer_addr(&self) -> std::io::Result<SocketAddr>;
+
+    /// Our own IP address, for transports that have one
+    fn local_addr(&self) -> std::io::Result<SocketAddr>;
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+
+    /// The socket underneath, for options the trait doesn't cover, if
+    /// there is one
+    fn socket(&self) -> Option<socket2::SockRef<'_>>;
+}
+
+impl PacketTransport for std::net::UdpSocket {
+    fn send(&self, data: &[u8]) -> std::io::Result<usize> {
+        std::net::UdpSocket::send(self, data)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, Option<SocketAddr>)> {
+        std::net::UdpSocket::recv_from(self, buf).map(|(size, peer)| (size, Some(peer)))
+    }
+
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        std::net::UdpSocket::peer_addr(self)
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        std::net::UdpSocket::local_addr(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        std::net::UdpSocket::set_read_timeout(self, timeout)
+    }
+
+    fn socket(&self) -> Option<socket2::SockRef<'_>> {
+        Some(socket2::SockRef::from(self))
+    }
+}
+
+/// A Unix domain datagram socket, for servers run locally. It has no IP
+/// addresses, so `peer_addr` and `local_addr` fail and pcap captures skip
+/// its datagrams.
+#[cfg(unix)]
+impl PacketTransport for std::os::unix::net::UnixDatagram {
+    fn send(&self, data: &[u8]) -> std::io::Result<usize> {
+        std::os::unix::net::UnixDatagram::send(self, data)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, Option<SocketAddr>)> {
+        std::os::unix::net::UnixDatagram::recv_from(self, buf).map(|(size, _)| (size, None))
+    }
+
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        Err(no_ip_address())
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        Err(no_ip_address())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        std::os::unix::net::UnixDatagram::set_read_timeout(self, timeout)
+    }
+
+    fn socket(&self) -> Option<socket2::SockRef<'_>> {
+        Some(socket2::SockRef::from(self))
+    }
+}
+
+/// A Unix datagram socket bound in a directory of its own under the temp
+/// dir, so its path can't be one another process is using. The directory
+/// goes when it's dropped.
+#[cfg(unix)]
+pub struct TempUnixSocket {
+    socket: std::os::unix::net::UnixDatagram,
+    dir: std::path::PathBuf
+}
+
+#[cfg(unix)]
+impl TempUnixSocket {
+    /// Bind a new socket and connect it to the server listening at `server`
+    pub fn connect(server: &std::path::Path) -> Result<Self, super::error::SegFsError> {
+        static COUNT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+        // Making the directory fails if it's there, so whichever process
+        // makes it has it to itself
+        let dir = loop {
+            let count = COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let dir = std::env::temp_dir().join(format!("seg-fs-client-{}-{}", std::process::id(), count));
+            match std::fs::create_dir(&dir) {
+                Ok(()) => break dir,
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(format!("unable to create directory {}: {}", dir.display(), e).into())
+            }
+        };
+
+        match connect_unix_socket(&dir.join("client.sock"), server) {
+            Ok(socket) => Ok(Self { socket, dir }),
+            Err(e) => {
+                let _ = std::fs::remove_dir_all(&dir);
+                Err(e)
+            }
+        }
+    }
+
+    /// Where the socket is bound
+    pub fn path(&self) -> std::path::PathBuf {
+        self.dir.join("client.sock")
+    }
+}
+
+#[cfg(unix)]
+impl Drop for TempUnixSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(unix)]
+impl PacketTransport for TempUnixSocket {
+    fn send(&self, data: &[u8]) -> std::io::Result<usize> {
+        self.socket.send(data)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, Option<SocketAddr>)> {
+        PacketTransport::recv_from(&self.socket, buf)
+    }
+
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        Err(no_ip_address())
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        Err(no_ip_address())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    fn socket(&self) -> Option<socket2::SockRef<'_>> {
+        Some(socket2::SockRef::from(&self.socket))
+    }
+}
+
+/// No network at all, for a client that's only fed datagrams it's handed,
+/// say from a trace. Sending and receiving fail as not connected.
+pub struct Offline;
+
+impl PacketTransport for Offline {
+    fn send(&self, _: &[u8]) -> std::io::Result<usize> {
+        Err(std::io::ErrorKind::NotConnected.into())
+    }
+
+    fn recv_from(&self, _: &mut [u8]) -> std::io::Result<(usize, Option<SocketAddr>)> {
+        Err(std::io::ErrorKind::NotConnected.into())
+    }
+
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        Err(std::io::ErrorKind::NotConnected.into())
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        Err(std::io::ErrorKind::NotConnected.into())
+    }
+
+    fn set_read_timeout(&self, _: Option<Duration>) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn socket(&self) -> Option<socket2::SockRef<'_>> {
+        None
+    }
+}
+
+#[cfg(unix)]
+fn no_ip_address() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Unsupported, "unix sockets have no ip address")
+}
+
+/// Bind a Unix datagram socket at `bind`, where the server's replies will
+/// go, and connect it to the server listening at `server`
+#[cfg(unix)]
+pub fn connect_unix_socket(bind: &std::path::Path, server: &std::path::Path) -> Result<std::os::unix::net::UnixDatagram, super::error::SegFsError> {
+    let socket = std::os::unix::net::UnixDatagram::bind(bind).map_err(|e| format!("unable to bind unix socket to {}: {}", bind.display(), e))?;
+    socket.connect(server).map_err(|e| format!("unable to connect to unix socket {} : {}", server.display(), e))?;
+    Ok(socket)
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_transfer() {
+    use super::client::Client;
+    use super::packets::*;
+    use std::os::unix::net::UnixDatagram;
+
+    let dir = std::env::temp_dir().join(format!("seg-fs-unix_transfer-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let server = UnixDatagram::bind(dir.join("server.sock")).unwrap();
+    server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let socket = connect_unix_socket(&dir.join("client.sock"), &dir.join("server.sock")).unwrap();
+
+    let handle = std::thread::spawn(move || {
+        let mut buf = [0; 16];
+        let (_, peer) = server.recv_from(&mut buf).unwrap();
+        let peer = peer.as_pathname().unwrap().to_path_buf();
+        let packets = [
+            DataPacket { is_last: false, file_id: 1, packet_number: 0, data: b"over ".to_vec() }.to_bytes(),
+            HeaderPacket { file_id: 1, name: String::from("a.txt"), expected_size: None }.to_bytes(),
+            DataPacket { is_last: true, file_id: 1, packet_number: 1, data: b"unix".to_vec() }.to_bytes(),
+            HeaderPacket { file_id: 2, name: String::from("b.txt"), expected_size: None }.to_bytes(),
+            DataPacket { is_last: true, file_id: 2, packet_number: 0, data: b"b".to_vec() }.to_bytes()
+        ];
+        for packet in packets.iter() {
+            server.send_to(packet, &peer).unwrap();
+        }
+    });
+
+    let mut client = Client::with_transport(socket);
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.set_output_dir(&dir);
+    assert!(client.remote_addr().is_err());
+
+    client.send_request().unwrap();
+    while !client.is_batch_complete(2) {
+        client.recv_packet().unwrap();
+    }
+    client.finalize_files_ref().unwrap();
+    handle.join().unwrap();
+
+    assert_eq!(std::fs::read(dir.join("a.txt")).unwrap(), b"over unix");
+    assert_eq!(std::fs::read(dir.join("b.txt")).unwrap(), b"b");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn temp_unix_socket() {
+    use std::os::unix::net::UnixDatagram;
+
+    let dir = std::env::temp_dir().join(format!("seg-fs-temp_unix_socket-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let server = UnixDatagram::bind(dir.join("server.sock")).unwrap();
+
+    // Two at once get paths of their own
+    let first = TempUnixSocket::connect(&dir.join("server.sock")).unwrap();
+    let second = TempUnixSocket::connect(&dir.join("server.sock")).unwrap();
+    assert_ne!(first.path(), second.path());
+
+    first.send(b"ping").unwrap();
+    let mut buf = [0; 4];
+    let (_, peer) = server.recv_from(&mut buf).unwrap();
+    assert_eq!(peer.as_pathname(), Some(first.path().as_path()));
+
+    let path = first.path();
+    drop(first);
+    assert!(!path.exists());
+    assert!(second.path().exists());
+
+    // Nothing is left behind when there's no server to connect to
+    assert!(TempUnixSocket::connect(&dir.join("missing.sock")).is_err());
+    drop(second);
+    assert!(std::fs::read_dir(std::env::temp_dir()).unwrap().flatten()
+        .all(|entry| !entry.file_name().to_string_lossy().starts_with(&format!("seg-fs-client-{}-", std::process::id()))));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
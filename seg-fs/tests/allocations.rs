@@ -0,0 +1,86 @@
+//! Checks the receive path doesn't allocate more than it needs to. Counting
+//! allocations takes a global allocator, which would be shared with every
+//! other test, so this is a binary of its own.
+#![allow(dead_code)]
+
+use std::time::Duration;
+
+// seg-fs is a binary, so the modules the client needs are built in here
+#[path = "../src/client.rs"]
+mod client;
+#[path = "../src/clock.rs"]
+mod clock;
+#[path = "../src/error.rs"]
+mod error;
+#[path = "../src/filesystem.rs"]
+mod filesystem;
+#[path = "../src/journal.rs"]
+mod journal;
+#[path = "../src/packets.rs"]
+mod packets;
+#[cfg(feature = "pcap")]
+#[path = "../src/pcap.rs"]
+mod pcap;
+#[path = "../src/rng.rs"]
+mod rng;
+#[path = "../src/trace.rs"]
+mod trace;
+#[path = "../src/transport.rs"]
+mod transport;
+
+use client::Client;
+use packets::DataPacket;
+
+/// Counts allocations made on the current thread
+struct CountingAllocator;
+
+std::thread_local! {
+    static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        unsafe { std::alloc::System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        unsafe { std::alloc::System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn main() {
+    let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.connect(server.local_addr().unwrap()).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let mut client = Client::new(socket);
+    client.set_throughput_history_len(1);
+    client.send_request().unwrap();
+    let mut buf = [0; 16];
+    let (_, peer) = server.recv_from(&mut buf).unwrap();
+
+    // Few enough to fit in the socket's receive buffer
+    let packets: Vec<Vec<u8>> = (0..40)
+        .map(|packet_number| DataPacket { is_last: false, file_id: 1, packet_number, data: vec![7; 1024] }.to_bytes())
+        .collect();
+    for packet in packets.iter() {
+        server.send_to(packet, peer).unwrap();
+    }
+
+    let before = ALLOCATIONS.with(|count| count.get());
+    for _ in 0..packets.len() {
+        client.recv_packet().unwrap();
+    }
+    let allocations = ALLOCATIONS.with(|count| count.get()) - before;
+
+    // One copy of each payload, plus the segment map growing now and then.
+    // Copying the whole datagram as well used to take at least three each.
+    assert!(allocations < 60, "{} allocations for 40 packets", allocations);
+    println!("receive_allocations: {} allocations for {} packets", allocations, packets.len());
+}